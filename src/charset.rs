@@ -0,0 +1,199 @@
+// CHARACTER SET
+
+// Layouts, n-gram tables and scores all refer to characters by a single byte, their index in the
+// character set. The first 95 indices are always the printable ASCII characters, in order, with
+// the index being the ASCII code minus 32, so that the space is 0 and the default character set is
+// exactly the one described at the top of main.rs. Any other Unicode characters to be placed on
+// the layout (accented letters, dashes, curly quotes and the like) are listed in the configuration
+// and numbered from 95 in the order they are listed. Since NONE is 255, there can be at most 255
+// characters in all.
+
+// Characters can also be typed as a sequence of keys, either by pressing a dead key and then the
+// base character (´ then e for é) or by pressing a compose key followed by several characters. The
+// configuration lists each sequence as a single word of at least three characters, the keys to press
+// followed by the character they produce, as in "´eé ¨uü ◆'aá". The keys must be in the character
+// set, so that they can be placed on the layout, and the produced character must not be, since it is
+// always typed with its sequence: texts are scored as if the sequence were typed in its place.
+
+// Letters are the characters that have a single-character counterpart of the other case in the
+// character set, like a and A or é and É. As with ASCII, lower-case letters go on unshifted keys and
+// upper-case letters on shifted keys, and the two cases are moved together as a key.
+
+use std::collections::HashMap;
+
+use config::Config;
+use super::NONE;
+
+#[derive(Clone)]
+pub struct Charset
+{
+    chars:     Vec<char>,
+    index:     HashMap<char, u8>,
+    sequences: Vec<(char, Vec<u8>)>,
+}
+
+impl Charset
+{
+    // The printable ASCII characters, followed by the extra characters that aren't already in the
+    // set. Whitespace other than the space can't be placed on a layout.
+    pub fn new(extra_characters: &str) -> Result<Charset, String> {
+        let mut charset = Charset{ chars: Vec::new(), index: HashMap::new(), sequences: Vec::new() };
+        for c in (32u8..127).map(|x| x as char).chain(extra_characters.chars()) {
+            if charset.index.contains_key(&c) {
+                continue;
+            }
+            if c != ' ' && (c.is_whitespace() || c.is_control() || c == super::EMPTY_POSITION) {
+                return Err(format!("the character {:?} can't be placed on a layout", c));
+            }
+            if charset.chars.len() == NONE as usize {
+                return Err(format!("the character set can have at most {} characters", NONE));
+            }
+            charset.index.insert(c, charset.chars.len() as u8);
+            charset.chars.push(c);
+        }
+        Ok(charset)
+    }
+
+    // Add the dead key and compose sequences, given as described above.
+    pub fn with_sequences(mut self, sequences: &str) -> Result<Charset, String> {
+        for word in sequences.split_whitespace() {
+            let chars = word.chars().collect::<Vec<char>>();
+            let (&produced, keys) = chars.split_last().unwrap();
+            if keys.len() < 2 {
+                return Err(format!("the sequence {:?} needs at least two keys", word));
+            }
+            if self.index(produced).is_some() || self.sequence(produced).is_some() {
+                return Err(format!("the character {:?} is already in the character set", produced));
+            }
+            let mut sequence = Vec::new();
+            for &c in keys.iter() {
+                match self.index(c) {
+                    Some(i) => sequence.push(i),
+                    None    => return Err(format!("the key {:?} of the sequence {:?} is not in the \
+                                                   character set", c, word)),
+                }
+            }
+            self.sequences.push((produced, sequence));
+        }
+        Ok(self)
+    }
+
+    // The character set of a configuration.
+    pub fn from_config(config: &Config) -> Result<Charset, String> {
+        let charset = Charset::new(&config.extra_characters)
+                              .map_err(|e| format!("key 'extra_characters': {}", e))?;
+        charset.with_sequences(&config.dead_keys).map_err(|e| format!("key 'dead_keys': {}", e))
+    }
+
+    #[cfg(test)]
+    pub fn ascii() -> Charset {
+        Charset::new("").unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    pub fn char(&self, i: u8) -> char {
+        self.chars[i as usize]
+    }
+
+    pub fn index(&self, c: char) -> Option<u8> {
+        self.index.get(&c).copied()
+    }
+
+    // The keys that type a character that is not in the set, if it has a sequence.
+    pub fn sequence(&self, c: char) -> Option<&[u8]> {
+        self.sequences.iter().find(|s| s.0 == c).map(|s| &s.1[..])
+    }
+
+    // All sequences, each with the character it produces.
+    pub fn sequences(&self) -> &[(char, Vec<u8>)] {
+        &self.sequences
+    }
+
+    // The characters that start a sequence of two keys, which operating systems can type as dead
+    // keys, in the order of their first sequence.
+    pub fn dead_keys(&self) -> Vec<u8> {
+        let mut dead_keys = Vec::new();
+        for (_, keys) in self.sequences.iter().filter(|s| s.1.len() == 2) {
+            if !dead_keys.contains(&keys[0]) {
+                dead_keys.push(keys[0]);
+            }
+        }
+        dead_keys
+    }
+
+    // The other case of a letter, if it is in the character set.
+    pub fn other_case(&self, i: u8) -> Option<u8> {
+        let c = self.char(i);
+        let other: String = if c.is_lowercase() {
+            c.to_uppercase().collect()
+        } else if c.is_uppercase() {
+            c.to_lowercase().collect()
+        } else {
+            return None;
+        };
+        let mut other = other.chars();
+        match (other.next(), other.next()) {
+            (Some(o), None) => self.index(o),
+            _               => None,
+        }
+    }
+
+    pub fn is_lower_case_letter(&self, i: u8) -> bool {
+        self.char(i).is_lowercase() && self.other_case(i).is_some()
+    }
+
+    pub fn is_upper_case_letter(&self, i: u8) -> bool {
+        self.char(i).is_uppercase() && self.other_case(i).is_some()
+    }
+
+    pub fn is_letter(&self, i: u8) -> bool {
+        self.is_lower_case_letter(i) || self.is_upper_case_letter(i)
+    }
+
+    // Convert a text to character indices, with all whitespace as spaces and the characters that are
+    // typed with a sequence replaced by its keys. A character that is neither in the set nor typed
+    // with a sequence is returned along with its line number.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, (char, usize)> {
+        let mut encoded = Vec::with_capacity(text.len());
+        for (line_index, line) in text.split('\n').enumerate() {
+            if line_index > 0 {
+                encoded.push(0);
+            }
+            for c in line.chars() {
+                match (self.index(c), self.sequence(c)) {
+                    (Some(i), _)                      => encoded.push(i),
+                    (None, Some(keys))                => encoded.extend_from_slice(keys),
+                    (None, None) if c.is_whitespace() => encoded.push(0),
+                    (None, None)                      => return Err((c, line_index + 1)),
+                }
+            }
+        }
+        Ok(encoded)
+    }
+}
+
+#[test]
+fn charset_test()
+{
+    let charset = Charset::new("äÄß–é€ä").unwrap();
+    assert_eq!(charset.len(), 101);
+    assert_eq!(charset.index('a'), Some(65));
+    assert_eq!(charset.index('ä'), Some(95));
+    assert_eq!(charset.char(100), '€');
+    assert_eq!(charset.other_case(95), Some(96));
+    assert!(charset.is_lower_case_letter(65) && charset.is_upper_case_letter(96));
+    assert!(!charset.is_letter(97)); // ß has no single upper-case character
+    assert!(!charset.is_letter(99)); // é is only a letter along with É
+    assert_eq!(charset.encode("a ä\tb\n–"), Ok(vec![65, 0, 95, 0, 66, 0, 98]));
+    assert_eq!(charset.encode("ok\nnö"), Err(('ö', 2)));
+    assert!(Charset::new("\t").is_err());
+
+    let charset = Charset::new("´¨◆").unwrap().with_sequences("´eé ¨uü ◆'aá").unwrap();
+    assert_eq!(charset.sequence('ü'), Some(&[96, 85][..]));
+    assert_eq!(charset.encode("né ü\ná"), Ok(vec![78, 95, 69, 0, 96, 85, 0, 97, 7, 65]));
+    assert!(Charset::new("").unwrap().with_sequences("´eé").is_err());
+    assert!(Charset::new("´é").unwrap().with_sequences("´eé").is_err());
+}
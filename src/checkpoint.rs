@@ -0,0 +1,273 @@
+// CHECKPOINT FILES
+
+// A checkpoint holds everything needed to continue an annealing run exactly where it stopped: the
+// configuration it was started with (including the seed), the current and best layouts, the
+// temperatures and iteration counters, the tabu state of the layout swapper, and the state of the
+// random number generator. It is written in the same "key = value" format as configuration files,
+// with the configuration under a [config] header and the run itself under a [state] header. A
+// parallel run has one [state] section for each of its chains, in order.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::collections::HashMap;
+
+use charset::Charset;
+use config::{self, Config};
+use constraints::Constraints;
+use geometry::Geometry;
+use rng::SeededRng;
+use super::{AnnealingState, LayoutScore, LayoutSwap, LayoutSwapper};
+use super::{layout_from_string, layout_to_string};
+
+fn format_score(score: LayoutScore) -> String
+{
+    format!("{} {}", score.i, score.f)
+}
+
+fn parse_score(key: &str, value: &str) -> Result<LayoutScore, String>
+{
+    let parts = value.split_whitespace().collect::<Vec<&str>>();
+    match (parts.len(), parts.first().map(|x| x.parse::<i32>()), parts.get(1).map(|x| x.parse::<f32>())) {
+        (2, Some(Ok(i)), Some(Ok(f))) => Ok(LayoutScore{ i, f }),
+        _ => Err(format!("key '{}' expects an integer and a number, found '{}'", key, value))
+    }
+}
+
+fn format_swaps(swaps: &[u8]) -> String
+{
+    swaps.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(" ")
+}
+
+fn parse_swaps(key: &str, value: &str) -> Result<Vec<u8>, String>
+{
+    value.split_whitespace().map(|x| {
+        x.parse::<u8>().map_err(|_| format!("key '{}' has an invalid entry '{}'", key, x))
+    }).collect()
+}
+
+fn format_tabu_swaps(swaps: &[LayoutSwap]) -> String
+{
+    swaps.iter().map(|swap| match *swap {
+        LayoutSwap::None      => "-".to_string(),
+        LayoutSwap::Symbol(s) => format!("S{}", s),
+        LayoutSwap::Home8K(k) => format!("H{}", k),
+        LayoutSwap::Letter(k) => format!("L{}", k),
+        LayoutSwap::AltGr(p)  => format!("A{}", p),
+    }).collect::<Vec<String>>().join(" ")
+}
+
+fn parse_tabu_swaps(key: &str, value: &str) -> Result<Vec<LayoutSwap>, String>
+{
+    value.split_whitespace().map(|x| {
+        let error = || format!("key '{}' has an invalid entry '{}'", key, x);
+        if x == "-" {
+            return Ok(LayoutSwap::None);
+        }
+        let n = x[1..].parse::<u8>().map_err(|_| error())?;
+        match &x[..1] {
+            "S" => Ok(LayoutSwap::Symbol(n)),
+            "H" => Ok(LayoutSwap::Home8K(n)),
+            "L" => Ok(LayoutSwap::Letter(n)),
+            "A" => Ok(LayoutSwap::AltGr(n)),
+            _   => Err(error())
+        }
+    }).collect()
+}
+
+// Format the state of one chain as the body of a [state] section.
+pub fn format_state(state: &AnnealingState, charset: &Charset) -> String
+{
+    let swapper = &state.swapper;
+    let mut text = String::new();
+    let entries = vec![
+        ("layout",            config::format_string(&layout_to_string(&state.layout, charset))),
+        ("score",             format_score(state.score)),
+        ("best_layout",       config::format_string(&layout_to_string(&state.best_layout, charset))),
+        ("best_score",        format_score(state.best_score)),
+        ("prev_best_score",   format_score(state.prev_best_score)),
+        ("temperature",       state.temperature.to_string()),
+        ("iteration",         state.iteration.to_string()),
+        ("cycle_temperature", state.cycle_temperature.to_string()),
+        ("cycle_iteration",   state.cycle_iteration.to_string()),
+        ("total_iterations",  state.total_iterations.to_string()),
+        ("rng_state",         state.rng.state().to_string()),
+        ("tabu_swaps",        format_tabu_swaps(&swapper.tabu_swaps)),
+        ("symbol_swaps",      format_swaps(&swapper.symbol_swaps)),
+        ("home8k_swaps",      format_swaps(&swapper.home8k_swaps)),
+        ("letter_swaps",      format_swaps(&swapper.letter_swaps)),
+        ("altgr_swaps",       format_swaps(&swapper.altgr_swaps)),
+        ("tabu_iteration",    swapper.iteration.to_string()),
+        ("random_bits",       swapper.random_bits.to_string()),
+        ("random_bits_left",  swapper.random_bits_left.to_string()),
+    ];
+    for (key, value) in entries {
+        text.push_str(&format!("{:17} = {}\n", key, value));
+    }
+    text
+}
+
+// Write a checkpoint from the formatted states of all chains. The file is first written under a
+// temporary name and then renamed, so that a run killed in the middle of writing never leaves a
+// truncated checkpoint behind.
+pub fn write_checkpoint(filename: &str, config: &Config, states: &[String])
+{
+    let mut text = String::new();
+    text.push_str("# Annealing checkpoint\n[config]\n");
+    text.push_str(&config.to_config_string());
+    for state in states.iter() {
+        text.push_str("\n[state]\n");
+        text.push_str(state);
+    }
+
+    let temporary_filename = format!("{}.tmp", filename);
+    let mut file = File::create(&temporary_filename).unwrap();
+    let io_result = file.write_all(text.as_bytes());
+    assert!(io_result.is_ok());
+    std::fs::rename(&temporary_filename, filename).unwrap();
+}
+
+// Read a checkpoint back into the configuration and the states of all chains of the run.
+pub fn read_checkpoint(filename: &str) -> Result<(Config, Vec<AnnealingState>), String>
+{
+    let mut text = String::new();
+    let read_result = File::open(filename).and_then(|mut file| file.read_to_string(&mut text));
+    if let Err(e) = read_result {
+        return Err(format!("{}: {}", filename, e));
+    }
+
+    // Separate the sections
+    let mut config_text = String::new();
+    let mut state_sections: Vec<Vec<(usize, &str)>> = Vec::new();
+    let mut in_config = false;
+    for (line_index, raw_line) in text.lines().enumerate() {
+        let line = config::strip_comment(raw_line).trim();
+        config_text.push_str(if in_config && line != "[state]" { raw_line } else { "" });
+        config_text.push('\n');
+        if line == "[config]" {
+            in_config = true;
+        } else if line == "[state]" {
+            in_config = false;
+            state_sections.push(Vec::new());
+        } else if let Some(section) = state_sections.last_mut() {
+            section.push((line_index + 1, line));
+        }
+    }
+    let config = Config::from_string(&config_text, filename)?;
+    if state_sections.len() != config.num_chains {
+        return Err(format!("{}: expected {} [state] sections, one for each chain, found {}",
+                           filename, config.num_chains, state_sections.len()));
+    }
+    let geometry = Geometry::from_config(&config)?;
+    let charset  = Charset::from_config(&config).unwrap();
+    let constraints = Constraints::from_config(&config, &geometry, &charset)?;
+    let mut states = Vec::new();
+    for state_lines in state_sections.iter() {
+        states.push(read_state(filename, &config, &geometry, &charset, &constraints, state_lines)?);
+    }
+    Ok((config, states))
+}
+
+// Read the lines of one [state] section.
+fn read_state(filename: &str, config: &Config, geometry: &Geometry, charset: &Charset,
+              constraints: &Constraints, state_lines: &[(usize, &str)])
+    -> Result<AnnealingState, String>
+{
+    let mut values: HashMap<&str, &str> = HashMap::new();
+    for &(line_num, line) in state_lines.iter() {
+        if line.is_empty() {
+            continue;
+        }
+        match line.find('=') {
+            Some(i) => { values.insert(line[..i].trim(), line[i+1..].trim()); },
+            None    => return Err(format!("{}, line {}: expected \"key = value\", found \"{}\"",
+                                          filename, line_num, line))
+        }
+    }
+    let get = |key: &str| -> Result<&str, String> {
+        match values.get(key) {
+            Some(value) => Ok(*value),
+            None        => Err(format!("{}: missing key '{}'", filename, key))
+        }
+    };
+    let in_file = |e: String| format!("{}: {}", filename, e);
+    let read_layout = |key: &str| -> Result<Vec<u8>, String> {
+        Ok(layout_from_string(&config::parse_string(key, get(key)?).map_err(in_file)?, geometry,
+                              charset))
+    };
+
+    let swapper = LayoutSwapper{
+        tabu_swaps:       parse_tabu_swaps("tabu_swaps", get("tabu_swaps")?).map_err(in_file)?,
+        symbol_swaps:     parse_swaps("symbol_swaps", get("symbol_swaps")?).map_err(in_file)?,
+        home8k_swaps:     parse_swaps("home8k_swaps", get("home8k_swaps")?).map_err(in_file)?,
+        letter_swaps:     parse_swaps("letter_swaps", get("letter_swaps")?).map_err(in_file)?,
+        altgr_swaps:      parse_swaps("altgr_swaps", get("altgr_swaps")?).map_err(in_file)?,
+        constraints:      constraints.clone(),
+        candidates:       Vec::new(),
+        original:         Vec::new(),
+        num_chars:        charset.len(),
+        num_keys:         geometry.num_keys(),
+        iteration:        config::parse_usize("tabu_iteration", get("tabu_iteration")?).map_err(in_file)?,
+        random_bits:      config::parse_usize("random_bits", get("random_bits")?).map_err(in_file)?,
+        random_bits_left: config::parse_usize("random_bits_left", get("random_bits_left")?)
+                                  .map_err(in_file)?,
+    };
+    if swapper.tabu_swaps.len() != 2 * config.num_tabu_swaps {
+        return Err(format!("{}: key 'tabu_swaps' must have {} entries", filename,
+                           2 * config.num_tabu_swaps));
+    }
+    let state = AnnealingState{
+        layout:            read_layout("layout")?,
+        score:             parse_score("score", get("score")?).map_err(in_file)?,
+        best_layout:       read_layout("best_layout")?,
+        best_score:        parse_score("best_score", get("best_score")?).map_err(in_file)?,
+        prev_best_score:   parse_score("prev_best_score", get("prev_best_score")?).map_err(in_file)?,
+        swapper,
+        rng:               SeededRng::from_state(config::parse_u64("rng_state", get("rng_state")?)
+                                                         .map_err(in_file)?),
+        temperature:       config::parse_f64("temperature", get("temperature")?).map_err(in_file)?,
+        iteration:         config::parse_u64("iteration", get("iteration")?).map_err(in_file)?,
+        cycle_temperature: config::parse_f64("cycle_temperature", get("cycle_temperature")?)
+                                   .map_err(in_file)?,
+        cycle_iteration:   config::parse_u64("cycle_iteration", get("cycle_iteration")?)
+                                   .map_err(in_file)?,
+        total_iterations:  config::parse_u64("total_iterations", get("total_iterations")?)
+                                   .map_err(in_file)?,
+        stuck_iterations:  0,
+    };
+    Ok(state)
+}
+
+#[test]
+fn checkpoint_round_trip_test()
+{
+    use rand::Rng;
+    use super::{WHITE_STRING, layout_from_string};
+
+    let config = Config{ seed: Some(99), ..Config::default() };
+    let geometry = Geometry::ansi();
+    let charset = Charset::ascii();
+    let layout = layout_from_string(WHITE_STRING, &geometry, &charset);
+    let constraints = Constraints::new(&config, &geometry, &charset);
+    let mut state = AnnealingState::new(layout, LayoutScore{ i: 118, f: 662.103 }, &config,
+                                        &geometry, &charset, &constraints, SeededRng::new(99));
+    for _ in 0..25 {
+        state.swapper.swap(&mut state.layout, &mut state.rng);
+    }
+    state.temperature = 1234.5678901234567;
+
+    let filename = std::env::temp_dir().join("white_keyboard_layout_checkpoint_test.txt");
+    let filename = filename.to_str().unwrap();
+    write_checkpoint(filename, &config, &[format_state(&state, &charset)]);
+    let (read_config, mut read_states) = read_checkpoint(filename).unwrap();
+    std::fs::remove_file(filename).unwrap();
+    let read_state = &mut read_states[0];
+
+    assert_eq!(read_config.entries(), config.entries());
+    assert!(read_state.layout[..] == state.layout[..]);
+    assert!(read_state.score == state.score);
+    assert_eq!(read_state.temperature, state.temperature);
+    assert_eq!(read_state.rng.next_u64(), state.rng.next_u64());
+    assert_eq!(format_tabu_swaps(&read_state.swapper.tabu_swaps),
+               format_tabu_swaps(&state.swapper.tabu_swaps));
+    assert_eq!(read_state.swapper.symbol_swaps, state.swapper.symbol_swaps);
+}
@@ -0,0 +1,326 @@
+// COMMAND LINE INTERFACE
+
+// The binary is driven by a subcommand followed by any number of options. For compatibility with
+// the original interface, running it with no arguments, or with a single argument that isn't a
+// subcommand, runs the optimizer with that argument as the output prefix.
+
+pub const USAGE: &str = "\
+Usage: white_keyboard_layout [COMMAND] [OPTIONS]
+
+Commands:
+  optimize [PREFIX]       Search for an optimal layout by simulated annealing (default)
+  pareto [PREFIX]         Search for the layouts that trade off effort, similarity, hand
+                          balance and same-finger bigrams best
+  score <LAYOUT>...       Score layout files without optimizing them
+  compare <A> <B>         Score two layout files and show where their symbols differ
+  analyze                 Print the key metrics and the character counts of the corpus
+  export <LAYOUT>         Write a layout file out in another format
+  help                    Print this message
+
+Options:
+  --config <FILE>         Configuration file                  [default: config.toml]
+  --texts <DIR>           Directory of evaluation texts       [default: texts]
+  --layout <FILE>         Starting layout for optimize and pareto [default: optimal_layout.txt]
+  --best <FILE>           Where optimize keeps the best layout [default: optimal_layout.txt]
+  --output-dir <DIR>      Directory for optimize and pareto results [default: layouts]
+  --prefix <NAME>         Filename prefix for optimize and pareto results [default: layout]
+  --iterations <N>        Stop optimize, or each run of pareto, after N iterations in total
+  --search <SEARCH>       Search used by optimize: annealing or genetic [default: annealing]
+  --seed <N>              Seed for the random number generator [default: random]
+  --chains <N>            Number of annealing chains run in parallel [default: from config]
+  --checkpoint <FILE>     Periodically save the state of optimize to a checkpoint file
+  --checkpoint-interval <N>  Iterations between checkpoints [default: 1000000]
+  --resume <FILE>         Continue optimize from a checkpoint file
+  --output <FILE>         Output file for export              [default: standard output]
+  --format <FORMAT>       Output format for export: text, klc, keylayout, xkb, loadkeys,
+                          qmk or zmk [default: text]
+  --name <NAME>           Name of an exported keyboard layout [default: Custom]
+  --locale <LOCALE>       Locale of an exported keyboard layout [default: en-US]
+  --qwerty-shortcuts      Keep keyboard shortcuts on their QWERTY keys in an exported layout
+  --caps-backspace        Turn Caps Lock into backspace in an exported keymap
+";
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Command
+{
+    Optimize,
+    Pareto,
+    Score(Vec<String>),
+    Compare(String, String),
+    Analyze,
+    Export(String),
+    Help,
+}
+
+// The search strategy of the optimizer. A genetic search counts each child it evaluates as one
+// iteration, so that the two can be compared on the same budget.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Search
+{
+    Annealing,
+    Genetic,
+}
+
+#[derive(Clone, Debug)]
+pub struct Options
+{
+    pub command:     Command,
+    pub config_file: String,
+    pub texts_dir:   String,
+    pub layout_file: String,
+    pub best_file:   String,
+    pub output_dir:  String,
+    pub prefix:      String,
+    pub iterations:  Option<u64>,
+    pub search:      Search,
+    pub seed:        Option<u64>,
+    pub chains:      Option<usize>,
+    pub checkpoint_file:     Option<String>,
+    pub checkpoint_interval: u64,
+    pub resume_file:         Option<String>,
+    pub output_file: Option<String>,
+    pub format:      String,
+    pub layout_name: String,
+    pub locale:      String,
+    pub qwerty_shortcuts: bool,
+    pub caps_backspace:   bool,
+}
+
+impl Default for Options
+{
+    fn default() -> Options {
+        Options{
+            command:     Command::Optimize,
+            config_file: "config.toml".to_string(),
+            texts_dir:   "texts".to_string(),
+            layout_file: "optimal_layout.txt".to_string(),
+            best_file:   "optimal_layout.txt".to_string(),
+            output_dir:  "layouts".to_string(),
+            prefix:      "layout".to_string(),
+            iterations:  None,
+            search:      Search::Annealing,
+            seed:        None,
+            chains:      None,
+            checkpoint_file:     None,
+            checkpoint_interval: 1000000,
+            resume_file:         None,
+            output_file: None,
+            format:      "text".to_string(),
+            layout_name: "Custom".to_string(),
+            locale:      "en-US".to_string(),
+            qwerty_shortcuts: false,
+            caps_backspace:   false,
+        }
+    }
+}
+
+// Parse the command line arguments, not including the program name.
+pub fn parse_args(args: &[String]) -> Result<Options, String>
+{
+    let mut options = Options::default();
+    let mut positional: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg.starts_with("--") {
+            // Options may be written either as "--key value" or as "--key=value"
+            let (name, inline_value) = match arg.find('=') {
+                Some(j) => (&arg[..j], Some(arg[j+1..].to_string())),
+                None    => (&arg[..], None)
+            };
+            let mut value = || -> Result<String, String> {
+                if let Some(ref v) = inline_value {
+                    return Ok(v.clone());
+                }
+                i += 1;
+                match args.get(i) {
+                    Some(v) => Ok(v.clone()),
+                    None    => Err(format!("option {} requires a value", name))
+                }
+            };
+            match name {
+                "--config"     => options.config_file = value()?,
+                "--texts"      => options.texts_dir   = value()?,
+                "--layout"     => options.layout_file = value()?,
+                "--best"       => options.best_file   = value()?,
+                "--output-dir" => options.output_dir  = value()?,
+                "--prefix"     => options.prefix      = value()?,
+                "--output"     => options.output_file = Some(value()?),
+                "--format"     => options.format      = value()?,
+                "--name"       => options.layout_name = value()?,
+                "--locale"     => options.locale      = value()?,
+                "--qwerty-shortcuts" => options.qwerty_shortcuts = true,
+                "--caps-backspace"   => options.caps_backspace   = true,
+                "--iterations" => {
+                    let v = value()?;
+                    match v.parse::<u64>() {
+                        Ok(n) if n > 0 => options.iterations = Some(n),
+                        _ => return Err(format!("option --iterations expects a positive integer, \
+                                                 found '{}'", v))
+                    }
+                },
+                "--search" => {
+                    let v = value()?;
+                    options.search = match &v[..] {
+                        "annealing" => Search::Annealing,
+                        "genetic"   => Search::Genetic,
+                        _ => return Err(format!("option --search expects annealing or genetic, \
+                                                 found '{}'", v))
+                    };
+                },
+                "--checkpoint" => options.checkpoint_file = Some(value()?),
+                "--resume"     => options.resume_file     = Some(value()?),
+                "--checkpoint-interval" => {
+                    let v = value()?;
+                    match v.parse::<u64>() {
+                        Ok(n) if n > 0 => options.checkpoint_interval = n,
+                        _ => return Err(format!("option --checkpoint-interval expects a positive \
+                                                 integer, found '{}'", v))
+                    }
+                },
+                "--seed" => {
+                    let v = value()?;
+                    match v.parse::<u64>() {
+                        Ok(n) => options.seed = Some(n),
+                        _ => return Err(format!("option --seed expects a non-negative integer, \
+                                                 found '{}'", v))
+                    }
+                },
+                "--chains" => {
+                    let v = value()?;
+                    match v.parse::<usize>() {
+                        Ok(n) if n > 0 => options.chains = Some(n),
+                        _ => return Err(format!("option --chains expects a positive integer, \
+                                                 found '{}'", v))
+                    }
+                },
+                "--help" => options.command = Command::Help,
+                _ => return Err(format!("unknown option {}", name))
+            }
+        } else {
+            positional.push(arg.clone());
+        }
+        i += 1;
+    }
+    if options.command == Command::Help {
+        return Ok(options);
+    }
+
+    let mut positional = positional.into_iter();
+    let command_name = positional.next();
+    let operands = positional.collect::<Vec<String>>();
+    options.command = match command_name.as_ref().map(|s| &s[..]) {
+        None | Some("optimize") => {
+            match operands.len() {
+                0 => {},
+                1 => options.prefix = operands[0].clone(),
+                _ => return Err("optimize takes at most one output prefix".to_string())
+            }
+            Command::Optimize
+        },
+        Some("pareto") => {
+            match operands.len() {
+                0 => {},
+                1 => options.prefix = operands[0].clone(),
+                _ => return Err("pareto takes at most one output prefix".to_string())
+            }
+            Command::Pareto
+        },
+        Some("score") => {
+            if operands.is_empty() {
+                return Err("score requires at least one layout file".to_string());
+            }
+            Command::Score(operands)
+        },
+        Some("compare") => {
+            if operands.len() != 2 {
+                return Err("compare requires exactly two layout files".to_string());
+            }
+            Command::Compare(operands[0].clone(), operands[1].clone())
+        },
+        Some("analyze") => {
+            if !operands.is_empty() {
+                return Err("analyze does not take any layout files".to_string());
+            }
+            Command::Analyze
+        },
+        Some("export") => {
+            if operands.len() != 1 {
+                return Err("export requires exactly one layout file".to_string());
+            }
+            Command::Export(operands[0].clone())
+        },
+        Some("help") => Command::Help,
+        Some(prefix) => {
+            // The original interface: a lone argument is the output prefix
+            if !operands.is_empty() {
+                return Err(format!("unknown command '{}'", prefix));
+            }
+            options.prefix = prefix.to_string();
+            Command::Optimize
+        }
+    };
+    if options.resume_file.is_some() && options.command != Command::Optimize {
+        return Err("--resume can only be used with optimize".to_string());
+    }
+    if options.search == Search::Genetic &&
+       (options.resume_file.is_some() || options.checkpoint_file.is_some() || options.chains.is_some()) {
+        return Err("--checkpoint, --resume and --chains can only be used with annealing".to_string());
+    }
+    if options.checkpoint_file.is_none() {
+        options.checkpoint_file = options.resume_file.clone();
+    }
+    if !["text", "klc", "keylayout", "xkb", "loadkeys", "qmk", "zmk"].contains(&&options.format[..]) {
+        return Err(format!("unknown export format '{}'", options.format));
+    }
+    if options.format == "xkb" && options.output_file.is_none() {
+        return Err("the xkb format writes several files, so it needs --output".to_string());
+    }
+    Ok(options)
+}
+
+#[test]
+fn parse_args_test()
+{
+    let args = |s: &str| s.split_whitespace().map(|x| x.to_string()).collect::<Vec<String>>();
+
+    let options = parse_args(&args("")).unwrap();
+    assert_eq!(options.command, Command::Optimize);
+    assert_eq!(options.prefix, "layout");
+
+    let options = parse_args(&args("run7")).unwrap();
+    assert_eq!(options.command, Command::Optimize);
+    assert_eq!(options.prefix, "run7");
+
+    let options = parse_args(&args("optimize --texts=corpus --iterations 500 --output-dir out")).unwrap();
+    assert_eq!(options.texts_dir, "corpus");
+    assert_eq!(options.iterations, Some(500));
+    assert_eq!(options.output_dir, "out");
+
+    let options = parse_args(&args("optimize --chains 4 --seed 7")).unwrap();
+    assert_eq!(options.chains, Some(4));
+    assert!(parse_args(&args("optimize --chains 0")).is_err());
+
+    let options = parse_args(&args("compare a.txt b.txt")).unwrap();
+    assert_eq!(options.command, Command::Compare("a.txt".to_string(), "b.txt".to_string()));
+
+    let options = parse_args(&args("pareto front --iterations 1000")).unwrap();
+    assert_eq!((options.command, options.prefix), (Command::Pareto, "front".to_string()));
+
+    let options = parse_args(&args("optimize --search genetic --iterations 1000")).unwrap();
+    assert_eq!(options.search, Search::Genetic);
+    assert!(parse_args(&args("optimize --search genetic --chains 2")).is_err());
+    assert!(parse_args(&args("optimize --search hill")).is_err());
+
+    let options = parse_args(&args("export w.txt --format klc --name White --qwerty-shortcuts")).unwrap();
+    assert_eq!((&options.format[..], &options.layout_name[..]), ("klc", "White"));
+    assert!(options.qwerty_shortcuts);
+    assert!(parse_args(&args("export white.txt --format pdf")).is_err());
+    assert!(parse_args(&args("export white.txt --format xkb")).is_err());
+    let options = parse_args(&args("export white.txt --format loadkeys --caps-backspace")).unwrap();
+    assert!(options.caps_backspace);
+
+    assert!(parse_args(&args("compare a.txt")).is_err());
+    assert!(parse_args(&args("score --iterations")).is_err());
+    assert!(parse_args(&args("optimize --bogus 1")).is_err());
+}
@@ -0,0 +1,527 @@
+// RUNTIME CONFIGURATION
+
+// All of the optimization parameters can be overridden at startup by a configuration file written
+// in a small TOML/INI-style format: one "key = value" pair per line, "#" starts a comment, strings
+// are written in double quotes, and [section] headers are accepted but ignored so that a file can
+// be organized however its author likes. Keys are the lower-case names of the constants below, so
+// for example "shift_holding_penalty = 1.25" replaces SHIFT_HOLDING_PENALTY. Any key that is not
+// mentioned in the file keeps its default value.
+
+use std::fs::File;
+use std::path::Path;
+use std::io::Read;
+
+use charset::Charset;
+
+
+// DEFAULT OPTIMIZATION PARAMETERS
+const PRINT_OBJECTIVE_FUNCTION:  bool  =  false;
+
+// The keyboard to make layouts for: "ansi", "iso", "jis", "3x10", "3x12", "3x10_columnar",
+// "3x12_columnar", "split_3x6_3", or the name of a geometry file
+const GEOMETRY:                  &str  =  "ansi";
+
+// Characters outside of printable ASCII to place on the layout, such as "äöüÄÖÜß€–"
+const EXTRA_CHARACTERS:          &str  =  "";
+
+// Dead key and compose sequences, each written as the keys to press followed by the character they
+// type, such as "´eé ¨uü" (see charset.rs)
+const DEAD_KEYS:                 &str  =  "";
+
+// The number of layers on each key: 2 for unshifted and shifted, 3 to add an AltGr layer, and 4 to
+// add AltGr with shift
+const LAYERS:                    usize =  2;
+
+const FROZEN_SYMBOLS:            &str  =  "0123456789";
+
+// A file of constraints on where characters may go (see constraints.rs), or "" for none
+const CONSTRAINTS:               &str  =  "";
+
+// Letters used in keyboard shortcuts, such as "zxcvasqw" for undo, cut, copy, paste, select all,
+// quit and close, which are penalized for each one that isn't on its QWERTY key, or kept on their
+// QWERTY keys altogether if they are fixed
+const SHORTCUT_KEYS:             &str  =  "";
+const SHORTCUT_PENALTY:          f32   =  1000.0;
+const FIX_SHORTCUT_KEYS:         bool  =  false;
+
+// A layout file to stay similar to, or "" for QWERTY, and the penalty for each keystroke of a
+// character that has moved from where it is in that layout (see LayoutObjectiveFunction)
+const REFERENCE_LAYOUT:          &str  =  "";
+const SIMILARITY_PENALTY:        f32   =  0.00;
+
+const SINGLE_METRIC_COEFFICIENT: f32   =  1.00;
+const DOUBLE_METRIC_COEFFICIENT: f32   =  1.00;
+const TRIPLE_METRIC_COEFFICIENT: f32   =  1.00;
+const SHIFT_HOLDING_PENALTY:     f32   =  1.50;
+const ALTGR_HOLDING_PENALTY:     f32   =  2.00;
+const ALTGR_SHIFT_PENALTY:       f32   =  3.00;
+const REVERSED_TRIPLE_PENALTY:   f32   =  0.25;
+const HAND_ALTERNATION_PENALTY:  f32   =  0.20;
+
+// Score evaluation texts as running text, including the space bar and the transitions across it,
+// with the space bar pressed by a thumb at the given cost and the keys on either side of a space
+// scored with the double metric at the given weight
+const RUNNING_TEXT:              bool  =  false;
+const SPACE_KEY_METRIC:          f32   =  0.00;
+const SPACE_SKIPGRAM_WEIGHT:     f32   =  0.50;
+
+const CORPUS_MIN_WORD_FREQUENCY: f32   =  20.0;
+const CORPUS_1_COEFFICIENT:      f64   =  1e-3;
+const CORPUS_2_COEFFICIENT:      f64   =  0.50;
+
+const CYCLE_TEMPERATURE_START:   f64   =  1e+5;
+const CYCLE_TEMPERATURE_FINAL:   f64   =  5e+3;
+const CYCLE_TEMPERATURE_FACTOR:  f64   =  0.50;
+const TEMPERATURE_FINAL:         f64   =  1.00;
+const TEMPERATURE_FACTOR:        f64   =  0.99999;
+const NUM_TABU_SWAPS:            usize =  10;
+
+// The genetic search (see genetic.rs): the number of layouts in the population, the number of them
+// that compete to be a parent, the number of random swaps that mutate each child, and the number
+// of generations
+const POPULATION_SIZE:           usize =  50;
+const TOURNAMENT_SIZE:           usize =  3;
+const MUTATION_SWAPS:            usize =  1;
+const GENERATIONS:               usize =  2000;
+
+// Independent annealing chains run in parallel, each on its own thread with its own seed
+const NUM_CHAINS:                usize =  1;
+
+// The pareto command runs this many annealing runs, with similarity penalties spread evenly from 0
+// up to the given one, and keeps at most this many layouts on the front (see pareto.rs)
+const PARETO_RUNS:               usize =  8;
+const PARETO_SIMILARITY_PENALTY: f32   =  1.00;
+const PARETO_FRONT_SIZE:         usize =  20;
+
+// A run without a seed picks a random one, which is then echoed with the rest of the configuration
+const SEED:                      Option<u64> = None;
+
+
+#[derive(Clone)]
+pub struct Config
+{
+    pub print_objective_function:  bool,
+    pub geometry:                  String,
+    pub extra_characters:          String,
+    pub dead_keys:                 String,
+    pub layers:                    usize,
+    pub frozen_symbols:            String,
+    pub constraints:               String,
+    pub shortcut_keys:             String,
+    pub shortcut_penalty:          f32,
+    pub fix_shortcut_keys:         bool,
+    pub reference_layout:          String,
+    pub similarity_penalty:        f32,
+    pub single_metric_coefficient: f32,
+    pub double_metric_coefficient: f32,
+    pub triple_metric_coefficient: f32,
+    pub shift_holding_penalty:     f32,
+    pub altgr_holding_penalty:     f32,
+    pub altgr_shift_penalty:       f32,
+    pub reversed_triple_penalty:   f32,
+    pub hand_alternation_penalty:  f32,
+    pub running_text:              bool,
+    pub space_key_metric:          f32,
+    pub space_skipgram_weight:     f32,
+    pub corpus_min_word_frequency: f32,
+    pub corpus_1_coefficient:      f64,
+    pub corpus_2_coefficient:      f64,
+    pub cycle_temperature_start:   f64,
+    pub cycle_temperature_final:   f64,
+    pub cycle_temperature_factor:  f64,
+    pub temperature_final:         f64,
+    pub temperature_factor:        f64,
+    pub num_tabu_swaps:            usize,
+    pub population_size:           usize,
+    pub tournament_size:           usize,
+    pub mutation_swaps:            usize,
+    pub generations:               usize,
+    pub num_chains:                usize,
+    pub pareto_runs:               usize,
+    pub pareto_similarity_penalty: f32,
+    pub pareto_front_size:         usize,
+    pub seed:                      Option<u64>,
+}
+
+impl Default for Config
+{
+    fn default() -> Config {
+        Config{
+            print_objective_function:  PRINT_OBJECTIVE_FUNCTION,
+            geometry:                  GEOMETRY.to_string(),
+            extra_characters:          EXTRA_CHARACTERS.to_string(),
+            dead_keys:                 DEAD_KEYS.to_string(),
+            layers:                    LAYERS,
+            frozen_symbols:            FROZEN_SYMBOLS.to_string(),
+            constraints:               CONSTRAINTS.to_string(),
+            shortcut_keys:             SHORTCUT_KEYS.to_string(),
+            shortcut_penalty:          SHORTCUT_PENALTY,
+            fix_shortcut_keys:         FIX_SHORTCUT_KEYS,
+            reference_layout:          REFERENCE_LAYOUT.to_string(),
+            similarity_penalty:        SIMILARITY_PENALTY,
+            single_metric_coefficient: SINGLE_METRIC_COEFFICIENT,
+            double_metric_coefficient: DOUBLE_METRIC_COEFFICIENT,
+            triple_metric_coefficient: TRIPLE_METRIC_COEFFICIENT,
+            shift_holding_penalty:     SHIFT_HOLDING_PENALTY,
+            altgr_holding_penalty:     ALTGR_HOLDING_PENALTY,
+            altgr_shift_penalty:       ALTGR_SHIFT_PENALTY,
+            reversed_triple_penalty:   REVERSED_TRIPLE_PENALTY,
+            hand_alternation_penalty:  HAND_ALTERNATION_PENALTY,
+            running_text:              RUNNING_TEXT,
+            space_key_metric:          SPACE_KEY_METRIC,
+            space_skipgram_weight:     SPACE_SKIPGRAM_WEIGHT,
+            corpus_min_word_frequency: CORPUS_MIN_WORD_FREQUENCY,
+            corpus_1_coefficient:      CORPUS_1_COEFFICIENT,
+            corpus_2_coefficient:      CORPUS_2_COEFFICIENT,
+            cycle_temperature_start:   CYCLE_TEMPERATURE_START,
+            cycle_temperature_final:   CYCLE_TEMPERATURE_FINAL,
+            cycle_temperature_factor:  CYCLE_TEMPERATURE_FACTOR,
+            temperature_final:         TEMPERATURE_FINAL,
+            temperature_factor:        TEMPERATURE_FACTOR,
+            num_tabu_swaps:            NUM_TABU_SWAPS,
+            population_size:           POPULATION_SIZE,
+            tournament_size:           TOURNAMENT_SIZE,
+            mutation_swaps:            MUTATION_SWAPS,
+            generations:               GENERATIONS,
+            num_chains:                NUM_CHAINS,
+            pareto_runs:               PARETO_RUNS,
+            pareto_similarity_penalty: PARETO_SIMILARITY_PENALTY,
+            pareto_front_size:         PARETO_FRONT_SIZE,
+            seed:                      SEED,
+        }
+    }
+}
+
+impl Config
+{
+    // Load a configuration file on top of the defaults. A missing file is not an error, since the
+    // defaults are a perfectly good configuration, but a file that exists must be entirely valid.
+    pub fn load(filename: &str) -> Result<Config, String> {
+        let path = Path::new(filename);
+        if std::fs::metadata(path).is_err() {
+            return Ok(Config::default());
+        }
+        let mut text = String::new();
+        let read_result = File::open(path).and_then(|mut file| file.read_to_string(&mut text));
+        if let Err(e) = read_result {
+            return Err(format!("{}: {}", filename, e));
+        }
+        Config::from_string(&text, filename)
+    }
+
+    // Parse the text of a configuration file. The filename is only used in error messages.
+    pub fn from_string(text: &str, filename: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut seen_keys: Vec<String> = Vec::new();
+        for (line_index, raw_line) in text.lines().enumerate() {
+            let line_num = line_index + 1;
+            let line = strip_comment(raw_line).trim();
+            if line.is_empty() || (line.starts_with('[') && line.ends_with(']')) {
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim().to_lowercase(), line[i+1..].trim()),
+                None    => return Err(format!("{}, line {}: expected \"key = value\", found \"{}\"",
+                                              filename, line_num, line))
+            };
+            if seen_keys.contains(&key) {
+                return Err(format!("{}, line {}: key '{}' is set more than once",
+                                   filename, line_num, key));
+            }
+            if let Err(e) = config.set(&key, value) {
+                return Err(format!("{}, line {}: {}", filename, line_num, e));
+            }
+            seen_keys.push(key);
+        }
+        if let Err(e) = config.validate() {
+            return Err(format!("{}: {}", filename, e));
+        }
+        Ok(config)
+    }
+
+    // Assign a single key from its textual value.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "print_objective_function"  => self.print_objective_function  = parse_bool(key, value)?,
+            "geometry"                  => self.geometry                  = parse_string(key, value)?,
+            "extra_characters"          => self.extra_characters          = parse_string(key, value)?,
+            "dead_keys"                 => self.dead_keys                 = parse_string(key, value)?,
+            "layers"                    => self.layers                    = parse_usize(key, value)?,
+            "frozen_symbols"            => self.frozen_symbols            = parse_string(key, value)?,
+            "constraints"               => self.constraints               = parse_string(key, value)?,
+            "shortcut_keys"             => self.shortcut_keys             = parse_string(key, value)?,
+            "shortcut_penalty"          => self.shortcut_penalty          = parse_f32(key, value)?,
+            "fix_shortcut_keys"         => self.fix_shortcut_keys         = parse_bool(key, value)?,
+            "reference_layout"          => self.reference_layout          = parse_string(key, value)?,
+            "similarity_penalty"        => self.similarity_penalty        = parse_f32(key, value)?,
+            "single_metric_coefficient" => self.single_metric_coefficient = parse_f32(key, value)?,
+            "double_metric_coefficient" => self.double_metric_coefficient = parse_f32(key, value)?,
+            "triple_metric_coefficient" => self.triple_metric_coefficient = parse_f32(key, value)?,
+            "shift_holding_penalty"     => self.shift_holding_penalty     = parse_f32(key, value)?,
+            "altgr_holding_penalty"     => self.altgr_holding_penalty     = parse_f32(key, value)?,
+            "altgr_shift_penalty"       => self.altgr_shift_penalty       = parse_f32(key, value)?,
+            "reversed_triple_penalty"   => self.reversed_triple_penalty   = parse_f32(key, value)?,
+            "hand_alternation_penalty"  => self.hand_alternation_penalty  = parse_f32(key, value)?,
+            "running_text"              => self.running_text              = parse_bool(key, value)?,
+            "space_key_metric"          => self.space_key_metric          = parse_f32(key, value)?,
+            "space_skipgram_weight"     => self.space_skipgram_weight     = parse_f32(key, value)?,
+            "corpus_min_word_frequency" => self.corpus_min_word_frequency = parse_f32(key, value)?,
+            "corpus_1_coefficient"      => self.corpus_1_coefficient      = parse_f64(key, value)?,
+            "corpus_2_coefficient"      => self.corpus_2_coefficient      = parse_f64(key, value)?,
+            "cycle_temperature_start"   => self.cycle_temperature_start   = parse_f64(key, value)?,
+            "cycle_temperature_final"   => self.cycle_temperature_final   = parse_f64(key, value)?,
+            "cycle_temperature_factor"  => self.cycle_temperature_factor  = parse_f64(key, value)?,
+            "temperature_final"         => self.temperature_final         = parse_f64(key, value)?,
+            "temperature_factor"        => self.temperature_factor        = parse_f64(key, value)?,
+            "num_tabu_swaps"            => self.num_tabu_swaps            = parse_usize(key, value)?,
+            "population_size"           => self.population_size           = parse_usize(key, value)?,
+            "tournament_size"           => self.tournament_size           = parse_usize(key, value)?,
+            "mutation_swaps"            => self.mutation_swaps            = parse_usize(key, value)?,
+            "generations"               => self.generations               = parse_usize(key, value)?,
+            "num_chains"                => self.num_chains                = parse_usize(key, value)?,
+            "pareto_runs"               => self.pareto_runs               = parse_usize(key, value)?,
+            "pareto_similarity_penalty" => self.pareto_similarity_penalty = parse_f32(key, value)?,
+            "pareto_front_size"         => self.pareto_front_size         = parse_usize(key, value)?,
+            "seed"                      => self.seed                      = Some(parse_u64(key, value)?),
+            _ => return Err(format!("unknown key '{}'", key))
+        }
+        Ok(())
+    }
+
+    // Check the relationships between values that can't be checked one key at a time.
+    fn validate(&self) -> Result<(), String> {
+        let charset = Charset::from_config(self)?;
+        for c in self.frozen_symbols.chars() {
+            if c == ' ' || charset.index(c).is_none() {
+                return Err(format!("key 'frozen_symbols' contains the invalid character {:?}", c));
+            }
+        }
+        for c in self.shortcut_keys.chars() {
+            if !c.is_ascii_alphabetic() {
+                return Err(format!("key 'shortcut_keys' contains {:?}, which is not a letter", c));
+            }
+        }
+        if !(2..=4).contains(&self.layers) {
+            return Err("key 'layers' must be 2, 3 or 4".to_string());
+        }
+        if !(self.cycle_temperature_factor > 0.0 && self.cycle_temperature_factor < 1.0) {
+            return Err("key 'cycle_temperature_factor' must be between 0 and 1".to_string());
+        }
+        if !(self.temperature_factor > 0.0 && self.temperature_factor < 1.0) {
+            return Err("key 'temperature_factor' must be between 0 and 1".to_string());
+        }
+        if self.cycle_temperature_final <= 0.0 {
+            return Err("key 'cycle_temperature_final' must be positive".to_string());
+        }
+        if self.temperature_final <= 0.0 {
+            return Err("key 'temperature_final' must be positive".to_string());
+        }
+        if self.cycle_temperature_start < self.cycle_temperature_final {
+            return Err("key 'cycle_temperature_start' must not be less than \
+                        'cycle_temperature_final'".to_string());
+        }
+        if self.num_chains == 0 {
+            return Err("key 'num_chains' must be at least 1".to_string());
+        }
+        if self.population_size < 2 || self.tournament_size == 0 {
+            return Err("key 'population_size' must be at least 2 and 'tournament_size' at least 1"
+                       .to_string());
+        }
+        if self.pareto_runs == 0 {
+            return Err("key 'pareto_runs' must be at least 1".to_string());
+        }
+        if self.pareto_front_size < 2 {
+            return Err("key 'pareto_front_size' must be at least 2".to_string());
+        }
+        if self.corpus_1_coefficient < 0.0 || self.corpus_2_coefficient < 0.0 {
+            return Err("corpus coefficients must not be negative".to_string());
+        }
+        Ok(())
+    }
+
+    // All keys and their current values, formatted the same way they are written in a file.
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let mut entries = vec![
+            ("print_objective_function",  self.print_objective_function.to_string()),
+            ("geometry",                  format_string(&self.geometry)),
+            ("extra_characters",          format_string(&self.extra_characters)),
+            ("dead_keys",                 format_string(&self.dead_keys)),
+            ("layers",                    self.layers.to_string()),
+            ("frozen_symbols",            format_string(&self.frozen_symbols)),
+            ("constraints",               format_string(&self.constraints)),
+            ("shortcut_keys",             format_string(&self.shortcut_keys)),
+            ("shortcut_penalty",          self.shortcut_penalty.to_string()),
+            ("fix_shortcut_keys",         self.fix_shortcut_keys.to_string()),
+            ("reference_layout",          format_string(&self.reference_layout)),
+            ("similarity_penalty",        self.similarity_penalty.to_string()),
+            ("single_metric_coefficient", self.single_metric_coefficient.to_string()),
+            ("double_metric_coefficient", self.double_metric_coefficient.to_string()),
+            ("triple_metric_coefficient", self.triple_metric_coefficient.to_string()),
+            ("shift_holding_penalty",     self.shift_holding_penalty.to_string()),
+            ("altgr_holding_penalty",     self.altgr_holding_penalty.to_string()),
+            ("altgr_shift_penalty",       self.altgr_shift_penalty.to_string()),
+            ("reversed_triple_penalty",   self.reversed_triple_penalty.to_string()),
+            ("hand_alternation_penalty",  self.hand_alternation_penalty.to_string()),
+            ("running_text",              self.running_text.to_string()),
+            ("space_key_metric",          self.space_key_metric.to_string()),
+            ("space_skipgram_weight",     self.space_skipgram_weight.to_string()),
+            ("corpus_min_word_frequency", self.corpus_min_word_frequency.to_string()),
+            ("corpus_1_coefficient",      self.corpus_1_coefficient.to_string()),
+            ("corpus_2_coefficient",      self.corpus_2_coefficient.to_string()),
+            ("cycle_temperature_start",   self.cycle_temperature_start.to_string()),
+            ("cycle_temperature_final",   self.cycle_temperature_final.to_string()),
+            ("cycle_temperature_factor",  self.cycle_temperature_factor.to_string()),
+            ("temperature_final",         self.temperature_final.to_string()),
+            ("temperature_factor",        self.temperature_factor.to_string()),
+            ("num_tabu_swaps",            self.num_tabu_swaps.to_string()),
+            ("population_size",           self.population_size.to_string()),
+            ("tournament_size",           self.tournament_size.to_string()),
+            ("mutation_swaps",            self.mutation_swaps.to_string()),
+            ("generations",               self.generations.to_string()),
+            ("num_chains",                self.num_chains.to_string()),
+            ("pareto_runs",               self.pareto_runs.to_string()),
+            ("pareto_similarity_penalty", self.pareto_similarity_penalty.to_string()),
+            ("pareto_front_size",         self.pareto_front_size.to_string()),
+        ];
+        if let Some(seed) = self.seed {
+            entries.push(("seed", seed.to_string()));
+        }
+        entries
+    }
+
+    // Write out the effective configuration, in a form that can be pasted back into a file to
+    // reproduce a run exactly.
+    pub fn to_config_string(&self) -> String {
+        let entries = self.entries();
+        let width = entries.iter().map(|e| e.0.len()).max().unwrap_or(0);
+        let mut text = String::new();
+        for (key, value) in entries {
+            text.push_str(&format!("{:width$} = {}\n", key, value, width = width));
+        }
+        text
+    }
+
+    pub fn print(&self) {
+        println!("# Effective configuration");
+        print!("{}", self.to_config_string());
+        println!();
+    }
+}
+
+// Remove a trailing comment from a line, leaving any "#" inside a quoted string alone.
+pub fn strip_comment(line: &str) -> &str
+{
+    let mut in_string = false;
+    let mut escaped   = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && in_string {
+            escaped = true;
+        } else if c == '"' {
+            in_string = !in_string;
+        } else if c == '#' && !in_string {
+            return &line[..i];
+        }
+    }
+    line
+}
+
+pub fn parse_bool(key: &str, value: &str) -> Result<bool, String>
+{
+    match value {
+        "true"  => Ok(true),
+        "false" => Ok(false),
+        _       => Err(format!("key '{}' expects true or false, found '{}'", key, value))
+    }
+}
+
+pub fn parse_f32(key: &str, value: &str) -> Result<f32, String>
+{
+    match value.parse::<f32>() {
+        Ok(x) if x.is_finite() => Ok(x),
+        _ => Err(format!("key '{}' expects a finite number, found '{}'", key, value))
+    }
+}
+
+pub fn parse_f64(key: &str, value: &str) -> Result<f64, String>
+{
+    match value.parse::<f64>() {
+        Ok(x) if x.is_finite() => Ok(x),
+        _ => Err(format!("key '{}' expects a finite number, found '{}'", key, value))
+    }
+}
+
+pub fn parse_u64(key: &str, value: &str) -> Result<u64, String>
+{
+    match value.parse::<u64>() {
+        Ok(x) => Ok(x),
+        _     => Err(format!("key '{}' expects a non-negative integer, found '{}'", key, value))
+    }
+}
+
+pub fn parse_usize(key: &str, value: &str) -> Result<usize, String>
+{
+    match value.parse::<usize>() {
+        Ok(x) => Ok(x),
+        _     => Err(format!("key '{}' expects a non-negative integer, found '{}'", key, value))
+    }
+}
+
+// Parse a double-quoted string with backslash escapes for quotes and backslashes.
+pub fn parse_string(key: &str, value: &str) -> Result<String, String>
+{
+    let error = || format!("key '{}' expects a double-quoted string, found '{}'", key, value);
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return Err(error());
+    }
+    let mut result  = String::new();
+    let mut escaped = false;
+    for c in value[1..value.len()-1].chars() {
+        if escaped {
+            match c {
+                '"' | '\\' => result.push(c),
+                _          => return Err(format!("key '{}' has an unknown escape \\{}", key, c))
+            }
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            return Err(error());
+        } else {
+            result.push(c);
+        }
+    }
+    if escaped {
+        return Err(error());
+    }
+    Ok(result)
+}
+
+pub fn format_string(s: &str) -> String
+{
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[test]
+fn config_round_trip_test()
+{
+    let config = Config{
+        frozen_symbols: "0123\"\\".to_string(),
+        num_tabu_swaps: 7,
+        seed:           Some(12345678901234),
+        ..Config::default()
+    };
+    let text = config.to_config_string();
+    let parsed = Config::from_string(&text, "test").unwrap();
+    assert_eq!(parsed.entries(), config.entries());
+}
+
+#[test]
+fn config_error_names_key_test()
+{
+    let err = Config::from_string("[annealing]\ntemperature_factor = hot\n", "test").err().unwrap();
+    assert!(err.contains("line 2") && err.contains("temperature_factor"), "{}", err);
+    let err = Config::from_string("no_such_key = 1 # comment\n", "test").err().unwrap();
+    assert!(err.contains("no_such_key"), "{}", err);
+}
@@ -0,0 +1,455 @@
+// LAYOUT CONSTRAINTS
+
+// Besides the frozen symbols of the configuration, a constraint file can say where characters may
+// and may not go, and which characters have to stay together. Each line is one of the following,
+// and "#" starts a comment:
+//
+//   pin      = "1234567890" 2           pin the characters to consecutive positions, counted from 1
+//                                       in the order of the layout file, so that on the ANSI board
+//                                       this pins the digits to keys 2 to 11; a letter is pinned
+//                                       along with its other case, on the same key
+//   forbid   = "aeiou" 1-13 19 25       keep the characters off the listed keys and ranges of keys,
+//                                       on every layer
+//   adjacent = "()"                     keep the second character on the key just right of the
+//                                       first, on the same layer
+//   same_key = ",<"                     keep the two characters on the same key
+//
+// The shortcut letters of the configuration are pinned to their QWERTY keys when they are fixed, and
+// the board keeps the letters off some keys with forbid rules of its own (see geometry.rs).
+// The pinned characters of the starting layout are moved to their positions before optimizing.
+// Pinned characters are never moved, like frozen ones. The optimizer only makes moves that keep
+// every constraint satisfied: it never draws a swap that would put a character on a forbidden key,
+// and when a character that belongs to an adjacent pair or shares a key with a partner moves, its
+// partner moves along with it, swapping with whatever symbol is in the way, and the move is
+// undone if that still breaks a constraint.
+
+use std::fs::File;
+use std::io::Read;
+
+use charset::Charset;
+use config::{self, Config};
+use geometry::Geometry;
+use super::{NONE, layout_from_string};
+
+#[derive(Clone)]
+pub struct Constraints
+{
+    names:     Vec<char>,
+    num_chars: usize,
+    num_keys:  usize,
+    right_key: Vec<usize>,
+    fixed:     Vec<bool>,
+    movable:   Vec<bool>,
+    pins:      Vec<(u8, usize)>,
+    forbidden: Vec<Vec<bool>>,
+    partnered: Vec<bool>,
+    adjacent:  Vec<(u8, u8)>,
+    same_key:  Vec<(u8, u8)>,
+}
+
+impl Constraints
+{
+    // No constraints other than the frozen symbols, and the shortcut letters if they are fixed.
+    pub fn new(config: &Config, geometry: &Geometry, charset: &Charset) -> Constraints {
+        let num_chars = charset.len();
+        let mut right_key = vec![0; geometry.keys.len()];
+        for row in geometry.rows().iter() {
+            for pair in row.windows(2) {
+                right_key[pair[0]] = pair[1];
+            }
+        }
+        let mut constraints = Constraints{
+            names:     (0..num_chars).map(|i| charset.char(i as u8)).collect(),
+            num_chars,
+            num_keys:  geometry.num_keys(),
+            right_key,
+            fixed:     vec![false; num_chars],
+            movable:   vec![false; num_chars],
+            pins:      Vec::new(),
+            forbidden: vec![Vec::new(); num_chars],
+            partnered: vec![false; num_chars],
+            adjacent:  Vec::new(),
+            same_key:  Vec::new(),
+        };
+        for c in config.frozen_symbols.chars() {
+            constraints.fixed[charset.index(c).unwrap() as usize] = true;
+        }
+        if config.fix_shortcut_keys && !config.shortcut_keys.is_empty() {
+            let qwerty = layout_from_string(&geometry.qwerty_layout(), geometry, charset);
+            for c in config.shortcut_keys.chars() {
+                for c in [c.to_ascii_lowercase(), c.to_ascii_uppercase()].iter() {
+                    let c = charset.index(*c).unwrap();
+                    constraints.pins.push((c, qwerty[c as usize] as usize));
+                    constraints.fixed[c as usize] = true;
+                }
+            }
+        }
+        constraints.update_movable(charset);
+        constraints
+    }
+
+    // The constraints of a configuration: those of its board, and those of its constraint file if
+    // it has one.
+    pub fn from_config(config: &Config, geometry: &Geometry, charset: &Charset)
+        -> Result<Constraints, String> {
+        let mut constraints = Constraints::new(config, geometry, charset);
+        constraints.add_string(&geometry.constraints, &geometry.name, geometry, charset)?;
+        if !config.constraints.is_empty() {
+            let filename = &config.constraints;
+            let mut text = String::new();
+            let read_result = File::open(filename).and_then(|mut file| file.read_to_string(&mut text));
+            if let Err(e) = read_result {
+                return Err(format!("{}: {}", filename, e));
+            }
+            constraints.add_string(&text, filename, geometry, charset)?;
+        }
+        Ok(constraints)
+    }
+
+    // Add the constraints of a constraint file.
+    pub fn add_string(&mut self, text: &str, filename: &str, geometry: &Geometry, charset: &Charset)
+        -> Result<(), String> {
+        let num_positions = geometry.num_positions();
+        for (line_index, raw_line) in text.lines().enumerate() {
+            let line = config::strip_comment(raw_line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |e: String| format!("{}, line {}: {}", filename, line_index + 1, e);
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i+1..].trim()),
+                None    => return Err(error(format!("expected \"key = value\", found \"{}\"", line)))
+            };
+            let (string, rest) = split_string(key, value).map_err(&error)?;
+            let mut chars = Vec::new();
+            for c in string.chars() {
+                match charset.index(c) {
+                    Some(i) if i != 0 => chars.push(i),
+                    _ => return Err(error(format!("the character {:?} is not in the character set", c)))
+                }
+            }
+            let fields = rest.split_whitespace().collect::<Vec<&str>>();
+            let pair = || -> Result<(u8, u8), String> {
+                match chars.len() {
+                    2 => Ok((chars[0], chars[1])),
+                    _ => Err(format!("key '{}' expects a string of two characters", key))
+                }
+            };
+            match key {
+                "pin" => {
+                    let last = num_positions.checked_sub(chars.len());
+                    let first = match (fields.first().map(|x| x.parse::<usize>()), last) {
+                        (Some(Ok(p)), Some(last)) if fields.len() == 1 && p >= 1 && p <= last => p,
+                        _ => return Err(error(format!("key 'pin' expects a string and a position from \
+                                                       1 to {}", last.unwrap_or(0))))
+                    };
+                    for (i, &c) in chars.iter().enumerate() {
+                        let p = first + i;
+                        let shifted = geometry.is_shifted(p);
+                        if (charset.is_lower_case_letter(c) && shifted) ||
+                           (charset.is_upper_case_letter(c) && !shifted) {
+                            return Err(error(format!("the letter {:?} can't be pinned to the {} \
+                                                      position {}", charset.char(c),
+                                                     if shifted { "shifted" } else { "unshifted" }, p)));
+                        }
+                        self.pins.push((c, p));
+                        self.fixed[c as usize] = true;
+                        // The other case of a letter stays on the same key
+                        if let Some(o) = charset.other_case(c) {
+                            let num_keys = geometry.num_keys();
+                            self.pins.push((o, if shifted { p - num_keys } else { p + num_keys }));
+                            self.fixed[o as usize] = true;
+                        }
+                    }
+                },
+                "forbid" => {
+                    let keys = parse_keys(&fields, self.num_keys).map_err(&error)?;
+                    for &c in chars.iter() {
+                        let forbidden = &mut self.forbidden[c as usize];
+                        forbidden.resize(keys.len(), false);
+                        for (f, &k) in forbidden.iter_mut().zip(keys.iter()) {
+                            *f |= k;
+                        }
+                    }
+                },
+                "adjacent" | "same_key" if !fields.is_empty() => {
+                    return Err(error(format!("key '{}' expects only a string", key)));
+                },
+                "adjacent" => self.adjacent.push(pair().map_err(&error)?),
+                "same_key" => self.same_key.push(pair().map_err(&error)?),
+                _ => return Err(error(format!("unknown key '{}'", key)))
+            }
+        }
+        self.update_movable(charset);
+        Ok(())
+    }
+
+    // Characters that can be moved out of the way of a partner: the symbols that aren't fixed. Also
+    // mark the characters that have a partner.
+    fn update_movable(&mut self, charset: &Charset) {
+        for c in 1..self.num_chars {
+            self.movable[c] = !self.fixed[c] && !charset.is_letter(c as u8);
+        }
+        for &(a, b) in self.adjacent.iter().chain(self.same_key.iter()) {
+            self.partnered[a as usize] = true;
+            self.partnered[b as usize] = true;
+        }
+    }
+
+    // Whether a character is frozen or pinned.
+    pub fn is_fixed(&self, c: u8) -> bool {
+        self.fixed[c as usize]
+    }
+
+    // Whether any character is kept off some keys.
+    pub fn has_forbidden_keys(&self) -> bool {
+        self.forbidden.iter().any(|f| !f.is_empty())
+    }
+
+    // Whether a character may go on a key. Leaving a position empty is always allowed.
+    pub fn allows(&self, c: u8, key: usize) -> bool {
+        c == NONE || !self.forbidden[c as usize].get(key).cloned().unwrap_or(false)
+    }
+
+    // Whether a character has to stay adjacent to or on the same key as another one.
+    pub fn has_partner(&self, c: u8) -> bool {
+        c != NONE && self.partnered[c as usize]
+    }
+
+    fn key(&self, position: usize) -> usize {
+        (position - 1) % self.num_keys + 1
+    }
+
+    // The position just right of another on the same layer, if there is one.
+    fn right_of(&self, position: usize) -> Option<usize> {
+        let k = self.key(position);
+        match self.right_key[k] {
+            0 => None,
+            r => Some(position - k + r),
+        }
+    }
+
+    fn left_of(&self, position: usize) -> Option<usize> {
+        let k = self.key(position);
+        self.right_key.iter().position(|&r| r == k).map(|l| position - k + l)
+    }
+
+    // Check a layout against every constraint, describing the first one it breaks.
+    pub fn check(&self, layout: &[u8]) -> Result<(), String> {
+        let name     = |c: u8| self.names[c as usize];
+        let position = |c: u8| layout[c as usize] as usize;
+        let placed   = |c: u8| layout[c as usize] != NONE;
+        for &(c, p) in self.pins.iter() {
+            if position(c) != p {
+                return Err(format!("'{}' must be at position {}", name(c), p));
+            }
+        }
+        for c in 1..self.num_chars as u8 {
+            if placed(c) && !self.allows(c, self.key(position(c))) {
+                return Err(format!("'{}' must not be on key {}", name(c), self.key(position(c))));
+            }
+        }
+        for &(a, b) in self.adjacent.iter() {
+            let satisfied = match (placed(a), placed(b)) {
+                (true, true)   => self.right_of(position(a)) == Some(position(b)),
+                (false, false) => true,
+                _              => false,
+            };
+            if !satisfied {
+                return Err(format!("'{}' must be just right of '{}'", name(b), name(a)));
+            }
+        }
+        for &(a, b) in self.same_key.iter() {
+            let satisfied = match (placed(a), placed(b)) {
+                (true, true)   => self.key(position(a)) == self.key(position(b)),
+                (false, false) => true,
+                _              => false,
+            };
+            if !satisfied {
+                return Err(format!("'{}' and '{}' must be on the same key", name(a), name(b)));
+            }
+        }
+        Ok(())
+    }
+
+    // Move the pinned characters of a starting layout to their positions, each swapping places with
+    // whatever is there, so that a layout made without the pins in mind can still be optimized.
+    pub fn place_pins(&self, layout: &mut [u8]) {
+        for &(c, target) in self.pins.iter() {
+            let p = layout[c as usize];
+            let occupant = layout[self.num_chars + target];
+            if occupant == c {
+                continue;
+            }
+            layout[c as usize] = target as u8;
+            layout[self.num_chars + target] = c;
+            if p != NONE {
+                layout[self.num_chars + p as usize] = occupant;
+            }
+            if occupant != NONE {
+                layout[occupant as usize] = p;
+            }
+        }
+    }
+
+    // Move the partners of characters that have just moved along with them, and check whether the
+    // result satisfies every constraint. The layout is left in an unspecified state if it doesn't.
+    pub fn follow(&self, layout: &mut [u8], moved: &[u8]) -> bool {
+        for &c in moved.iter() {
+            if c == NONE || layout[c as usize] == NONE {
+                continue;
+            }
+            let p = layout[c as usize] as usize;
+            for &(a, b) in self.adjacent.iter() {
+                let target = if a == c {
+                    self.right_of(p).map(|t| (b, t))
+                } else if b == c {
+                    self.left_of(p).map(|t| (a, t))
+                } else {
+                    continue;
+                };
+                match target {
+                    Some((partner, t)) if self.move_to(layout, partner, t) => {},
+                    _ => return false,
+                }
+            }
+            for &(a, b) in self.same_key.iter() {
+                let partner = if a == c { b } else if b == c { a } else { continue };
+                let q = layout[partner as usize] as usize;
+                if q == NONE as usize {
+                    return false;
+                }
+                let t = q - self.key(q) + self.key(p);
+                if t == p || !self.move_to(layout, partner, t) {
+                    return false;
+                }
+            }
+        }
+        self.check(layout).is_ok()
+    }
+
+    // Move a character to a position, swapping it with the symbol there. Nothing is moved onto an
+    // empty key, except on the AltGr layers.
+    fn move_to(&self, layout: &mut [u8], c: u8, target: usize) -> bool {
+        let q = layout[c as usize] as usize;
+        let occupant = layout[self.num_chars + target];
+        if occupant == c {
+            return true;
+        }
+        let layer = (target - 1) / self.num_keys;
+        if (occupant == NONE && layer < 2) || (occupant != NONE && !self.movable[occupant as usize]) {
+            return false;
+        }
+        layout[c as usize] = target as u8;
+        layout[self.num_chars + target] = c;
+        layout[self.num_chars + q] = occupant;
+        if occupant != NONE {
+            layout[occupant as usize] = q as u8;
+        }
+        true
+    }
+}
+
+// Split a value into the double-quoted string at its start and the fields after it.
+fn split_string<'a>(key: &str, value: &'a str) -> Result<(String, &'a str), String>
+{
+    let mut escaped = false;
+    for (i, c) in value.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            return Ok((config::parse_string(key, &value[..i+1])?, &value[i+1..]));
+        }
+    }
+    config::parse_string(key, value).map(|s| (s, ""))
+}
+
+// Parse a list of keys and ranges of keys, like "1-13 19 25", into a flag for each key.
+fn parse_keys(fields: &[&str], num_keys: usize) -> Result<Vec<bool>, String>
+{
+    let mut keys = vec![false; num_keys + 1];
+    if fields.is_empty() {
+        return Err("key 'forbid' expects a string and a list of keys".to_string());
+    }
+    for field in fields.iter() {
+        let mut bounds = field.splitn(2, '-').map(|x| x.parse::<usize>());
+        let first = bounds.next();
+        let last  = bounds.next().unwrap_or_else(|| first.clone().unwrap());
+        match (first, last) {
+            (Some(Ok(a)), Ok(b)) if 1 <= a && a <= b && b <= num_keys => {
+                for key in keys[a..=b].iter_mut() {
+                    *key = true;
+                }
+            },
+            _ => return Err(format!("key 'forbid' expects keys from 1 to {}, found '{}'", num_keys,
+                                    field))
+        }
+    }
+    Ok(keys)
+}
+
+#[test]
+fn constraints_test()
+{
+    use super::{WHITE_STRING, LayoutSwapper, SeededRng, layout_from_string};
+
+    let config = Config::default();
+    let geometry = Geometry::ansi();
+    let charset = Charset::ascii();
+    let text = "pin = \"#1\" 1  # the top left keys\nforbid = \"(\" 1-13\nadjacent = \"()\"\n\
+                adjacent = \"[]\"\nsame_key = \"-+\"\n";
+    let mut constraints = Constraints::new(&config, &geometry, &charset);
+    constraints.add_string(text, "test", &geometry, &charset).unwrap();
+    let index = |c: char| charset.index(c).unwrap();
+    assert!(constraints.is_fixed(index('#')) && constraints.is_fixed(index('5')));
+    let layout = layout_from_string(WHITE_STRING, &geometry, &charset);
+    assert!(constraints.check(&layout).is_ok());
+
+    // Moving [ one key to the left takes ] with it
+    let mut moved = layout.clone();
+    assert!(constraints.move_to(&mut moved, index('['), 57));
+    assert!(constraints.check(&moved).is_err());
+    assert!(constraints.follow(&mut moved, &[index('[')]));
+    assert_eq!((moved[index('[') as usize], moved[index(']') as usize]), (57, 58));
+
+    // Moving ( onto the top row is forbidden even though ) can follow it
+    let mut moved = layout.clone();
+    assert!(constraints.move_to(&mut moved, index('('), 7));
+    assert!(!constraints.follow(&mut moved, &[index('(')]));
+
+    // The swapper only makes moves that satisfy the constraints, including those of the board
+    let mut constraints = Constraints::from_config(&config, &geometry, &charset).unwrap();
+    constraints.add_string(text, "test", &geometry, &charset).unwrap();
+    let mut swapper = LayoutSwapper::new(&layout, &config, &geometry, &charset, &constraints);
+    let mut rng = SeededRng::new(1);
+    let mut moved = layout.clone();
+    for _ in 0..2000 {
+        assert!(swapper.swap(&mut moved, &mut rng));
+        assert!(constraints.check(&moved).is_ok(), "{}", constraints.check(&moved).err().unwrap());
+    }
+
+    let err = Constraints::new(&config, &geometry, &charset)
+                          .add_string("forbid = \"a\" 0-3\n", "test", &geometry, &charset).err().unwrap();
+    assert!(err.contains("line 1") && err.contains("forbid"), "{}", err);
+
+    // A letter is pinned along with its other case, and only to a position of its own case
+    let mut constraints = Constraints::new(&config, &geometry, &charset);
+    constraints.add_string("pin = \"a\" 30\n", "test", &geometry, &charset).unwrap();
+    assert!(constraints.is_fixed(index('A')));
+    let mut pinned = layout.clone();
+    constraints.place_pins(&mut pinned);
+    assert!(constraints.check(&pinned).is_ok());
+    assert_eq!(pinned[index('A') as usize] as usize, 30 + geometry.num_keys());
+    for text in ["\n\npin = \"A\" 20\n", "\n\npin = \"a\" 70\n"].iter() {
+        let err = Constraints::new(&config, &geometry, &charset)
+                              .add_string(text, "test", &geometry, &charset).err().unwrap();
+        assert!(err.contains("line 3") && err.contains("pinned"), "{}", err);
+    }
+    let symbols = (33u8..127).map(|c| c as char).filter(|&c| c != '"' && c != '\\').collect::<String>();
+    let long = format!("pin = \"{}\" 1\n", symbols.repeat(3));
+    let err = Constraints::new(&config, &geometry, &charset)
+                          .add_string(&long, "test", &geometry, &charset).err().unwrap();
+    assert!(err.contains("expects a string and a position"), "{}", err);
+}
@@ -0,0 +1,315 @@
+// FIRMWARE KEYMAPS
+
+// Programmable keyboards can hold a layout in their firmware, so that the computer keeps the plain
+// US layout and the keyboard sends whatever US keys type the characters of the layout. "export
+// --format qmk" writes the keymap.c of a 60% board for QMK and "--format zmk" the .keymap of one for
+// ZMK, for a layout of the ANSI or ISO board, with the keys around the layout (Tab, Enter, the
+// modifiers and so on) where they usually are. ZMK has no layout macros, so its bindings are in
+// the same order and may need rearranging for the matrix of a particular board.
+//
+// Firmware keycodes assume the US shift pairs: the key that sends 2 sends @ with Shift. Where the
+// shifted character of a key isn't the one the US layout pairs with its unshifted character, the
+// key gets a shift override, a QMK key override or a ZMK mod-morph behavior, which sends the right
+// character instead while Shift is held. QMK needs KEY_OVERRIDE_ENABLE = yes in rules.mk for these.
+//
+// AltGr layers go on a firmware layer of their own, held with the right Alt key. Characters outside
+// ASCII are sent as Unicode in QMK, which needs UNICODE_ENABLE = yes and a Unicode input mode set up
+// on the computer, and can't be sent by ZMK at all. Keyboard shortcuts follow the characters, and
+// dead key sequences are left to the computer, which sees the characters that start them like any
+// other. A key with nothing unshifted has no QMK shift override, since QMK matches overrides by the
+// key's own keycode.
+
+use charset::Charset;
+use geometry::{Geometry, ISO_EXTRA_KEY};
+use super::NONE;
+
+// The keys of the US layout, with their unshifted and shifted characters and their keycodes in QMK
+// and ZMK.
+const US_KEYS: [(char, char, &str, &str); 48] = [
+    (' ', ' ', "KC_SPC", "SPACE"), ('`', '~', "KC_GRV", "GRAVE"), ('1', '!', "KC_1", "N1"),
+    ('2', '@', "KC_2", "N2"), ('3', '#', "KC_3", "N3"), ('4', '$', "KC_4", "N4"),
+    ('5', '%', "KC_5", "N5"), ('6', '^', "KC_6", "N6"), ('7', '&', "KC_7", "N7"),
+    ('8', '*', "KC_8", "N8"), ('9', '(', "KC_9", "N9"), ('0', ')', "KC_0", "N0"),
+    ('-', '_', "KC_MINS", "MINUS"), ('=', '+', "KC_EQL", "EQUAL"), ('[', '{', "KC_LBRC", "LBKT"),
+    (']', '}', "KC_RBRC", "RBKT"), ('\\', '|', "KC_BSLS", "BSLH"), (';', ':', "KC_SCLN", "SEMI"),
+    ('\'', '"', "KC_QUOT", "SQT"), (',', '<', "KC_COMM", "COMMA"), ('.', '>', "KC_DOT", "DOT"),
+    ('/', '?', "KC_SLSH", "FSLH"), ('a', 'A', "KC_A", "A"), ('b', 'B', "KC_B", "B"),
+    ('c', 'C', "KC_C", "C"), ('d', 'D', "KC_D", "D"), ('e', 'E', "KC_E", "E"),
+    ('f', 'F', "KC_F", "F"), ('g', 'G', "KC_G", "G"), ('h', 'H', "KC_H", "H"),
+    ('i', 'I', "KC_I", "I"), ('j', 'J', "KC_J", "J"), ('k', 'K', "KC_K", "K"),
+    ('l', 'L', "KC_L", "L"), ('m', 'M', "KC_M", "M"), ('n', 'N', "KC_N", "N"),
+    ('o', 'O', "KC_O", "O"), ('p', 'P', "KC_P", "P"), ('q', 'Q', "KC_Q", "Q"),
+    ('r', 'R', "KC_R", "R"), ('s', 'S', "KC_S", "S"), ('t', 'T', "KC_T", "T"),
+    ('u', 'U', "KC_U", "U"), ('v', 'V', "KC_V", "V"), ('w', 'W', "KC_W", "W"),
+    ('x', 'X', "KC_X", "X"), ('y', 'Y', "KC_Y", "Y"), ('z', 'Z', "KC_Z", "Z"),
+];
+
+// A key of a 60% board: either a PC key of the layout or a key around it, with its QMK and ZMK
+// keycodes.
+#[derive(Clone, Copy)]
+enum BoardKey
+{
+    Layout(usize),
+    Other(&'static str, &'static str),
+}
+
+// What a key sends: nothing, a US key with or without Shift, or a Unicode character.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Code
+{
+    Nothing,
+    Us(usize, bool),
+    Unicode(char),
+}
+
+// The code that sends a character.
+fn code(c: Option<char>) -> Code
+{
+    let c = match c {
+        Some(c) => c,
+        None    => return Code::Nothing,
+    };
+    match (US_KEYS.iter().position(|k| k.0 == c), US_KEYS.iter().position(|k| k.1 == c)) {
+        (Some(i), _)    => Code::Us(i, false),
+        (None, Some(i)) => Code::Us(i, true),
+        (None, None)    => Code::Unicode(c),
+    }
+}
+
+// What a code sends while Shift is held, without a shift override.
+fn shifted(code: Code) -> Code
+{
+    match code {
+        Code::Us(i, _) => Code::Us(i, true),
+        other          => other,
+    }
+}
+
+fn qmk_code(code: Code) -> String
+{
+    match code {
+        Code::Nothing      => "KC_NO".to_string(),
+        Code::Us(i, false) => US_KEYS[i].2.to_string(),
+        Code::Us(i, true)  => format!("S({})", US_KEYS[i].2),
+        Code::Unicode(c)   => format!("UC(0x{:04X})", c as u32),
+    }
+}
+
+fn zmk_code(code: Code) -> String
+{
+    match code {
+        Code::Us(i, false) => format!("&kp {}", US_KEYS[i].3),
+        Code::Us(i, true)  => format!("&kp LS({})", US_KEYS[i].3),
+        _                  => "&none".to_string(),
+    }
+}
+
+// A ZMK shift override, which sends the shifted code instead of the unshifted one while Shift is held.
+fn mod_morph(i: usize, unshifted: Code, shifted: Code) -> String
+{
+    format!("        so{0}: shift_override_{0} {{\n\
+             \x20           compatible = \"zmk,behavior-mod-morph\";\n\
+             \x20           #binding-cells = <0>;\n\
+             \x20           bindings = <{1}>, <{2}>;\n\
+             \x20           mods = <(MOD_LSFT|MOD_RSFT)>;\n\
+             \x20       }};\n", i, zmk_code(unshifted), zmk_code(shifted))
+}
+
+// The rows of keys of a 60% board, with the right Alt key holding the AltGr layer if there is one
+// and Caps Lock as backspace on request. The space bar is always a space.
+fn board_rows(iso: bool, altgr: bool, caps_backspace: bool) -> Vec<Vec<BoardKey>>
+{
+    use self::BoardKey::{Layout, Other};
+    let mut rows = vec![Vec::new(); 5];
+    rows[0].extend((1..14).map(Layout));
+    rows[0].push(Other("KC_BSPC", "&kp BSPC"));
+    rows[1].push(Other("KC_TAB", "&kp TAB"));
+    rows[1].extend((14..26).map(Layout));
+    rows[2].push(match caps_backspace {
+        true  => Other("KC_BSPC", "&kp BSPC"),
+        false => Other("KC_CAPS", "&kp CAPS"),
+    });
+    rows[2].extend((27..38).map(Layout));
+    rows[3].push(Other("KC_LSFT", "&kp LSHFT"));
+    rows[3].extend((38..48).map(Layout));
+    rows[3].push(Other("KC_RSFT", "&kp RSHFT"));
+    if iso {
+        rows[2].push(Layout(26));
+        rows[3].insert(1, Layout(ISO_EXTRA_KEY));
+    } else {
+        rows[1].push(Layout(26));
+    }
+    rows[2].push(Other("KC_ENT", "&kp RET"));
+    rows[4] = vec![Other("KC_LCTL", "&kp LCTRL"), Other("KC_LGUI", "&kp LGUI"),
+                   Other("KC_LALT", "&kp LALT"), Other("KC_SPC", "&kp SPACE"),
+                   if altgr { Other("MO(1)", "&mo 1") } else { Other("KC_RALT", "&kp RALT") },
+                   Other("KC_RGUI", "&kp RGUI"), Other("KC_APP", "&kp K_APP"),
+                   Other("KC_RCTL", "&kp RCTRL")];
+    rows
+}
+
+// The unshifted and shifted codes of every PC key but the space bar on each firmware layer, by PC
+// key.
+fn codes(layout: &[u8], geometry: &Geometry, charset: &Charset)
+    -> Result<Vec<Vec<(Code, Code)>>, String>
+{
+    let pc_keys   = geometry.pc_keys()?;
+    let num_chars = charset.len();
+    let char_at   = |k: usize, layer: usize| match layout[num_chars + geometry.position(k, layer)] {
+        NONE => None,
+        c    => Some(charset.char(c)),
+    };
+    Ok((0..geometry.layers / 2).map(|firmware_layer| {
+        let mut codes = vec![(Code::Nothing, Code::Nothing); ISO_EXTRA_KEY + 1];
+        for k in 1..geometry.num_keys() + 1 {
+            let layer = 2 * firmware_layer;
+            codes[pc_keys[k]] = (code(char_at(k, layer)), code(char_at(k, layer + 1)));
+        }
+        codes
+    }).collect())
+}
+
+// Write a layout as a QMK keymap.c.
+pub fn qmk_file(layout: &[u8], geometry: &Geometry, charset: &Charset, name: &str,
+                caps_backspace: bool) -> Result<String, String>
+{
+    let codes = codes(layout, geometry, charset)?;
+    let iso   = geometry.pc_keys()?.contains(&ISO_EXTRA_KEY);
+    let rows  = board_rows(iso, codes.len() > 1, caps_backspace);
+
+    let mut overrides = Vec::new();
+    for (layer, layer_codes) in codes.iter().enumerate() {
+        for &(unshifted, shifted_code) in layer_codes.iter() {
+            if unshifted != Code::Nothing && shifted(unshifted) != shifted_code {
+                overrides.push(format!("ko_make_with_layers(MOD_MASK_SHIFT, {}, {}, 1 << {})",
+                                       qmk_code(unshifted), qmk_code(shifted_code), layer));
+            }
+        }
+    }
+
+    let mut keymap = format!("// The {} keyboard layout for QMK, as the keymap of a 60% {} board\n\n\
+                              #include QMK_KEYBOARD_H\n\n", name, if iso { "ISO" } else { "ANSI" });
+    if !overrides.is_empty() {
+        keymap.push_str("// Shifted characters that aren't the US shift pairs of their keys\n");
+        for (i, key_override) in overrides.iter().enumerate() {
+            keymap.push_str(&format!("const key_override_t shift_override_{} = {};\n", i, key_override));
+        }
+        keymap.push_str("\nconst key_override_t *key_overrides[] = {\n");
+        for i in 0..overrides.len() {
+            keymap.push_str(&format!("    &shift_override_{},\n", i));
+        }
+        keymap.push_str("};\n\n");
+    }
+    keymap.push_str("const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {\n");
+    for (layer, layer_codes) in codes.iter().enumerate() {
+        let rows = rows.iter().map(|row| row.iter().map(|&key| match key {
+            BoardKey::Layout(p)                   => qmk_code(layer_codes[p].0),
+            BoardKey::Other(qmk, _) if layer == 0 => qmk.to_string(),
+            BoardKey::Other(..)                   => "KC_TRNS".to_string(),
+        }).collect::<Vec<String>>().join(", ")).collect::<Vec<String>>();
+        keymap.push_str(&format!("    [{}] = LAYOUT_60_{}(\n        {}\n    ),\n",
+                                 layer, if iso { "iso" } else { "ansi" }, rows.join(",\n        ")));
+    }
+    keymap.push_str("};\n");
+    Ok(keymap)
+}
+
+// Write a layout as a ZMK .keymap.
+pub fn zmk_file(layout: &[u8], geometry: &Geometry, charset: &Charset, name: &str,
+                caps_backspace: bool) -> Result<String, String>
+{
+    let codes = codes(layout, geometry, charset)?;
+    let iso   = geometry.pc_keys()?.contains(&ISO_EXTRA_KEY);
+    let rows  = board_rows(iso, codes.len() > 1, caps_backspace);
+
+    let mut behaviors = String::new();
+    let mut num_overrides = 0;
+    let mut layers = String::new();
+    for (layer, layer_codes) in codes.iter().enumerate() {
+        let mut bindings = Vec::new();
+        for &key in rows.iter().flat_map(|row| row.iter()) {
+            let binding = match key {
+                BoardKey::Other(_, zmk) if layer == 0 => zmk.to_string(),
+                BoardKey::Other(..)                   => "&trans".to_string(),
+                BoardKey::Layout(p) if shifted(layer_codes[p].0) == layer_codes[p].1 => {
+                    zmk_code(layer_codes[p].0)
+                },
+                BoardKey::Layout(p) => {
+                    behaviors.push_str(&mod_morph(num_overrides, layer_codes[p].0, layer_codes[p].1));
+                    num_overrides += 1;
+                    format!("&so{}", num_overrides - 1)
+                },
+            };
+            bindings.push(binding);
+        }
+        let bindings = rows.iter().scan(0, |start, row| {
+            *start += row.len();
+            Some(bindings[*start - row.len()..*start].join(" "))
+        }).collect::<Vec<String>>();
+        layers.push_str(&format!("\n        {}_layer {{\n            bindings = <\n                {}\n\
+                                  \x20           >;\n        }};\n",
+                                 if layer == 0 { "default" } else { "altgr" },
+                                 bindings.join("\n                ")));
+    }
+
+    let mut keymap = format!("// The {} keyboard layout for ZMK, as the keymap of a 60% {} board\n\n\
+                              #include <behaviors.dtsi>\n#include <dt-bindings/zmk/keys.h>\n\n/ {{\n",
+                             name, if iso { "ISO" } else { "ANSI" });
+    if !behaviors.is_empty() {
+        keymap.push_str(&format!("    behaviors {{\n{}    }};\n\n", behaviors));
+    }
+    keymap.push_str(&format!("    keymap {{\n        compatible = \"zmk,keymap\";\n{}    }};\n}};\n",
+                             layers));
+    Ok(keymap)
+}
+
+#[test]
+fn firmware_test()
+{
+    use super::{WHITE_STRING, layout_from_string};
+
+    // Typing each key of the QMK keymap on a US computer, with and without Shift, gives the layout
+    let geometry = Geometry::ansi();
+    let charset = Charset::ascii();
+    let white = layout_from_string(WHITE_STRING, &geometry, &charset);
+    let qmk = qmk_file(&white, &geometry, &charset, "White", true).unwrap();
+    let us_char = |qmk_code: &str| {
+        let key = qmk_code.trim_start_matches("S(").trim_end_matches(')');
+        US_KEYS.iter().find(|k| k.2 == key).map(|k| if qmk_code.starts_with("S(") { k.1 } else { k.0 })
+    };
+    let overrides = qmk.lines().filter(|l| l.contains("ko_make_with_layers")).map(|l| {
+        let args = l[l.find("SHIFT, ").unwrap() + 7..].split(", ").collect::<Vec<&str>>();
+        (args[0], args[1])
+    }).collect::<Vec<(&str, &str)>>();
+    let keys = qmk.lines().skip_while(|l| !l.contains("LAYOUT_60_ansi(")).skip(1).take(5)
+                  .flat_map(|l| l.trim().trim_end_matches(',').split(", ")).collect::<Vec<&str>>();
+    let board = board_rows(false, false, true).concat();
+    assert_eq!((keys.len(), board.len()), (61, 61));
+    for (&key, &board_key) in keys.iter().zip(board.iter()) {
+        if let BoardKey::Layout(k) = board_key {
+            let us_shifted = format!("S({})", key.trim_start_matches("S(").trim_end_matches(')'));
+            let shifted_key = overrides.iter().find(|o| o.0 == key).map(|o| o.1).unwrap_or(&us_shifted);
+            for (layer, key) in [key, shifted_key].iter().enumerate() {
+                let c = charset.char(white[charset.len() + geometry.position(k, layer)]);
+                assert_eq!(us_char(key), Some(c));
+            }
+        }
+    }
+    assert_eq!(keys[28], "KC_BSPC");
+    assert!(qmk.contains("ko_make_with_layers(MOD_MASK_SHIFT, S(KC_3), KC_GRV, 1 << 0)"));
+    assert!(!qmk.contains("ko_make_with_layers(MOD_MASK_SHIFT, KC_1,"));
+
+    // ZMK gets a mod-morph for each override, and AltGr layers go on a layer of their own
+    let zmk = zmk_file(&white, &geometry, &charset, "White", false).unwrap();
+    assert_eq!(zmk.matches("zmk,behavior-mod-morph").count(), overrides.len());
+    assert!(zmk.contains("bindings = <&kp LS(N3)>, <&kp GRAVE>;"));
+    let geometry = Geometry{ layers: 4, ..Geometry::iso() };
+    let charset = Charset::new("é").unwrap();
+    let mut layout = layout_from_string(&Geometry::iso().layout, &geometry, &charset);
+    let position = geometry.position(30, 2);
+    layout[charset.len() + position] = 95;
+    layout[95] = position as u8;
+    let qmk = qmk_file(&layout, &geometry, &charset, "White", false).unwrap();
+    assert!(qmk.contains("LAYOUT_60_iso(") && qmk.contains("MO(1)") && qmk.contains("UC(0x00E9)"));
+    assert!(zmk_file(&layout, &geometry, &charset, "White", false).unwrap().contains("altgr_layer {"));
+}
@@ -0,0 +1,223 @@
+// GENETIC SEARCH
+
+// An alternative to simulated annealing that evolves a population of layouts, selected with
+// "--search genetic". Every generation breeds as many children as there are layouts in the
+// population: two parents are picked by tournament, the child is made by cycle crossover and then
+// mutated by a few random swaps, and the best layouts among the parents and children survive.
+//
+// Cycle crossover works on the positions of the layout. Following the characters around from one
+// parent to the other splits the positions into cycles that hold the same characters in both
+// parents, so taking each cycle whole from one parent or the other always gives a valid layout in
+// which every character is at a position it has in one of the parents. Since the starting
+// population is made from the starting layout by swaps, and the swaps keep every character within
+// its kind of position (letters on letter keys, nothing on frozen positions, and so on), the
+// children keep to those rules too. A cycle takes its parent from the lowest key it touches, so
+// that the shifted half of a letter key follows the unshifted half, and a child that would still
+// split the two cases of a letter or break a constraint is bred again.
+//
+// Each child costs one evaluation of the objective function, the same as one iteration of
+// annealing, so "--iterations" gives both searches the same budget.
+
+use rand::Rng;
+
+use charset::Charset;
+use config::Config;
+use constraints::Constraints;
+use geometry::Geometry;
+use rng::SeededRng;
+use super::{LayoutObjectiveFunction, LayoutScore, LayoutSwapper, NONE};
+
+// The number of times a child is bred again before a copy of its first parent is used instead.
+const MAX_CROSSOVER_ATTEMPTS: usize = 10;
+
+pub struct Population
+{
+    pub layouts: Vec<(Vec<u8>, LayoutScore)>,
+    swapper:     LayoutSwapper,
+    constraints: Constraints,
+    evaluations: u64,
+}
+
+fn random_index(rng: &mut SeededRng, n: usize) -> usize
+{
+    (rng.next_u64() % (n as u64)) as usize
+}
+
+// Whether the two cases of every letter are on the same key.
+fn letters_paired(layout: &[u8], geometry: &Geometry, charset: &Charset) -> bool
+{
+    let num_keys = geometry.num_keys();
+    (0..charset.len() as u8).filter(|&c| charset.is_lower_case_letter(c)).all(|c| {
+        let upper = charset.other_case(c).unwrap();
+        let (p, q) = (layout[c as usize], layout[upper as usize]);
+        p == NONE || q == NONE || (q as usize) == (p as usize) + num_keys
+    })
+}
+
+// Breed a child from two layouts by cycle crossover, taking a cycle from the first parent when the
+// bit of its lowest key is set.
+pub fn cycle_crossover(a: &[u8], b: &[u8], geometry: &Geometry, num_chars: usize, bits: &[bool])
+    -> Vec<u8>
+{
+    let num_positions = a.len() - num_chars;
+    let at_a = |p: usize| a[num_chars + p];
+    let at_b = |p: usize| b[num_chars + p];
+
+    // The position in the first parent of what the second parent has at each position. Empty
+    // positions are all alike, so those that are only empty in one parent are paired up in order.
+    let mut next = vec![0; num_positions];
+    let empty_a = (0..num_positions).filter(|&p| at_a(p) == NONE && at_b(p) != NONE);
+    let empty_b = (0..num_positions).filter(|&p| at_b(p) == NONE && at_a(p) != NONE);
+    for (pa, pb) in empty_a.zip(empty_b) {
+        next[pb] = pa;
+    }
+    for (p, n) in next.iter_mut().enumerate() {
+        match at_b(p) {
+            NONE if at_a(p) == NONE => *n = p,
+            NONE                    => {},
+            c                       => *n = a[c as usize] as usize,
+        }
+    }
+
+    let mut child = vec![NONE; a.len()];
+    let mut visited = vec![false; num_positions];
+    for start in 0..num_positions {
+        if visited[start] {
+            continue;
+        }
+        let mut cycle = Vec::new();
+        let mut p = start;
+        while !visited[p] {
+            visited[p] = true;
+            cycle.push(p);
+            p = next[p];
+        }
+        let lowest_key = cycle.iter().map(|&p| geometry.key(p)).min().unwrap();
+        let parent = if bits[lowest_key] { a } else { b };
+        for &p in cycle.iter() {
+            let c = parent[num_chars + p];
+            child[num_chars + p] = c;
+            if c != NONE {
+                child[c as usize] = p as u8;
+            }
+        }
+    }
+    child
+}
+
+impl Population
+{
+    // A population made from the starting layout by random swaps, along with the layout itself.
+    pub fn new(layout: &[u8], objective: &LayoutObjectiveFunction, config: &Config,
+               constraints: &Constraints, rng: &mut SeededRng) -> Population {
+        let swap_config = Config{ num_tabu_swaps: 0, ..config.clone() };
+        let mut swapper = LayoutSwapper::new(layout, &swap_config, &objective.geometry,
+                                             &objective.charset, constraints);
+        let mut layouts = vec![(layout.to_vec(), objective.score(layout))];
+        for _ in 1..config.population_size {
+            let mut individual = layout.to_vec();
+            for _ in 0..objective.geometry.num_keys() {
+                swapper.swap(&mut individual, rng);
+            }
+            let score = objective.score(&individual);
+            layouts.push((individual, score));
+        }
+        layouts.sort_by(|x, y| x.1.to_f64().partial_cmp(&y.1.to_f64()).unwrap());
+        Population{
+            layouts,
+            swapper,
+            constraints: constraints.clone(),
+            evaluations: config.population_size as u64,
+        }
+    }
+
+    pub fn evaluations(&self) -> u64 {
+        self.evaluations
+    }
+
+    pub fn best(&self) -> &(Vec<u8>, LayoutScore) {
+        &self.layouts[0]
+    }
+
+    // Pick the best of a few random layouts.
+    fn tournament(&self, tournament_size: usize, rng: &mut SeededRng) -> usize {
+        (0..tournament_size).map(|_| random_index(rng, self.layouts.len())).min().unwrap()
+    }
+
+    // Breed a child and score it, relative to its first parent.
+    fn breed(&mut self, objective: &LayoutObjectiveFunction, config: &Config, rng: &mut SeededRng)
+        -> (Vec<u8>, LayoutScore) {
+        let geometry = &objective.geometry;
+        let num_chars = objective.charset.len();
+        let i = self.tournament(config.tournament_size, rng);
+        let j = self.tournament(config.tournament_size, rng);
+        let (a, a_score) = (&self.layouts[i].0, self.layouts[i].1);
+        let b = &self.layouts[j].0;
+        let mut child = a.clone();
+        for _ in 0..MAX_CROSSOVER_ATTEMPTS {
+            let bits = (0..geometry.keys.len()).map(|_| rng.next_u64() & 1 == 1).collect::<Vec<bool>>();
+            let candidate = cycle_crossover(a, b, geometry, num_chars, &bits);
+            if letters_paired(&candidate, geometry, &objective.charset) &&
+               self.constraints.check(&candidate).is_ok() {
+                child = candidate;
+                break;
+            }
+        }
+        for _ in 0..config.mutation_swaps {
+            self.swapper.swap(&mut child, rng);
+        }
+        let score = a_score + objective.score_delta(a, &child);
+        (child, score)
+    }
+
+    // Breed a generation of children and keep the best of the parents and children, leaving out
+    // copies of the same layout.
+    pub fn next_generation(&mut self, objective: &LayoutObjectiveFunction, config: &Config,
+                           rng: &mut SeededRng) {
+        let mut children = Vec::new();
+        for _ in 0..config.population_size {
+            children.push(self.breed(objective, config, rng));
+        }
+        self.evaluations += children.len() as u64;
+        self.layouts.extend(children);
+        self.layouts.sort_by(|x, y| x.1.to_f64().partial_cmp(&y.1.to_f64()).unwrap());
+        self.layouts.dedup_by(|x, y| x.0 == y.0);
+        self.layouts.truncate(config.population_size);
+    }
+}
+
+#[test]
+fn cycle_crossover_test()
+{
+    use super::{WHITE_STRING, layout_from_string, assert_valid_layout};
+
+    let config = Config::default();
+    let geometry = Geometry::ansi();
+    let charset = Charset::ascii();
+    let white = layout_from_string(WHITE_STRING, &geometry, &charset);
+    let constraints = Constraints::new(&config, &geometry, &charset);
+    let mut swapper = LayoutSwapper::new(&white, &config, &geometry, &charset, &constraints);
+    let mut rng = SeededRng::new(11);
+    let mut a = white.clone();
+    let mut b = white.clone();
+    for _ in 0..40 {
+        swapper.swap(&mut a, &mut rng);
+        swapper.swap(&mut b, &mut rng);
+    }
+
+    // Every character of a child comes from one of the parents, and taking every cycle from the
+    // same parent gives that parent back
+    for seed in 0..20 {
+        let mut rng = SeededRng::new(seed);
+        let bits = (0..geometry.keys.len()).map(|_| rng.next_u64() & 1 == 1).collect::<Vec<bool>>();
+        let child = cycle_crossover(&a, &b, &geometry, charset.len(), &bits);
+        assert_valid_layout(&child, &geometry, &charset);
+        assert!(letters_paired(&child, &geometry, &charset));
+        for c in 0..charset.len() {
+            assert!(child[c] == a[c] || child[c] == b[c]);
+        }
+    }
+    let all = vec![true; geometry.keys.len()];
+    assert!(cycle_crossover(&a, &b, &geometry, charset.len(), &all) == a);
+    assert!(cycle_crossover(&a, &b, &geometry, charset.len(), &vec![false; all.len()]) == b);
+}
@@ -0,0 +1,710 @@
+// KEYBOARD GEOMETRY
+
+// A geometry describes the physical keyboard that layouts are made for: its keys and the rows they
+// are arranged in, which finger presses each key, how much each key costs to press, which keys the
+// fingers rest on, and the penalties for pressing certain pairs and triples of keys in a row. Keys
+// are numbered from 1 in reading order, row by row from the top, and number 0 is always the space
+// bar. Every key carries an unshifted and a shifted character, so a geometry with K keys has 2K+1
+// positions in a layout: the space bar, the K unshifted keys, and the K shifted keys.
+
+// The configuration can add a third and a fourth layer, reached by holding AltGr and by holding
+// AltGr and shift, which adds another K positions for each. The positions of layer L (counting the
+// unshifted keys as layer 0) are L*K+1 to L*K+K, so the shifted layers are the odd ones.
+
+// The built-in geometries are "ansi" (the original 47-key board whose metrics are tabulated in
+// main.rs), "iso" (the same board with the extra key left of Z and the key next to Enter), "jis"
+// (the Japanese board, with the yen key, the key next to Enter and the ro key), the ortholinear
+// "3x10" and "3x12" boards, the columnar-staggered "3x10_columnar" and "3x12_columnar" boards, and
+// "split_3x6_3", the 3x12 board split in two halves with three thumb keys under each. Any other
+// name is read as a geometry file, in which each line is one of the following, and "#" starts a
+// comment:
+//
+//   name   = "Split 3x12"
+//   layout = "qwertyuiop..."           the starting layout when there is no layout file
+//   key    = ROW COLUMN FINGER COST    one line for each key, in order, optionally followed by
+//                                      "home" for a key a finger rests on
+//   double = KEY KEY PENALTY           a penalty for typing two keys in a row
+//   triple = KEY KEY KEY PENALTY       a penalty for typing three keys in a row, whose reverse
+//                                      gets the reversed triple penalty on top
+//   forbid = "abc" 1-13 19             keep characters off keys, as in a constraint file (see
+//                                      constraints.rs), for every layout made for the board
+//
+// Fingers are numbered 1 to 8 from the left pinky to the right pinky, and the thumbs are 9 (left)
+// and 10 (right). Columns may be fractional, for staggered rows, and they are used both to draw the
+// layout and to estimate penalties, so a gap between the columns of a row draws a split keyboard. A
+// file without any double or triple lines gets penalties estimated from the positions of its keys.
+
+// Thumb keys are in addition to the space bar, which stays key 0 on every keyboard and has its cost
+// set by the configuration. Like all other keys they carry two characters, and the optimizer moves
+// symbols on and off them like any other key. A thumb key that is meant for Enter, Backspace, a
+// layer or a second shift is left empty in the layout, so that nothing is ever moved onto it.
+
+// Letters are kept off the keys that are awkward to reach on a board by forbid rules, which the
+// board adds to the constraints of every layout. On the ANSI and ISO boards these are the number
+// row, the QWERTY Y, H, ] and \ keys, and the extra key of the ISO board, and on the split board
+// the thumb keys.
+
+use std::fs::File;
+use std::io::Read;
+
+use config;
+use super::{FINGER_ASSIGNMENT, HOME_EIGHT, SINGLE_METRIC, DOUBLE_METRIC, TRIPLE_METRIC};
+use super::{WHITE_STRING, _QWERTY_STRING, EMPTY_POSITION};
+
+// QWERTY as it is on the JIS board, where the ¥ key types a backslash and the ro key an underscore.
+const JIS_QWERTY_STRING: &str = "1234567890-^\\qwertyuiop@[asdfghjkl;:]zxcvbnm,./□\
+                                          !\"#$%&'()□=~|QWERTYUIOP`{ASDFGHJKL+*}ZXCVBNM<>?_";
+
+#[derive(Copy, Clone, Debug)]
+pub struct Key
+{
+    pub row:    u8,
+    pub column: f32,
+    pub finger: u8,
+    pub cost:   f32,
+    pub home:   bool,
+}
+
+// The kind of board a geometry is, which decides where QWERTY is on it and which PC keys its keys
+// are when exporting. Boards of geometry files are always Other, whatever their name.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Board
+{
+    Ansi,
+    Iso,
+    Jis,
+    Other,
+}
+
+#[derive(Clone)]
+pub struct Geometry
+{
+    pub name:          String,
+    pub board:         Board,
+    pub layout:        String,
+    pub keys:          Vec<Key>,
+    pub double_metric: Vec<(u8, u8, f32)>,
+    pub triple_metric: Vec<(u8, u8, u8, f32)>,
+    pub layers:        usize,
+    pub constraints:   String,
+}
+
+const SPACE_BAR: Key = Key{ row: 255, column: 0.0, finger: 0, cost: 0.0, home: false };
+
+pub const LEFT_THUMB:  u8 = 9;
+pub const RIGHT_THUMB: u8 = 10;
+
+// The extra key of the ISO board, left of Z, as numbered by pc_keys.
+pub const ISO_EXTRA_KEY: usize = 48;
+
+impl Key
+{
+    pub fn is_thumb(&self) -> bool {
+        self.finger == LEFT_THUMB || self.finger == RIGHT_THUMB
+    }
+
+    // 1 for the left hand, 2 for the right hand, and 0 for the space bar, which either thumb presses.
+    pub fn hand(&self) -> u8 {
+        match self.finger {
+            1..=4 | LEFT_THUMB  => 1,
+            5..=8 | RIGHT_THUMB => 2,
+            _                   => 0,
+        }
+    }
+}
+
+impl Geometry
+{
+    pub fn load(name: &str) -> Result<Geometry, String> {
+        match name {
+            "ansi"          => Ok(Geometry::ansi()),
+            "iso"           => Ok(Geometry::iso()),
+            "jis"           => Ok(Geometry::jis()),
+            "3x10"          => Ok(Geometry::ortholinear(10)),
+            "3x12"          => Ok(Geometry::ortholinear(12)),
+            "3x10_columnar" => Ok(Geometry::columnar(10)),
+            "3x12_columnar" => Ok(Geometry::columnar(12)),
+            "split_3x6_3"   => Ok(Geometry::split_3x6_3()),
+            _               => {
+                let mut text = String::new();
+                let read_result = File::open(name).and_then(|mut file| file.read_to_string(&mut text));
+                match read_result {
+                    Ok(_)  => Geometry::from_string(&text, name),
+                    Err(e) => Err(format!("{}: {}", name, e))
+                }
+            }
+        }
+    }
+
+    // The geometry that a configuration asks for, with the number of layers it asks for.
+    pub fn from_config(config: &config::Config) -> Result<Geometry, String> {
+        let mut geometry = Geometry::load(&config.geometry)?;
+        geometry.layers = config.layers;
+        if geometry.num_positions() > 255 {
+            return Err(format!("{}: {} keys are too many for {} layers", config.geometry,
+                               geometry.num_keys(), geometry.layers));
+        }
+        Ok(geometry)
+    }
+
+    // The number of keys, not counting the space bar.
+    pub fn num_keys(&self) -> usize {
+        self.keys.len() - 1
+    }
+
+    pub fn num_positions(&self) -> usize {
+        self.layers * self.num_keys() + 1
+    }
+
+    // The key that a position is on.
+    pub fn key(&self, position: usize) -> usize {
+        if position == 0 { 0 } else { (position - 1) % self.num_keys() + 1 }
+    }
+
+    pub fn finger(&self, position: usize) -> u8 {
+        self.keys[self.key(position)].finger
+    }
+
+    pub fn hand(&self, position: usize) -> u8 {
+        self.keys[self.key(position)].hand()
+    }
+
+    pub fn has_thumb_keys(&self) -> bool {
+        self.keys.iter().any(|k| k.is_thumb())
+    }
+
+    // The layer that a position is on, which is 0 for the space bar.
+    pub fn layer(&self, position: usize) -> usize {
+        if position == 0 { 0 } else { (position - 1) / self.num_keys() }
+    }
+
+    // The position of a key on a layer.
+    pub fn position(&self, key: usize, layer: usize) -> usize {
+        layer * self.num_keys() + key
+    }
+
+    pub fn is_shifted(&self, position: usize) -> bool {
+        self.layer(position) % 2 == 1
+    }
+
+    // The keys of each row, from the top.
+    pub fn rows(&self) -> Vec<Vec<usize>> {
+        let mut rows: Vec<Vec<usize>> = Vec::new();
+        for k in 1..self.keys.len() {
+            if k == 1 || self.keys[k].row != self.keys[k-1].row {
+                rows.push(Vec::new());
+            }
+            rows.last_mut().unwrap().push(k);
+        }
+        rows
+    }
+
+    // The original board, with the metrics from main.rs.
+    pub fn ansi() -> Geometry {
+        let row_starts = [(1, 0.0), (14, 1.5), (27, 2.0), (38, 2.5)];
+        let mut keys = vec![SPACE_BAR];
+        for k in 1u8..48 {
+            let row = row_starts.iter().filter(|r| r.0 <= k).count() - 1;
+            let (first, column) = row_starts[row];
+            let home = HOME_EIGHT.contains(&k);
+            keys.push(Key{
+                row:    row as u8,
+                column: column + ((k - first) as f32),
+                finger: FINGER_ASSIGNMENT[k as usize],
+                cost:   SINGLE_METRIC[k as usize],
+                home,
+            });
+        }
+        Geometry{
+            name:          "ansi".to_string(),
+            board:         Board::Ansi,
+            layout:        WHITE_STRING.to_string(),
+            keys,
+            double_metric: DOUBLE_METRIC.to_vec(),
+            triple_metric: TRIPLE_METRIC.to_vec(),
+            layers:        2,
+            constraints:   "forbid = \"abcdefghijklmnopqrstuvwxyz\" 1-13 19 25 26 32\n".to_string(),
+        }
+    }
+
+    // The ISO board has the ANSI backslash key next to Enter at the end of the home row instead of
+    // at the end of the top row, and an extra key between the left shift key and Z. The moved key
+    // keeps the metrics of the backslash key, and the penalties for the extra key are estimated.
+    pub fn iso() -> Geometry {
+        let ansi = Geometry::ansi();
+        let mut keys = vec![SPACE_BAR; 49];
+        for k in 1u8..48 {
+            keys[iso_key(k) as usize] = ansi.keys[k as usize];
+        }
+        keys[37].row    = 2;
+        keys[37].column = 13.0;
+        keys[38] = Key{ row: 3, column: 1.5, finger: 1, cost: 4.0, home: false };
+
+        let mut double_metric = ansi.double_metric.iter().map(|&(k1, k2, p)| {
+            (iso_key(k1), iso_key(k2), p)
+        }).collect::<Vec<(u8, u8, f32)>>();
+        let extra_key_metric = estimate_double_metric(&keys).into_iter()
+                                                            .filter(|d| d.0 == 38 || d.1 == 38);
+        double_metric.extend(extra_key_metric);
+        let triple_metric = ansi.triple_metric.iter().map(|&(k1, k2, k3, p)| {
+            (iso_key(k1), iso_key(k2), iso_key(k3), p)
+        }).collect::<Vec<(u8, u8, u8, f32)>>();
+
+        Geometry{
+            name:          "iso".to_string(),
+            board:         Board::Iso,
+            layout:        iso_layout(WHITE_STRING),
+            keys,
+            double_metric,
+            triple_metric,
+            layers:        2,
+            constraints:   "forbid = \"abcdefghijklmnopqrstuvwxyz\" 1-13 19 25 31 37 38\n".to_string(),
+        }
+    }
+
+    // The JIS board has no key left of 1, and has the yen key at the end of the number row, the key
+    // next to Enter as on the ISO board, and the ro key right of the slash key. Like the ISO board
+    // it keeps the metrics of the keys it shares with ANSI, with the key next to Enter taking those
+    // of the backslash key, and the penalties for the yen and ro keys are estimated. The starting
+    // layout puts the characters of the key left of 1 on the yen key.
+    pub fn jis() -> Geometry {
+        let ansi = Geometry::ansi();
+        let mut keys = vec![SPACE_BAR; 49];
+        for k in 2u8..48 {
+            keys[jis_key(k).unwrap() as usize] = ansi.keys[k as usize];
+        }
+        keys[37].row    = 2;
+        keys[37].column = 13.0;
+        keys[13] = Key{ row: 0, column: 13.0, finger: 8, cost: 9.0, home: false };
+        keys[48] = Key{ row: 3, column: 12.5, finger: 8, cost: 4.0, home: false };
+
+        let mut double_metric = ansi.double_metric.iter().filter_map(|&(k1, k2, p)| {
+            Some((jis_key(k1)?, jis_key(k2)?, p))
+        }).collect::<Vec<(u8, u8, f32)>>();
+        let extra_key_metric = estimate_double_metric(&keys).into_iter().filter(|d| {
+            d.0 == 13 || d.1 == 13 || d.0 == 48 || d.1 == 48
+        });
+        double_metric.extend(extra_key_metric);
+        let triple_metric = ansi.triple_metric.iter().filter_map(|&(k1, k2, k3, p)| {
+            Some((jis_key(k1)?, jis_key(k2)?, jis_key(k3)?, p))
+        }).collect::<Vec<(u8, u8, u8, f32)>>();
+
+        Geometry{
+            name:          "jis".to_string(),
+            board:         Board::Jis,
+            layout:        jis_layout(WHITE_STRING),
+            keys,
+            double_metric,
+            triple_metric,
+            layers:        2,
+            constraints:   "forbid = \"abcdefghijklmnopqrstuvwxyz\" 1-13 19 25 31 37 48\n".to_string(),
+        }
+    }
+
+    // An ortholinear board with three rows of 10 or 12 columns, where the outer columns of the
+    // 12-column board belong to the pinkies. The characters that don't fit are left off the board.
+    pub fn ortholinear(columns: usize) -> Geometry {
+        assert!(columns == 10 || columns == 12);
+        // Costs by row, for the outer pinky column, then the pinky, ring, middle, index and inner
+        // index columns, which are mirrored for the right hand
+        let costs = [[4.0, 2.5,  0.1, -0.2,  1.0, 2.0],
+                     [2.0, -0.5, -0.9, -1.2, -1.0, 1.0],
+                     [4.0, 2.0,  2.0,  0.5,  0.0, 3.0]];
+        let mut keys = vec![SPACE_BAR];
+        for (row, row_costs) in costs.iter().enumerate() {
+            for column in 0..columns {
+                let right  = column >= columns / 2;
+                let kind   = column_kind(column, columns);
+                let finger = (kind.max(1) as u8).min(4);
+                keys.push(Key{
+                    row:    row as u8,
+                    column: column as f32,
+                    finger: if right { 9 - finger } else { finger },
+                    cost:   row_costs[kind],
+                    home:   row == 1 && (1..=4).contains(&kind),
+                });
+            }
+        }
+        let double_metric = estimate_double_metric(&keys);
+        let triple_metric = estimate_triple_metric(&keys);
+
+        let layout = match columns {
+            10 => "qwertyuiopasdfghjkl;zxcvbnm,./QWERTYUIOPASDFGHJKL:ZXCVBNM<>?".to_string(),
+            _  => "-qwertyuiop='asdfghjkl;[`zxcvbnm,./]\
+                   _QWERTYUIOP+\"ASDFGHJKL:{~ZXCVBNM<>?}".to_string(),
+        };
+        Geometry{
+            name:          format!("3x{}", columns),
+            board:         Board::Other,
+            layout,
+            keys,
+            double_metric,
+            triple_metric,
+            layers:        2,
+            constraints:   String::new(),
+        }
+    }
+
+    // An ortholinear board with its columns staggered along with the lengths of the fingers: the
+    // middle and ring finger columns sit higher and the pinky columns lower. This brings the
+    // bottom row closer to the long fingers and the top row closer to the pinkies, and moves the
+    // other rows away from them, which changes the costs of the top and bottom rows. Reaching
+    // between rows with one finger is the same as on the ortholinear board, and the penalties are
+    // estimated from the rows in the same way.
+    pub fn columnar(columns: usize) -> Geometry {
+        // How many rows each column is shifted up, for the outer pinky, pinky, ring, middle, index
+        // and inner index columns, and how much the cost of a key changes for each row of shift
+        let stagger = [-0.25, -0.25, 0.25, 0.5, 0.0, 0.0];
+        let cost_per_row = 2.0;
+        let mut geometry = Geometry::ortholinear(columns);
+        for key in geometry.keys[1..].iter_mut() {
+            let shift = stagger[column_kind(key.column as usize, columns)];
+            match key.row {
+                0 => key.cost += cost_per_row * shift,
+                2 => key.cost -= cost_per_row * shift,
+                _ => {},
+            }
+        }
+        geometry.name = format!("3x{}_columnar", columns);
+        geometry
+    }
+
+    // A split board with the 3x12 keys on two halves and three thumb keys under each half, one of
+    // which is the space bar. The thumb keys start out with the symbols that the 3x12 board leaves
+    // off, apart from the digits.
+    pub fn split_3x6_3() -> Geometry {
+        let mut geometry = Geometry::ortholinear(12);
+        for key in geometry.keys[1..].iter_mut() {
+            if key.column >= 6.0 {
+                key.column += 2.0;
+            }
+        }
+        // The middle thumb key is the resting position, and the space bar is the middle right one
+        let thumbs = [(3.5, LEFT_THUMB, 1.0), (4.5, LEFT_THUMB, -0.5), (5.5, LEFT_THUMB, 0.5),
+                      (8.5, RIGHT_THUMB, 0.5), (10.5, RIGHT_THUMB, 1.0)];
+        for &(column, finger, cost) in thumbs.iter() {
+            geometry.keys.push(Key{ row: 3, column, finger, cost, home: false });
+        }
+        geometry.double_metric = estimate_double_metric(&geometry.keys);
+        geometry.triple_metric = estimate_triple_metric(&geometry.keys);
+        geometry.constraints = "forbid = \"abcdefghijklmnopqrstuvwxyz\" 37-41\n".to_string();
+        geometry.name   = "split_3x6_3".to_string();
+        geometry.layout = "-qwertyuiop='asdfghjkl;[`zxcvbnm,./]()\\!@\
+                           _QWERTYUIOP+\"ASDFGHJKL:{~ZXCVBNM<>?}#$%^&".to_string();
+        geometry
+    }
+
+    // The QWERTY layout on this keyboard, which is where keyboard shortcuts are. The built-in
+    // ortholinear and split boards start out from QWERTY, and geometry files are expected to as well.
+    pub fn qwerty_layout(&self) -> String {
+        match self.board {
+            Board::Ansi  => _QWERTY_STRING.to_string(),
+            Board::Iso   => iso_layout(_QWERTY_STRING),
+            Board::Jis   => JIS_QWERTY_STRING.to_string(),
+            Board::Other => self.layout.clone(),
+        }
+    }
+
+    // The key of a PC keyboard under each key, for exporting layouts to operating systems. PC keys
+    // are numbered like the keys of the ANSI board, with the extra key of the ISO board as
+    // ISO_EXTRA_KEY, and the space bar is 0 on both. The exporters don't know the extra keys of
+    // the JIS board, and other boards have no fixed PC keys.
+    pub fn pc_keys(&self) -> Result<Vec<usize>, String> {
+        match self.board {
+            Board::Ansi => Ok((0..48).collect()),
+            Board::Iso  => Ok((0..49u8).map(|k| {
+                (0..48).find(|&a| iso_key(a) == k).map(|a| a as usize).unwrap_or(ISO_EXTRA_KEY)
+            }).collect()),
+            _           => Err(format!("the {} geometry has no PC keys to export to", self.name)),
+        }
+    }
+
+    // Read a geometry file.
+    pub fn from_string(text: &str, filename: &str) -> Result<Geometry, String> {
+        let mut geometry = Geometry{
+            name:          filename.to_string(),
+            board:         Board::Other,
+            layout:        String::new(),
+            keys:          vec![SPACE_BAR],
+            double_metric: Vec::new(),
+            triple_metric: Vec::new(),
+            layers:        2,
+            constraints:   String::new(),
+        };
+        let mut has_metrics = false;
+        for (line_index, raw_line) in text.lines().enumerate() {
+            // The forbid rules are read along with the constraint file, and stay on their own line
+            // numbers for its error messages
+            let line = config::strip_comment(raw_line).trim();
+            let is_forbid = line.starts_with("forbid") && line[6..].trim_start().starts_with('=');
+            geometry.constraints.push_str(if is_forbid { line } else { "" });
+            geometry.constraints.push('\n');
+            if line.is_empty() || is_forbid {
+                continue;
+            }
+            let error = |e: String| format!("{}, line {}: {}", filename, line_index + 1, e);
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i+1..].trim()),
+                None    => return Err(error(format!("expected \"key = value\", found \"{}\"", line)))
+            };
+            let fields = value.split_whitespace().collect::<Vec<&str>>();
+            let number = |i: usize| -> Result<f32, String> {
+                match fields.get(i).map(|x| x.parse::<f32>()) {
+                    Some(Ok(x)) if x.is_finite() => Ok(x),
+                    _ => Err(format!("key '{}' expects a number in field {}", key, i + 1))
+                }
+            };
+            let key_number = |i: usize, num_keys: usize| -> Result<u8, String> {
+                match fields.get(i).map(|x| x.parse::<usize>()) {
+                    Some(Ok(k)) if k >= 1 && k <= num_keys => Ok(k as u8),
+                    _ => Err(format!("key '{}' expects a key number from 1 to {} in field {}",
+                                     key, num_keys, i + 1))
+                }
+            };
+            let num_keys = geometry.num_keys();
+            match key {
+                "name"   => geometry.name   = config::parse_string(key, value).map_err(&error)?,
+                "layout" => geometry.layout = config::parse_string(key, value).map_err(&error)?,
+                "key"    => {
+                    let finger = number(2).map_err(&error)?;
+                    if !(1.0..=10.0).contains(&finger) || finger.fract() != 0.0 {
+                        let message = "key 'key' expects a finger from 1 to 10 in field 3";
+                        return Err(error(message.to_string()));
+                    }
+                    let flags = &fields[fields.len().min(4)..];
+                    if let Some(flag) = flags.iter().find(|&&f| f != "home") {
+                        return Err(error(format!("unknown key flag '{}'", flag)));
+                    }
+                    if geometry.keys.len() > 127 {
+                        return Err(error("too many keys".to_string()));
+                    }
+                    geometry.keys.push(Key{
+                        row:    number(0).map_err(&error)? as u8,
+                        column: number(1).map_err(&error)?,
+                        finger: finger as u8,
+                        cost:   number(3).map_err(&error)?,
+                        home:   flags.contains(&"home"),
+                    });
+                },
+                "double" => {
+                    has_metrics = true;
+                    geometry.double_metric.push((key_number(0, num_keys).map_err(&error)?,
+                                                 key_number(1, num_keys).map_err(&error)?,
+                                                 number(2).map_err(&error)?));
+                },
+                "triple" => {
+                    has_metrics = true;
+                    geometry.triple_metric.push((key_number(0, num_keys).map_err(&error)?,
+                                                 key_number(1, num_keys).map_err(&error)?,
+                                                 key_number(2, num_keys).map_err(&error)?,
+                                                 number(3).map_err(&error)?));
+                },
+                _ => return Err(error(format!("unknown key '{}'", key)))
+            }
+        }
+        if geometry.num_keys() < 2 {
+            return Err(format!("{}: a geometry needs at least two keys", filename));
+        }
+        if !has_metrics {
+            geometry.double_metric = estimate_double_metric(&geometry.keys);
+            geometry.triple_metric = estimate_triple_metric(&geometry.keys);
+        }
+        Ok(geometry)
+    }
+}
+
+fn same_hand(a: &Key, b: &Key) -> bool
+{
+    a.hand() != 0 && a.hand() == b.hand()
+}
+
+// The ISO number of an ANSI key.
+fn iso_key(k: u8) -> u8
+{
+    match k {
+        0..=25  => k,
+        26      => 37,
+        27..=37 => k - 1,
+        _       => k + 1,
+    }
+}
+
+// The JIS number of an ANSI key, if the JIS board has it.
+fn jis_key(k: u8) -> Option<u8>
+{
+    match k {
+        1       => None,
+        2..=13  => Some(k - 1),
+        26      => Some(37),
+        27..=37 => Some(k - 1),
+        _       => Some(k),
+    }
+}
+
+// An ANSI layout string moved onto the JIS board, with the characters of the key left of 1 on the
+// yen key and the ro key left empty.
+fn jis_layout(ansi_layout: &str) -> String
+{
+    let chars = ansi_layout.chars().filter(|c| !c.is_whitespace()).collect::<Vec<char>>();
+    let mut layout = vec![EMPTY_POSITION; 96];
+    for (i, &c) in chars.iter().enumerate() {
+        let k = jis_key((i % 47 + 1) as u8).unwrap_or(13) as usize;
+        layout[k - 1 + 48 * (i / 47)] = c;
+    }
+    layout.into_iter().collect()
+}
+
+// The kind of column of an ortholinear board with the given number of columns: 0 for the outer
+// pinky columns of a 12-column board, then 1 to 4 for the pinky, ring, middle and index finger
+// columns, and 5 for the inner index finger columns, mirrored for the right hand.
+fn column_kind(column: usize, columns: usize) -> usize
+{
+    let outer = (columns - 10) / 2;
+    let c = if column >= columns / 2 { columns - 1 - column } else { column };
+    if c < outer { 0 } else { (c - outer + 1).min(5) }
+}
+
+// An ANSI layout string moved onto the ISO board, with the extra key left empty.
+fn iso_layout(ansi_layout: &str) -> String
+{
+    let chars = ansi_layout.chars().filter(|c| !c.is_whitespace()).collect::<Vec<char>>();
+    let mut layout = vec![EMPTY_POSITION; 96];
+    for (i, &c) in chars.iter().enumerate() {
+        let k = iso_key((i % 47 + 1) as u8) as usize;
+        layout[k - 1 + 48 * (i / 47)] = c;
+    }
+    layout.into_iter().collect()
+}
+
+// Estimate the penalties for pairs of keys on the same hand from their positions: typing two keys
+// with the same finger is expensive, more so the further apart they are, reaching across two rows
+// with neighbouring fingers is awkward, and rolling along a row with different fingers is easy. A
+// thumb only takes part in the first of these, since it moves independently of the fingers.
+fn estimate_double_metric(keys: &[Key]) -> Vec<(u8, u8, f32)>
+{
+    let mut metric = Vec::new();
+    for i in 1..keys.len() {
+        for j in i+1..keys.len() {
+            let (a, b) = (&keys[i], &keys[j]);
+            if !same_hand(a, b) {
+                continue;
+            }
+            let rows    = (a.row as f32 - b.row as f32).abs();
+            let columns = (a.column - b.column).abs();
+            let fingers = (a.finger as i32 - b.finger as i32).abs();
+            let penalty = if fingers == 0 {
+                1.0 + 1.5 * rows + 0.5 * columns
+            } else if a.is_thumb() || b.is_thumb() {
+                continue;
+            } else if fingers == 1 && rows >= 2.0 {
+                1.0
+            } else if rows == 0.0 {
+                -0.5
+            } else {
+                continue;
+            };
+            metric.push((i as u8, j as u8, penalty));
+        }
+    }
+    metric
+}
+
+// Estimate the bonuses for rolling three keys inward along a row of one hand, from the pinky
+// towards the index finger, with a larger bonus when the fingers are next to each other.
+fn estimate_triple_metric(keys: &[Key]) -> Vec<(u8, u8, u8, f32)>
+{
+    let inward = |a: &Key, b: &Key| -> i32 {
+        let d = b.finger as i32 - a.finger as i32;
+        if a.hand() == 1 { d } else { -d }
+    };
+    let mut metric = Vec::new();
+    for i in 1..keys.len() {
+        for j in 1..keys.len() {
+            for k in 1..keys.len() {
+                let (a, b, c) = (&keys[i], &keys[j], &keys[k]);
+                if !same_hand(a, b) || !same_hand(b, c) || a.row != b.row || b.row != c.row ||
+                   a.is_thumb() || b.is_thumb() || c.is_thumb() {
+                    continue;
+                }
+                let (d1, d2) = (inward(a, b), inward(b, c));
+                if d1 >= 1 && d2 >= 1 {
+                    let bonus = if d1 == 1 && d2 == 1 { -2.0 } else { -0.5 };
+                    metric.push((i as u8, j as u8, k as u8, bonus));
+                }
+            }
+        }
+    }
+    metric
+}
+
+#[test]
+fn geometry_test()
+{
+    let ansi = Geometry::ansi();
+    assert_eq!(ansi.num_keys(), 47);
+    assert_eq!(ansi.rows().iter().map(|r| r.len()).collect::<Vec<usize>>(), vec![13, 13, 11, 10]);
+    assert_eq!(ansi.key(48), 1);
+    assert!(ansi.is_shifted(48) && !ansi.is_shifted(47));
+    let altgr = Geometry{ layers: 4, ..Geometry::ansi() };
+    assert_eq!(altgr.num_positions(), 189);
+    assert_eq!((altgr.layer(95), altgr.key(95), altgr.position(1, 2)), (2, 1, 95));
+    assert!(altgr.is_shifted(188) && !altgr.is_shifted(100));
+
+    // The ISO board keeps the fingers and costs of the keys it shares with ANSI
+    let iso = Geometry::iso();
+    assert_eq!(iso.rows().iter().map(|r| r.len()).collect::<Vec<usize>>(), vec![13, 12, 12, 11]);
+    assert_eq!(iso.keys[26].finger, ansi.keys[27].finger);
+    assert_eq!(iso.keys[48].cost, ansi.keys[47].cost);
+    assert!(iso.double_metric.iter().any(|d| d.0 == 38 || d.1 == 38));
+
+    // So does the JIS board, which puts the characters of the key left of 1 on the yen key
+    let jis = Geometry::jis();
+    assert_eq!(jis.rows().iter().map(|r| r.len()).collect::<Vec<usize>>(), vec![13, 12, 12, 11]);
+    assert_eq!(jis.keys[1].cost, ansi.keys[2].cost);
+    assert_eq!(jis.keys[37].finger, ansi.keys[26].finger);
+    assert!(jis.double_metric.iter().any(|d| d.0 == 48 || d.1 == 48));
+    assert_eq!(jis.layout.chars().nth(12), Some('#'));
+    assert!(jis.qwerty_layout().starts_with("1234567890-^\\qwertyuiop@["));
+    assert!(jis.pc_keys().is_err());
+
+    let ortho = Geometry::ortholinear(12);
+    assert_eq!(ortho.num_keys(), 36);
+    assert_eq!(ortho.keys.iter().filter(|k| k.home).count(), 8);
+    assert_eq!(ortho.keys[13].finger, 1);
+    assert_eq!(ortho.keys[24].finger, 8);
+
+    // Staggering the columns brings the top row closer to the pinkies and the bottom row closer to
+    // the middle finger
+    let columnar = Geometry::columnar(12);
+    assert_eq!(columnar.name, "3x12_columnar");
+    assert!(columnar.keys[2].cost < ortho.keys[2].cost && columnar.keys[26].cost > ortho.keys[26].cost);
+    assert!(columnar.keys[4].cost > ortho.keys[4].cost && columnar.keys[28].cost < ortho.keys[28].cost);
+    assert_eq!(columnar.keys[15].cost, ortho.keys[15].cost);
+
+    // The thumb keys of a split board come after the other keys, and have no rolls
+    let split = Geometry::split_3x6_3();
+    assert_eq!(split.num_keys(), 41);
+    assert_eq!(split.rows().iter().map(|r| r.len()).collect::<Vec<usize>>(), vec![12, 12, 12, 5]);
+    assert_eq!(split.keys[37].hand(), 1);
+    assert_eq!(split.keys[41].hand(), 2);
+    assert!(split.double_metric.iter().any(|d| d.0 == 37 && d.1 == 38));
+    assert!(!split.double_metric.iter().any(|d| d.0 < 37 && d.1 >= 37));
+    assert!(!split.triple_metric.iter().any(|t| t.0 >= 37 || t.1 >= 37 || t.2 >= 37));
+
+    let text = "name = \"tiny\"\nkey = 0 0 1 1.0\nkey = 0 1 2 0.5 home\nforbid = \"e\" 1\n\
+                key = 0 2 3 0.5\n";
+    let tiny = Geometry::from_string(text, "tiny.txt").unwrap();
+    assert_eq!(tiny.name, "tiny");
+    assert_eq!(tiny.num_positions(), 7);
+    assert!(tiny.keys[2].home);
+    assert_eq!(tiny.constraints, "\n\n\nforbid = \"e\" 1\n\n");
+    assert_eq!(tiny.triple_metric, vec![(1, 2, 3, -2.0)]);
+
+    // A geometry file is no built-in board, whatever its name
+    let fake = Geometry::from_string(&text.replace("tiny", "ansi"), "ansi.txt").unwrap();
+    assert_eq!(fake.board, Board::Other);
+    assert_eq!(fake.qwerty_layout(), fake.layout);
+    assert!(fake.pc_keys().is_err());
+    let err = Geometry::from_string("key = 0 0 11 1.0\n", "bad.txt").err().unwrap();
+    assert!(err.contains("line 1") && err.contains("finger"), "{}", err);
+}
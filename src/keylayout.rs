@@ -0,0 +1,271 @@
+// MACOS KEYBOARD LAYOUTS
+
+// macOS reads keyboard layouts from .keylayout files, XML documents that list what every key types
+// in each of a number of key maps, with a modifier map that picks the key map for each combination
+// of modifier keys. "export --format keylayout" writes one for a layout of the ANSI or ISO board,
+// with a key map for each layer of the layout, one for Caps Lock, one for the command key and one
+// for the control key. Caps Lock gives the upper case of the letters and leaves the other keys
+// alone, and the command key map decides where keyboard shortcuts are: with "--qwerty-shortcuts"
+// it has the QWERTY layout, and otherwise the unshifted layer of the layout. When the layout has
+// AltGr layers they go on the option key, with Caps Lock handled as on the unshifted layer.
+//
+// Keys are given by their virtual key codes, which are the same for every Mac keyboard, and the keys
+// that don't type characters of the layout, like Return, the keypad and the function keys, type
+// the same as on the White_layout_mac.keylayout file of this repository. A character that starts a
+// sequence of two keys is a dead key: it switches to a state of its own, in which the characters
+// typed after it produce the characters of their sequences, and any other key ends the state by
+// typing the dead key's character. As in KLC files, longer compose sequences are left out.
+
+use charset::Charset;
+use geometry::{Geometry, ISO_EXTRA_KEY};
+use super::{NONE, layout_from_string};
+
+// The virtual key code of each PC key, as numbered by Geometry::pc_keys.
+const KEY_CODES: [u8; 49] = [
+    49,
+    50, 18, 19, 20, 21, 23, 22, 26, 28, 25, 29, 27, 24,
+    12, 13, 14, 15, 17, 16, 32, 34, 31, 35, 33, 30, 42,
+    0, 1, 2, 3, 5, 4, 38, 40, 37, 41, 39,
+    6, 7, 8, 9, 11, 45, 46, 43, 47, 44,
+    10,
+];
+
+// What the keys outside of the layout type.
+const OTHER_KEYS: [(u8, char); 61] = [
+    (36, '\u{d}'), (48, '\u{9}'), (51, '\u{8}'), (52, '\u{3}'), (53, '\u{1b}'), (64, '\u{10}'),
+    (65, '.'), (66, '\u{1d}'), (67, '*'), (69, '+'), (70, '\u{1c}'), (71, '\u{1b}'),
+    (72, '\u{1f}'), (75, '/'), (76, '\u{3}'), (77, '\u{1e}'), (78, '-'), (79, '\u{10}'),
+    (80, '\u{10}'), (81, '='), (82, '0'), (83, '1'), (84, '2'), (85, '3'), (86, '4'), (87, '5'),
+    (88, '6'), (89, '7'), (91, '8'), (92, '9'), (96, '\u{10}'), (97, '\u{10}'), (98, '\u{10}'),
+    (99, '\u{10}'), (100, '\u{10}'), (101, '\u{10}'), (102, '\u{10}'), (103, '\u{10}'),
+    (104, '\u{10}'), (105, '\u{10}'), (106, '\u{10}'), (107, '\u{10}'), (108, '\u{10}'),
+    (109, '\u{10}'), (110, '\u{10}'), (111, '\u{10}'), (112, '\u{10}'), (113, '\u{10}'),
+    (114, '\u{5}'), (115, '\u{1}'), (116, '\u{b}'), (117, '\u{7f}'), (118, '\u{10}'),
+    (119, '\u{4}'), (120, '\u{10}'), (121, '\u{c}'), (122, '\u{10}'), (123, '\u{1c}'),
+    (124, '\u{1d}'), (125, '\u{1f}'), (126, '\u{1e}'),
+];
+
+// The keypad keys that type something else with shift.
+const SHIFTED_KEYPAD: [(u8, char); 4] = [(66, '*'), (70, '+'), (72, '='), (77, '/')];
+
+// What the extra ISO key types on an ANSI layout, unshifted and shifted, as on the US layout.
+const ISO_SECTION: (char, char) = ('§', '±');
+
+// A character as an XML attribute value. Control characters need XML 1.1.
+fn escape(c: char) -> String
+{
+    match c {
+        '"' | '&' | '\'' | '<' | '>' => format!("&#x{:04X};", c as u32),
+        _ if c.is_control()          => format!("&#x{:04X};", c as u32),
+        _                            => c.to_string(),
+    }
+}
+
+// The control character that the control key types with a character.
+fn control_char(c: char) -> char
+{
+    match c {
+        'a'..='z' | 'A'..='Z' => ((c.to_ascii_uppercase() as u8) - b'@') as char,
+        '['                   => '\u{1b}',
+        '\\'                  => '\u{1c}',
+        ']'                   => '\u{1d}',
+        _                     => c,
+    }
+}
+
+// Write a layout as a .keylayout file. macOS tells layouts apart by their ids, so the id is made
+// from the name, within the range of ids for Unicode layouts.
+pub fn keylayout_file(layout: &[u8], geometry: &Geometry, charset: &Charset, name: &str,
+                      qwerty_shortcuts: bool) -> Result<String, String>
+{
+    let pc_keys   = geometry.pc_keys()?;
+    let num_chars = charset.len();
+    let num_keys  = geometry.num_keys();
+    let qwerty    = layout_from_string(&geometry.qwerty_layout(), geometry, charset);
+    let char_at   = |l: &[u8], key: usize, layer: usize| {
+        if layer >= geometry.layers || l[num_chars + geometry.position(key, layer)] == NONE {
+            None
+        } else {
+            Some(charset.char(l[num_chars + geometry.position(key, layer)]))
+        }
+    };
+
+    // What each key types on a layer of a layout, by key, with the upper case of letters for Caps
+    // Lock. The space bar types a space on every key map.
+    let key_map = |l: &[u8], layer: usize, caps: bool| -> Vec<Option<char>> {
+        (0..num_keys + 1).map(|k| {
+            if k == 0 {
+                return Some(' ');
+            }
+            match (char_at(l, k, layer), char_at(l, k, layer + 1)) {
+                (Some(c), Some(u)) if caps && c.is_lowercase() && c.to_uppercase().eq(Some(u)) => {
+                    Some(u)
+                },
+                (c, _) => c,
+            }
+        }).collect()
+    };
+    let mut maps = vec![("",               key_map(layout, 0, false), false),
+                        ("anyShift caps?", key_map(layout, 1, false), true),
+                        ("caps",           key_map(layout, 0, true),  false)];
+    if geometry.layers > 2 {
+        maps.push(("anyOption",                key_map(layout, 2, false), false));
+        maps.push(("anyShift caps? anyOption", key_map(layout, 3, false), true));
+        maps.push(("caps anyOption",           key_map(layout, 2, true),  false));
+    }
+    let command = key_map(if qwerty_shortcuts { &qwerty } else { layout }, 0, false);
+    let control = command.iter().map(|c| c.map(control_char)).collect();
+    maps.push(("anyShift? caps? anyOption? command", command, false));
+    maps.push(("anyShift? caps? anyOption? anyControl", control, false));
+
+    // The characters that take part in dead key sequences are typed through actions, which are
+    // named after them
+    let dead_keys = charset.dead_keys().into_iter().map(|d| charset.char(d)).collect::<Vec<char>>();
+    let state = |d: char| format!("s{}", dead_keys.iter().position(|&x| x == d).unwrap() + 1);
+    let sequences = charset.sequences().iter().filter(|s| s.1.len() == 2).map(|s| {
+        (charset.char(s.1[0]), charset.char(s.1[1]), s.0)
+    }).collect::<Vec<(char, char, char)>>();
+    let mut action_chars = Vec::new();
+    if !dead_keys.is_empty() {
+        action_chars.push(' ');
+    }
+    for &(d, c, _) in sequences.iter() {
+        for &x in [d, c].iter() {
+            if !action_chars.contains(&x) {
+                action_chars.push(x);
+            }
+        }
+    }
+
+    let hash = name.bytes().fold(0, |h: u32, b| h.wrapping_mul(31).wrapping_add(b as u32));
+    let mut text = format!("<?xml version=\"1.1\" encoding=\"UTF-8\"?>\n<!DOCTYPE keyboard SYSTEM \
+                            \"file://localhost/System/Library/DTDs/KeyboardLayout.dtd\">\n\
+                            <keyboard group=\"126\" id=\"{}\" name=\"{}\" maxout=\"1\">\n",
+                           -2 - (hash % 30000) as i32, name.chars().map(escape).collect::<String>());
+    text.push_str("    <layouts>\n        <layout first=\"0\" last=\"0\" modifiers=\"commonModifiers\" \
+                   mapSet=\"ANSI\"/>\n    </layouts>\n");
+    text.push_str("    <modifierMap id=\"commonModifiers\" defaultIndex=\"0\">\n");
+    for (i, map) in maps.iter().enumerate() {
+        text.push_str(&format!("        <keyMapSelect mapIndex=\"{}\">\n", i));
+        text.push_str(&format!("            <modifier keys=\"{}\"/>\n        </keyMapSelect>\n", map.0));
+    }
+    text.push_str("    </modifierMap>\n    <keyMapSet id=\"ANSI\">\n");
+    for (i, &(_, ref chars, shifted)) in maps.iter().enumerate() {
+        let mut keys = (0..num_keys + 1).filter_map(|k| chars[k].map(|c| (KEY_CODES[pc_keys[k]], c)))
+                                        .collect::<Vec<(u8, char)>>();
+        if !pc_keys.contains(&ISO_EXTRA_KEY) {
+            keys.push((KEY_CODES[ISO_EXTRA_KEY], if shifted { ISO_SECTION.1 } else { ISO_SECTION.0 }));
+        }
+        for &(code, c) in OTHER_KEYS.iter() {
+            let shifted_keypad = SHIFTED_KEYPAD.iter().find(|k| k.0 == code);
+            keys.push((code, if shifted { shifted_keypad.map_or(c, |k| k.1) } else { c }));
+        }
+        keys.sort();
+        text.push_str(&format!("        <keyMap index=\"{}\">\n", i));
+        for &(code, c) in keys.iter() {
+            let kind = if action_chars.contains(&c) { "action" } else { "output" };
+            text.push_str(&format!("            <key code=\"{}\" {}=\"{}\"/>\n", code, kind, escape(c)));
+        }
+        text.push_str("        </keyMap>\n");
+    }
+    text.push_str("    </keyMapSet>\n");
+
+    if !dead_keys.is_empty() {
+        text.push_str("    <actions>\n");
+        for &c in action_chars.iter() {
+            text.push_str(&format!("        <action id=\"{}\">\n", escape(c)));
+            if dead_keys.contains(&c) {
+                text.push_str(&format!("            <when state=\"none\" next=\"{}\"/>\n", state(c)));
+            } else {
+                text.push_str(&format!("            <when state=\"none\" output=\"{}\"/>\n", escape(c)));
+            }
+            for &(d, _, produced) in sequences.iter().filter(|s| s.1 == c) {
+                text.push_str(&format!("            <when state=\"{}\" output=\"{}\"/>\n", state(d),
+                                       escape(produced)));
+            }
+            // A space after a dead key types the dead key's character, unless it has a sequence
+            if c == ' ' {
+                let with_space = |d: char| sequences.iter().any(|s| s.0 == d && s.1 == ' ');
+                for &d in dead_keys.iter().filter(|&&d| !with_space(d)) {
+                    text.push_str(&format!("            <when state=\"{}\" output=\"{}\"/>\n", state(d),
+                                           escape(d)));
+                }
+            }
+            text.push_str("        </action>\n");
+        }
+        text.push_str("    </actions>\n    <terminators>\n");
+        for &d in dead_keys.iter() {
+            text.push_str(&format!("        <when state=\"{}\" output=\"{}\"/>\n", state(d), escape(d)));
+        }
+        text.push_str("    </terminators>\n");
+    }
+    text.push_str("</keyboard>\n");
+    Ok(text)
+}
+
+// The output of each key of a key map, by key code, with actions replaced by what they type
+// without a dead key.
+#[cfg(test)]
+fn key_map_outputs(xml: &str, index: usize) -> Vec<(String, String)>
+{
+    let attribute = |line: &str, name: &str| -> Option<String> {
+        let start = line.find(&format!(" {}=\"", name))? + name.len() + 3;
+        let value = &line[start..start + line[start..].find('"').unwrap()];
+        Some(value.to_string())
+    };
+    let start = format!("<keyMap index=\"{}\"", index);
+    let mut lines = xml.lines().skip_while(|l| !l.contains(&start)).skip(1);
+    let mut outputs = Vec::new();
+    while let Some(line) = lines.next().filter(|l| !l.contains("</keyMap>")) {
+        let code = match attribute(line, "code") {
+            Some(code) => code,
+            None       => continue,
+        };
+        let output = attribute(line, "output").unwrap_or_else(|| {
+            let action = format!("<action id=\"{}\">", attribute(line, "action").unwrap());
+            let when = xml.lines().skip_while(|l| !l.contains(&action)).nth(1).unwrap();
+            attribute(when, "output").or_else(|| attribute(when, "next")).unwrap()
+        });
+        outputs.push((code, output));
+    }
+    outputs.sort();
+    outputs
+}
+
+#[test]
+fn keylayout_white_test()
+{
+    use super::WHITE_STRING;
+
+    let geometry = Geometry::ansi();
+    let charset = Charset::ascii();
+    let white = layout_from_string(WHITE_STRING, &geometry, &charset);
+    let keylayout = keylayout_file(&white, &geometry, &charset, "White", true).unwrap();
+
+    // The shipped file was edited from Dvorak, and only its unshifted and shifted key maps are the
+    // same as the layout's
+    let shipped = include_str!("../White_layout_mac.keylayout");
+    for index in 0..2 {
+        assert_eq!(key_map_outputs(&keylayout, index), key_map_outputs(shipped, index));
+    }
+    let caps = key_map_outputs(&keylayout, 2);
+    let command = key_map_outputs(&keylayout, 3);
+    let control = key_map_outputs(&keylayout, 4);
+    let output = |map: &[(String, String)], code: &str| {
+        map.iter().find(|k| k.0 == code).unwrap().1.clone()
+    };
+    assert_eq!((output(&caps, "12"), output(&caps, "15")), ("V".to_string(), ",".to_string()));
+    assert_eq!((output(&command, "8"), output(&command, "12")), ("c".to_string(), "q".to_string()));
+    assert_eq!(output(&control, "8"), "&#x0003;");
+
+    // Dead keys switch to a state in which the keys of their sequences type something else
+    let charset = Charset::new("´").unwrap().with_sequences("´eé").unwrap();
+    let layout = layout_from_string(&WHITE_STRING.replace('#', "´"), &geometry, &charset);
+    let keylayout = keylayout_file(&layout, &geometry, &charset, "White", false).unwrap();
+    assert!(keylayout.contains("<key code=\"50\" action=\"´\"/>"));
+    assert!(keylayout.contains("<action id=\"´\">\n            <when state=\"none\" next=\"s1\"/>"));
+    assert!(keylayout.contains("<action id=\"e\">\n            <when state=\"none\" output=\"e\"/>\n\
+                                \x20           <when state=\"s1\" output=\"é\"/>"));
+    assert!(keylayout.contains("<terminators>\n        <when state=\"s1\" output=\"´\"/>"));
+    assert_eq!(output(&key_map_outputs(&keylayout, 3), "8"), "g");
+}
@@ -0,0 +1,425 @@
+// WINDOWS KEYBOARD LAYOUTS
+
+// The Microsoft Keyboard Layout Creator compiles a layout from a KLC file, a tab-separated text
+// file in UTF-16 that lists every key by its scan code and virtual key, followed by the character
+// it types in each shift state. "export --format klc" writes one for a layout of the ANSI or ISO
+// board, in the same form as the White_layout_win.klc file of this repository.
+//
+// Windows programs look up keyboard shortcuts by the virtual key of a key rather than by the
+// character it types. Normally each key gets the virtual key of the character it types, wherever
+// that character is on QWERTY, so that Ctrl+C is wherever C is. With "--qwerty-shortcuts" every key
+// keeps its QWERTY virtual key instead, and the Ctrl and Ctrl+Alt shift states type the QWERTY
+// characters, so that shortcuts stay where they are on QWERTY, as they do for the White layout.
+// Like in the White layout file, Ctrl+] then types the group separator before the bracket, which is
+// written as a ligature. When the layout has AltGr layers, those take the Ctrl+Alt shift states,
+// which is how Windows handles AltGr. Caps Lock works on the keys with the virtual keys of letters.
+//
+// A character that starts a sequence of two keys becomes a dead key, and the characters typed after
+// it are listed in its DEADKEY section, along with the space for the dead key's own character.
+// Longer compose sequences can't be described in a KLC file, so they are left out.
+
+use charset::Charset;
+use geometry::{Geometry, ISO_EXTRA_KEY};
+use super::{NONE, layout_from_string};
+
+// The scan code and virtual key of each PC key, as numbered by Geometry::pc_keys.
+const PC_KEYS: [(&str, &str); 49] = [
+    ("39", "SPACE"),
+    ("29", "OEM_3"), ("02", "1"), ("03", "2"), ("04", "3"), ("05", "4"), ("06", "5"), ("07", "6"),
+    ("08", "7"), ("09", "8"), ("0a", "9"), ("0b", "0"), ("0c", "OEM_MINUS"), ("0d", "OEM_PLUS"),
+    ("10", "Q"), ("11", "W"), ("12", "E"), ("13", "R"), ("14", "T"), ("15", "Y"), ("16", "U"),
+    ("17", "I"), ("18", "O"), ("19", "P"), ("1a", "OEM_4"), ("1b", "OEM_6"), ("2b", "OEM_5"),
+    ("1e", "A"), ("1f", "S"), ("20", "D"), ("21", "F"), ("22", "G"), ("23", "H"), ("24", "J"),
+    ("25", "K"), ("26", "L"), ("27", "OEM_1"), ("28", "OEM_7"),
+    ("2c", "Z"), ("2d", "X"), ("2e", "C"), ("2f", "V"), ("30", "B"), ("31", "N"), ("32", "M"),
+    ("33", "OEM_COMMA"), ("34", "OEM_PERIOD"), ("35", "OEM_2"),
+    ("56", "OEM_102"),
+];
+
+// A locale by name, with its Windows identifier, language name and ISO 639-2 language code.
+pub type Locale = (&'static str, &'static str, &'static str, &'static str);
+
+// The locales a layout can be made for.
+const LOCALES: [Locale; 12] = [
+    ("en-US", "0409", "English (United States)",  "eng"),
+    ("en-GB", "0809", "English (United Kingdom)", "eng"),
+    ("en-CA", "1009", "English (Canada)",         "eng"),
+    ("en-AU", "0c09", "English (Australia)",      "eng"),
+    ("de-DE", "0407", "German (Germany)",         "ger"),
+    ("fr-FR", "040c", "French (France)",          "fre"),
+    ("es-ES", "0c0a", "Spanish (Spain)",          "spa"),
+    ("it-IT", "0410", "Italian (Italy)",          "ita"),
+    ("nl-NL", "0413", "Dutch (Netherlands)",      "dut"),
+    ("pt-BR", "0416", "Portuguese (Brazil)",      "por"),
+    ("sv-SE", "041d", "Swedish (Sweden)",         "swe"),
+    ("pl-PL", "0415", "Polish (Poland)",          "pol"),
+];
+
+// The Unicode names of the printable ASCII characters other than letters and digits.
+const SYMBOL_NAMES: [(char, &str); 33] = [
+    (' ', "SPACE"), ('!', "EXCLAMATION MARK"), ('"', "QUOTATION MARK"), ('#', "NUMBER SIGN"),
+    ('$', "DOLLAR SIGN"), ('%', "PERCENT SIGN"), ('&', "AMPERSAND"), ('\'', "APOSTROPHE"),
+    ('(', "LEFT PARENTHESIS"), (')', "RIGHT PARENTHESIS"), ('*', "ASTERISK"), ('+', "PLUS SIGN"),
+    (',', "COMMA"), ('-', "HYPHEN-MINUS"), ('.', "FULL STOP"), ('/', "SOLIDUS"), (':', "COLON"),
+    (';', "SEMICOLON"), ('<', "LESS-THAN SIGN"), ('=', "EQUALS SIGN"), ('>', "GREATER-THAN SIGN"),
+    ('?', "QUESTION MARK"), ('@', "COMMERCIAL AT"), ('[', "LEFT SQUARE BRACKET"),
+    ('\\', "REVERSE SOLIDUS"), (']', "RIGHT SQUARE BRACKET"), ('^', "CIRCUMFLEX ACCENT"),
+    ('_', "LOW LINE"), ('`', "GRAVE ACCENT"), ('{', "LEFT CURLY BRACKET"), ('|', "VERTICAL LINE"),
+    ('}', "RIGHT CURLY BRACKET"), ('~', "TILDE"),
+];
+
+// The control character that Ctrl types on the extra ISO key of the US layout.
+const FILE_SEPARATOR: char = '\u{1c}';
+
+// The characters that Ctrl+] types with QWERTY shortcuts, the group separator and the bracket.
+const GROUP_SEPARATOR: char = '\u{1d}';
+const CTRL_BRACKET: [char; 2] = [GROUP_SEPARATOR, ']'];
+
+// What a key types in one shift state: a character, the characters of its ligature, or nothing,
+// which the comment of the line names as such, or leaves unnamed for the keypad's period.
+#[derive(Copy, Clone, PartialEq)]
+enum Cell
+{
+    Char(char),
+    Ligature,
+    Nothing,
+    Unnamed,
+}
+
+// The names of the keys that don't type characters, which are the same for every layout.
+const KEY_NAMES: &str = "\
+KEYNAME\n\
+\n\
+01\tEsc\n\
+0e\tBackspace\n\
+0f\tTab\n\
+1c\tEnter\n\
+1d\tCtrl\n\
+2a\tShift\n\
+36\t\"Right Shift\"\n\
+37\t\"Num *\"\n\
+38\tAlt\n\
+39\tSpace\n\
+3a\t\"Caps Lock\"\n\
+3b\tF1\n\
+3c\tF2\n\
+3d\tF3\n\
+3e\tF4\n\
+3f\tF5\n\
+40\tF6\n\
+41\tF7\n\
+42\tF8\n\
+43\tF9\n\
+44\tF10\n\
+45\tPause\n\
+46\t\"Scroll Lock\"\n\
+47\t\"Num 7\"\n\
+48\t\"Num 8\"\n\
+49\t\"Num 9\"\n\
+4a\t\"Num -\"\n\
+4b\t\"Num 4\"\n\
+4c\t\"Num 5\"\n\
+4d\t\"Num 6\"\n\
+4e\t\"Num +\"\n\
+4f\t\"Num 1\"\n\
+50\t\"Num 2\"\n\
+51\t\"Num 3\"\n\
+52\t\"Num 0\"\n\
+53\t\"Num Del\"\n\
+54\t\"Sys Req\"\n\
+57\tF11\n\
+58\tF12\n\
+7c\tF13\n\
+7d\tF14\n\
+7e\tF15\n\
+7f\tF16\n\
+80\tF17\n\
+81\tF18\n\
+82\tF19\n\
+83\tF20\n\
+84\tF21\n\
+85\tF22\n\
+86\tF23\n\
+87\tF24\n\
+\n\
+KEYNAME_EXT\n\
+\n\
+1c\t\"Num Enter\"\n\
+1d\t\"Right Ctrl\"\n\
+35\t\"Num /\"\n\
+37\t\"Prnt Scrn\"\n\
+38\t\"Right Alt\"\n\
+45\t\"Num Lock\"\n\
+46\tBreak\n\
+47\tHome\n\
+48\tUp\n\
+49\t\"Page Up\"\n\
+4b\tLeft\n\
+4d\tRight\n\
+4f\tEnd\n\
+50\tDown\n\
+51\t\"Page Down\"\n\
+52\tInsert\n\
+53\tDelete\n\
+54\t<00>\n\
+56\tHelp\n\
+5b\t\"Left Windows\"\n\
+5c\t\"Right Windows\"\n\
+5d\tApplication\n\
+\n\
+";
+
+// A locale by name, which other exporters take from here too.
+pub fn find_locale(locale: &str) -> Result<&'static Locale, String>
+{
+    LOCALES.iter().find(|l| l.0 == locale).ok_or_else(|| {
+        format!("unknown locale '{}', expected one of {}", locale,
+                LOCALES.iter().map(|l| l.0).collect::<Vec<&str>>().join(", "))
+    })
+}
+
+// The name of a character in the comments of the file.
+fn char_name(c: char) -> String
+{
+    match c {
+        'a'..='z'       => format!("LATIN SMALL LETTER {}", c.to_ascii_uppercase()),
+        'A'..='Z'       => format!("LATIN CAPITAL LETTER {}", c),
+        '0'..='9'       => format!("DIGIT {}", ["ZERO", "ONE", "TWO", "THREE", "FOUR", "FIVE", "SIX",
+                                                "SEVEN", "EIGHT", "NINE"][c as usize - '0' as usize]),
+        FILE_SEPARATOR  => "INFORMATION SEPARATOR FOUR".to_string(),
+        GROUP_SEPARATOR => "INFORMATION SEPARATOR THREE".to_string(),
+        _ => match SYMBOL_NAMES.iter().find(|s| s.0 == c) {
+            Some(s) => s.1.to_string(),
+            None    => c.to_string(),
+        }
+    }
+}
+
+// Letters and digits are written as themselves, and all other characters by their code.
+fn char_code(c: char) -> String
+{
+    if c.is_ascii_alphanumeric() { c.to_string() } else { format!("{:04x}", c as u32) }
+}
+
+// The description of a shift state in the SHIFTSTATE section.
+fn shift_state_name(state: usize) -> String
+{
+    if state == 0 {
+        return String::new();
+    }
+    let name = format!("{}  {} {}", if state & 1 != 0 { "Shft" } else { "    " },
+                       if state & 2 != 0 { "Ctrl" } else { "    " },
+                       if state & 4 != 0 { "Alt" } else { "" });
+    format!(" : {}", name.trim_end())
+}
+
+// The tabs that line up what follows a virtual key.
+fn vk_tabs(vk: &str) -> &'static str
+{
+    if vk.len() >= 7 { "\t" } else { "\t\t" }
+}
+
+// One line of the LAYOUT section, with what each shift state types.
+fn layout_line(scan_code: &str, vk: &str, cells: &[Cell], dead_keys: &[char]) -> String
+{
+    let cap = vk.len() == 1 && vk.chars().all(|c| c.is_ascii_alphabetic());
+    let mut line = format!("{}\t{}{}{}", scan_code, vk, vk_tabs(vk), cap as u8);
+    let mut names = Vec::new();
+    for &cell in cells.iter() {
+        let (code, name) = match cell {
+            Cell::Char(c)  => {
+                let dead = if dead_keys.contains(&c) { "@" } else { "" };
+                (format!("{}{}", char_code(c), dead), char_name(c))
+            },
+            Cell::Ligature => ("%%".to_string(), "<null>".to_string()),
+            Cell::Nothing  => ("-1".to_string(), "<none>".to_string()),
+            Cell::Unnamed  => ("-1".to_string(), String::new()),
+        };
+        line.push_str(&format!("\t{}", code));
+        names.push(name);
+    }
+    format!("{}\t\t// {}\n", line, names.join(", "))
+}
+
+// Write a layout as a KLC file. The name of the layout, which Windows also uses as the name of its
+// DLL, can have at most eight letters and digits.
+pub fn klc_file(layout: &[u8], geometry: &Geometry, charset: &Charset, name: &str, locale: &str,
+                qwerty_shortcuts: bool) -> Result<String, String>
+{
+    if name.is_empty() || name.len() > 8 || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("the layout name '{}' must be one to eight letters and digits", name));
+    }
+    let &(_, locale_id, language, _) = find_locale(locale)?;
+    let pc_keys   = geometry.pc_keys()?;
+    let num_chars = charset.len();
+    let num_keys  = geometry.num_keys();
+    let qwerty    = layout_from_string(&geometry.qwerty_layout(), geometry, charset);
+    let char_at   = |l: &[u8], p: usize| {
+        if l[num_chars + p] == NONE { None } else { Some(charset.char(l[num_chars + p])) }
+    };
+
+    let dead_keys = charset.dead_keys().into_iter().map(|d| charset.char(d)).collect::<Vec<char>>();
+
+    // Ctrl and Ctrl+Alt are only described when something is on them
+    let mut states = vec![0, 1];
+    if qwerty_shortcuts {
+        states.extend_from_slice(&[2, 3]);
+    }
+    if qwerty_shortcuts || geometry.layers > 2 {
+        states.extend_from_slice(&[6, 7]);
+    }
+    let cell = |key: usize, state: usize| -> Cell {
+        let (source, layer) = match state {
+            0 | 1                    => (layout, state),
+            2 | 3                    => (&qwerty[..], state - 2),
+            _ if geometry.layers > 2 => (layout, state - 4),
+            _                        => (&qwerty[..], state - 6),
+        };
+        match char_at(source, geometry.position(key, layer)).filter(|_| layer < geometry.layers) {
+            Some(c) if state == 2 && c == CTRL_BRACKET[1] => Cell::Ligature,
+            Some(c)                                       => Cell::Char(c),
+            None                                          => Cell::Nothing,
+        }
+    };
+
+    // The virtual key of each key, as an index into PC_KEYS. Without QWERTY shortcuts a key takes
+    // the virtual key of the QWERTY key with the same unshifted character, and the keys whose
+    // character isn't unshifted on QWERTY share out the virtual keys that are left.
+    let mut vks = pc_keys.clone();
+    if !qwerty_shortcuts {
+        let mut taken = vec![false; PC_KEYS.len()];
+        let mut found = vec![false; pc_keys.len()];
+        for k in 1..num_keys + 1 {
+            let c = layout[num_chars + k];
+            if c != NONE && qwerty[c as usize] != NONE && (qwerty[c as usize] as usize) <= num_keys {
+                vks[k]   = pc_keys[qwerty[c as usize] as usize];
+                found[k] = true;
+                taken[vks[k]] = true;
+            }
+        }
+        for k in 1..num_keys + 1 {
+            if !found[k] {
+                vks[k] = *pc_keys[1..].iter().find(|&&pc| !taken[pc]).unwrap();
+                taken[vks[k]] = true;
+            }
+        }
+    }
+
+    // The ligatures are listed by the virtual key and the column of their shift state
+    let mut lines = (1..num_keys + 1).map(|k| {
+        let cells = states.iter().map(|&s| cell(k, s)).collect::<Vec<Cell>>();
+        let scan_code = PC_KEYS[pc_keys[k]].0;
+        let vk = PC_KEYS[vks[k]].1;
+        let ligature = cells.iter().position(|&c| c == Cell::Ligature).map(|column| (vk, column));
+        (scan_code, layout_line(scan_code, vk, &cells, &dead_keys), ligature)
+    }).collect::<Vec<(&str, String, Option<(&str, usize)>)>>();
+    lines.sort();
+    let ligatures = lines.iter().filter_map(|l| l.2).collect::<Vec<(&str, usize)>>();
+    let mut rows = lines.into_iter().map(|l| l.1).collect::<String>();
+    let space = states.iter().map(|&s| if s <= 2 { Cell::Char(' ') } else { Cell::Nothing });
+    rows.push_str(&layout_line("39", "SPACE", &space.collect::<Vec<_>>(), &dead_keys));
+    // An ANSI layout keeps the US characters on the extra ISO key, as Windows expects of a US
+    // layout, and the period of the numeric keypad is the same for all
+    if !pc_keys.contains(&ISO_EXTRA_KEY) {
+        let extra = states.iter().map(|&s| {
+            ['\\', '|', FILE_SEPARATOR].get(s).map_or(Cell::Nothing, |&c| Cell::Char(c))
+        });
+        rows.push_str(&layout_line("56", "OEM_102", &extra.collect::<Vec<_>>(), &[]));
+    }
+    let decimal = states.iter().map(|&s| if s <= 1 { Cell::Char('.') } else { Cell::Unnamed });
+    rows.push_str(&layout_line("53", "DECIMAL", &decimal.collect::<Vec<_>>(), &[]));
+
+    let mut text = format!("KBD\t{}\t\"{} keyboard layout\"\n\nCOPYRIGHT\t\"none\"\n\n\
+                            COMPANY\t\"none\"\n\nLOCALENAME\t\"{}\"\n\nLOCALEID\t\"0000{}\"\n\n\
+                            VERSION\t1.0\n\nSHIFTSTATE\n\n", name, name, locale, locale_id);
+    for (i, &s) in states.iter().enumerate() {
+        text.push_str(&format!("{}\t//Column {}{}\n", s, i + 4, shift_state_name(s)));
+    }
+    text.push_str("\nLAYOUT\t\t;an extra '@' at the end is a dead key\n\n");
+    let columns = states.iter().map(|s| format!("\t{}", s)).collect::<String>();
+    text.push_str(&format!("//SC\tVK_\t\tCap{}\n", columns));
+    text.push_str(&format!("//--\t----\t\t----{}\n\n", "\t----".repeat(states.len())));
+    text.push_str(&rows);
+    text.push('\n');
+
+    if !ligatures.is_empty() {
+        text.push_str("LIGATURE\n\n//VK_\tMod#\tChar0\tChar1\tChar2\tChar3\n\
+                       //----\t\t----\t----\t----\t----\t----\n\n");
+        for &(vk, column) in ligatures.iter() {
+            let codes = CTRL_BRACKET.iter().map(|&c| format!("\t{:04x}", c as u32)).collect::<String>();
+            let names = CTRL_BRACKET.iter().map(|&c| char_name(c)).collect::<Vec<String>>();
+            text.push_str(&format!("{}{}{}{}\t\t// {}\n", vk, vk_tabs(vk), column, codes,
+                                   names.join(" + ")));
+        }
+        text.push('\n');
+    }
+
+    for &d in dead_keys.iter() {
+        text.push_str(&format!("DEADKEY\t{}\n\n", char_code(d)));
+        let mut follows_space = false;
+        for &(produced, ref keys) in charset.sequences().iter() {
+            if keys.len() == 2 && charset.char(keys[0]) == d {
+                let c = charset.char(keys[1]);
+                text.push_str(&format!("{:04x}\t{:04x}\t// {} -> {}\n", c as u32, produced as u32, c,
+                                       produced));
+                follows_space |= c == ' ';
+            }
+        }
+        if !follows_space {
+            text.push_str(&format!("0020\t{:04x}\t//   -> {}\n", d as u32, d));
+        }
+        text.push('\n');
+    }
+
+    text.push_str(KEY_NAMES);
+    if !dead_keys.is_empty() {
+        text.push_str("KEYNAME_DEAD\n\n");
+        for &d in dead_keys.iter() {
+            text.push_str(&format!("{:04x}\t\"{}\"\n", d as u32, char_name(d)));
+        }
+        text.push('\n');
+    }
+    text.push_str(&format!("DESCRIPTIONS\n\n{}\t{} keyboard layout\n\n", locale_id, name));
+    text.push_str(&format!("LANGUAGENAMES\n\n{}\t{}\n\nENDKBD\n", locale_id, language));
+    Ok(text)
+}
+
+// A text as KLC files are stored: UTF-16 with a byte order mark, with Windows line endings.
+pub fn utf16_bytes(text: &str) -> Vec<u8>
+{
+    let mut bytes = vec![0xff, 0xfe];
+    for unit in text.replace('\n', "\r\n").encode_utf16() {
+        bytes.push((unit & 0xff) as u8);
+        bytes.push((unit >> 8) as u8);
+    }
+    bytes
+}
+
+#[test]
+fn klc_round_trip_test()
+{
+    use super::WHITE_STRING;
+
+    let geometry = Geometry::ansi();
+    let charset = Charset::ascii();
+    let white = layout_from_string(WHITE_STRING, &geometry, &charset);
+    let klc = klc_file(&white, &geometry, &charset, "White", "en-US", true).unwrap();
+
+    // The shipped file is the same apart from its copyright
+    let bytes = include_bytes!("../White_layout_win.klc");
+    let units = bytes[2..].chunks(2).map(|b| b[0] as u16 | (b[1] as u16) << 8).collect::<Vec<u16>>();
+    let shipped = String::from_utf16(&units).unwrap().replace("\r\n", "\n");
+    assert!(utf16_bytes(&shipped)[..] == bytes[..]);
+    let differences = klc.lines().zip(shipped.lines()).filter(|l| l.0 != l.1).collect::<Vec<_>>();
+    assert_eq!(differences, vec![("COPYRIGHT\t\"none\"", "COPYRIGHT\t\"(c) 2015 Michael White\"")]);
+    assert_eq!(klc.lines().count(), shipped.lines().count());
+
+    // Without QWERTY shortcuts the virtual keys follow the characters, and dead keys are marked
+    let charset = Charset::new("´").unwrap().with_sequences("´eé").unwrap();
+    let layout = layout_from_string(&WHITE_STRING.replace('#', "´"), &geometry, &charset);
+    let klc = klc_file(&layout, &geometry, &charset, "White", "en-GB", false).unwrap();
+    assert!(klc.contains("\n10\tV\t\t1\tv\tV\t\t// LATIN SMALL LETTER V, LATIN CAPITAL LETTER V\n"));
+    assert!(klc.contains("\n29\tOEM_3\t\t0\t00b4@\t0060\t\t// ´, GRAVE ACCENT\n"));
+    assert!(klc.contains("DEADKEY\t00b4\n\n0065\t00e9\t// e -> é\n0020\t00b4\t//   -> ´\n\n"));
+    assert!(klc_file(&layout, &geometry, &charset, "White layout", "en-US", false).is_err());
+}
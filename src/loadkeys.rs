@@ -0,0 +1,189 @@
+// LINUX CONSOLE KEYMAPS
+
+// The Linux console doesn't use XKB but its own keymaps, which "loadkeys FILE" loads as root.
+// "export --format loadkeys" writes one for a layout of the ANSI or ISO board, giving each key
+// its keysyms on the plain and shift keymaps, on the AltGr keymaps when the layout has AltGr layers,
+// and on the control keymap. Only the keys of the layout are listed, and the modifiers, Escape,
+// Return and the rest come from the standard include file. Keyboard shortcuts are control
+// characters on the console, so the control keymap follows "--qwerty-shortcuts" like the other
+// exporters, and with "--caps-backspace" the Caps Lock key becomes another backspace, as on the
+// White layout.
+//
+// Keys are given by their Linux keycodes, the same ones X gets from evdev minus 8. Letters are
+// marked with a '+' so that Caps Lock gives their upper case. The console only knows the dead keys
+// of the kernel's accent table, so a character that starts a sequence of two keys is a dead key if
+// it is one of those accents, with its sequences in the accent table as compose lines; other
+// sequences can't be typed on the console and are left out.
+
+use charset::Charset;
+use geometry::Geometry;
+use super::{NONE, layout_from_string};
+
+// The Linux keycode of each PC key, as numbered by Geometry::pc_keys.
+const KEY_CODES: [u8; 49] = [
+    57,
+    41, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13,
+    16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 43,
+    30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40,
+    44, 45, 46, 47, 48, 49, 50, 51, 52, 53,
+    86,
+];
+
+// The keycode of Caps Lock.
+const CAPS_LOCK: u8 = 58;
+
+// The keysyms of the printable ASCII characters other than letters.
+const SYMBOL_KEYSYMS: [(char, &str); 43] = [
+    (' ', "space"), ('!', "exclam"), ('"', "quotedbl"), ('#', "numbersign"), ('$', "dollar"),
+    ('%', "percent"), ('&', "ampersand"), ('\'', "apostrophe"), ('(', "parenleft"),
+    (')', "parenright"), ('*', "asterisk"), ('+', "plus"), (',', "comma"), ('-', "minus"),
+    ('.', "period"), ('/', "slash"), ('0', "zero"), ('1', "one"), ('2', "two"), ('3', "three"),
+    ('4', "four"), ('5', "five"), ('6', "six"), ('7', "seven"), ('8', "eight"), ('9', "nine"),
+    (':', "colon"), (';', "semicolon"), ('<', "less"), ('=', "equal"), ('>', "greater"),
+    ('?', "question"), ('@', "at"), ('[', "bracketleft"), ('\\', "backslash"),
+    (']', "bracketright"), ('^', "asciicircum"), ('_', "underscore"), ('`', "grave"),
+    ('{', "braceleft"), ('|', "bar"), ('}', "braceright"), ('~', "asciitilde"),
+];
+
+// The dead keysyms of the kernel, with the accent each one stands for in the accent table and the
+// characters that become it.
+const DEAD_KEYSYMS: [(&str, char, &str); 6] = [
+    ("dead_grave",      '`',  "`"),
+    ("dead_acute",      '\'', "´'"),
+    ("dead_circumflex", '^',  "^ˆ"),
+    ("dead_tilde",      '~',  "~˜"),
+    ("dead_diaeresis",  '"',  "¨\""),
+    ("dead_cedilla",    ',',  "¸"),
+];
+
+// The keysyms of the control characters that have a key of their own.
+const CONTROL_KEYSYMS: [(char, &str); 5] = [
+    ('[', "Escape"), ('\\', "Control_backslash"), (']', "Control_bracketright"),
+    ('^', "Control_asciicircum"), ('_', "Control_underscore"),
+];
+
+// The keysym of a character, which is its Unicode keysym outside of ASCII.
+fn keysym(c: char) -> String
+{
+    match SYMBOL_KEYSYMS.iter().find(|s| s.0 == c) {
+        Some(s)                         => s.1.to_string(),
+        None if c.is_ascii_alphabetic() => c.to_string(),
+        None                            => format!("U+{:04x}", c as u32),
+    }
+}
+
+// The keysym that types the control character of a character, if it has one.
+fn control_keysym(c: char) -> Option<String>
+{
+    match CONTROL_KEYSYMS.iter().find(|s| s.0 == c) {
+        Some(s)                         => Some(s.1.to_string()),
+        None if c.is_ascii_alphabetic() => Some(format!("Control_{}", c.to_ascii_lowercase())),
+        None                            => None,
+    }
+}
+
+// A character for the accent table, quoted and escaped as loadkeys expects.
+fn quoted(c: char) -> String
+{
+    match c {
+        '\'' | '\\' => format!("'\\{}'", c),
+        _           => format!("'{}'", c),
+    }
+}
+
+// Write a layout as a console keymap.
+pub fn loadkeys_file(layout: &[u8], geometry: &Geometry, charset: &Charset, name: &str,
+                     qwerty_shortcuts: bool, caps_backspace: bool) -> Result<String, String>
+{
+    let pc_keys   = geometry.pc_keys()?;
+    let num_chars = charset.len();
+    let qwerty    = layout_from_string(&geometry.qwerty_layout(), geometry, charset);
+    let char_at   = |l: &[u8], key: usize, layer: usize| {
+        match l[num_chars + geometry.position(key, layer)] {
+            NONE => None,
+            c    => Some(c),
+        }
+    };
+    let dead_key = |c: u8| {
+        let is_dead_key = charset.dead_keys().contains(&c);
+        DEAD_KEYSYMS.iter().find(|d| is_dead_key && d.2.contains(charset.char(c)))
+    };
+
+    let mut keymap = format!("# The {} keyboard layout for the Linux console, for loadkeys\n\n\
+                              keymaps {}\ninclude \"linux-with-alt-and-altgr\"\nstrings as usual\n\n",
+                             name, if geometry.layers > 2 { "0-6,8-9,12" } else { "0-2,4-6,8-9,12" });
+    for k in 1..geometry.num_keys() + 1 {
+        // Letters are marked on both cases, when the shifted key has the upper case of the unshifted
+        let is_letter = match (char_at(layout, k, 0), char_at(layout, k, 1)) {
+            (Some(c), Some(u)) => charset.is_lower_case_letter(c) && charset.other_case(c) == Some(u),
+            _                  => false,
+        };
+        let keysyms = (0..geometry.layers).map(|layer| match char_at(layout, k, layer) {
+            Some(c) if dead_key(c).is_some()  => dead_key(c).unwrap().0.to_string(),
+            Some(c) if is_letter && layer < 2 => format!("+{}", keysym(charset.char(c))),
+            Some(c)                           => keysym(charset.char(c)),
+            None                              => "VoidSymbol".to_string(),
+        }).collect::<Vec<String>>();
+        let key_code = KEY_CODES[pc_keys[k]];
+        keymap.push_str(&format!("keycode {} = {} {}\n", key_code, keysyms[0], keysyms[1]));
+        for (modifiers, keysym) in ["altgr", "shift altgr"].iter().zip(keysyms.iter().skip(2)) {
+            keymap.push_str(&format!("\t{} keycode {} = {}\n", modifiers, key_code, keysym));
+        }
+        let shortcuts = if qwerty_shortcuts { &qwerty[..] } else { layout };
+        if let Some(control) = char_at(shortcuts, k, 0).and_then(|c| control_keysym(charset.char(c))) {
+            keymap.push_str(&format!("\tcontrol keycode {} = {}\n", key_code, control));
+        }
+    }
+    if caps_backspace {
+        keymap.push_str(&format!("keycode {} = BackSpace\n", CAPS_LOCK));
+    }
+
+    // The accent table lists the sequences by the accents of their dead keys
+    let mut compose = String::new();
+    for &(produced, ref keys) in charset.sequences().iter().filter(|s| s.1.len() == 2) {
+        if let Some(&(_, accent, _)) = dead_key(keys[0]) {
+            compose.push_str(&format!("compose {} {} to {}\n",
+                                      quoted(accent), quoted(charset.char(keys[1])), quoted(produced)));
+        }
+    }
+    if !compose.is_empty() {
+        keymap.push('\n');
+        keymap.push_str(&compose);
+    }
+    Ok(keymap)
+}
+
+#[test]
+fn loadkeys_test()
+{
+    use super::WHITE_STRING;
+
+    // Every key has the keysyms of its characters, with the QWERTY control characters on request
+    let geometry = Geometry::ansi();
+    let charset = Charset::ascii();
+    let white = layout_from_string(WHITE_STRING, &geometry, &charset);
+    let keymap = loadkeys_file(&white, &geometry, &charset, "White", true, true).unwrap();
+    let lines = keymap.lines().filter(|l| l.starts_with("keycode")).collect::<Vec<&str>>();
+    assert_eq!(lines.len(), geometry.num_keys() + 1);
+    assert!(keymap.contains("keycode 41 = numbersign grave\nkeycode 2 = one exclam\n"));
+    assert!(keymap.contains("\tcontrol keycode 46 = Control_c\n"));
+    assert_eq!(lines.last(), Some(&"keycode 58 = BackSpace"));
+    for line in lines.iter().take(geometry.num_keys()) {
+        let keysyms = line.split(" = ").nth(1).unwrap().split(' ').collect::<Vec<&str>>();
+        let code = line[8..line.find(" =").unwrap()].parse::<u8>().unwrap();
+        let k = geometry.pc_keys().unwrap().iter().position(|&p| KEY_CODES[p] == code).unwrap();
+        for layer in 0..2 {
+            let c = charset.char(white[charset.len() + geometry.position(k, layer)]);
+            assert_eq!(keysyms[layer].trim_start_matches('+'), keysym(c));
+        }
+    }
+
+    // Accents that start sequences are dead keys, and the control keymap follows the layout
+    let charset = Charset::new("´").unwrap().with_sequences("´eé ´aá").unwrap();
+    let layout = layout_from_string(&WHITE_STRING.replace('#', "´"), &geometry, &charset);
+    let keymap = loadkeys_file(&layout, &geometry, &charset, "White", false, false).unwrap();
+    assert!(keymap.contains("keycode 41 = dead_acute grave\n"));
+    assert!(keymap.contains("\tcontrol keycode 46 = Control_g\n"));
+    assert!(keymap.ends_with("compose '\\'' 'e' to 'é'\ncompose '\\'' 'a' to 'á'\n"));
+    assert!(!keymap.contains("BackSpace"));
+}
@@ -21,6 +21,14 @@
 // corresponds to pressing key number 27 in QWERTY, then l[95+27] will give us back the number 65,
 // which when added to 32 gives us the ASCII code (97) for the character 'a'.
 
+// All of the above describes the standard ANSI keyboard. Layouts for other keyboards (see
+// geometry.rs) are numbered the same way, but a keyboard with K keys has 2K+1 positions, so its
+// layout array is 95+2K+1 bytes long and the shifted keys are numbered from K+1. A keyboard can
+// have more positions than there are characters, in which case some positions are left empty, or
+// fewer, in which case some characters are left off the layout. Both are marked by the byte NONE, and an
+// empty position is written as EMPTY_POSITION in layout strings. Empty positions and characters
+// that are not on the layout always stay where they are during optimization.
+
 
 // TABLE OF THE 95 ASCII CHARACTER CODES
 
@@ -42,40 +50,51 @@
 // 47 /      63 ?      79 O      95 _     111 o
 
 extern crate rand;
+
+mod checkpoint;
+mod charset;
+mod cli;
+mod config;
+mod firmware;
+mod constraints;
+mod genetic;
+mod keylayout;
+mod klc;
+mod loadkeys;
+mod xkb;
+mod pareto;
+mod geometry;
+mod ngrams;
+mod rng;
+
+use charset::Charset;
+use cli::{Command, Options, Search};
+use config::Config;
+use constraints::Constraints;
+use genetic::Population;
+use pareto::{ParetoArchive, NUM_OBJECTIVES, OBJECTIVE_NAMES};
+use geometry::Geometry;
+use ngrams::{NgramCounts, NgramTables, FREQUENCY_UNIT};
+#[cfg(test)]
+use ngrams::grid_frequency;
+use rand::Rng;
+use rng::SeededRng;
 use std::ops::Add;
 use std::fs::File;
 use std::path::Path;
 use std::io::{Read, Write};
 use std::collections::HashMap;
+use std::sync::Mutex;
 use std::collections::hash_map::Entry::{Vacant, Occupied};
 
 
-// CUSTOMIZABLE OPTIMIZATION PARAMETERS
-const PRINT_OBJECTIVE_FUNCTION:  bool  =  false;
-
-const FROZEN_SYMBOLS:   &'static str   = "0123456789";
-
-const SINGLE_METRIC_COEFFICIENT: f32   =  1.00;
-const DOUBLE_METRIC_COEFFICIENT: f32   =  1.00;
-const TRIPLE_METRIC_COEFFICIENT: f32   =  1.00;
-const SHIFT_HOLDING_PENALTY:     f32   =  1.50;
-const REVERSED_TRIPLE_PENALTY:   f32   =  0.25;
-const HAND_ALTERNATION_PENALTY:  f32   =  0.20;
-
-const CORPUS_MIN_WORD_FREQUENCY: f32   =  20.0;
-const CORPUS_1_COEFFICIENT:      f64   =  1e-3;
-const CORPUS_2_COEFFICIENT:      f64   =  0.50;
-
-const CYCLE_TEMPERATURE_START:   f64   =  1e+5;
-const CYCLE_TEMPERATURE_FINAL:   f64   =  5e+3;
-const CYCLE_TEMPERATURE_FACTOR:  f64   =  0.50;
-const TEMPERATURE_FINAL:         f64   =  1.00;
-const TEMPERATURE_FACTOR:        f64   =  0.99999;
-const NUM_TABU_SWAPS:            usize =  10;
+// Marks an empty position and a character that is not on the layout
+const NONE: u8 = 255;
+const EMPTY_POSITION: char = '□';
 
 
 // LAYOUT STRINGS
-const _QWERTY_STRING: &'static str = "
+const _QWERTY_STRING: &str = "
  `1234567890-=
   qwertyuiop[]\\
    asdfghjkl;'
@@ -85,7 +104,7 @@ const _QWERTY_STRING: &'static str = "
    ASDFGHJKL:\"
     ZXCVBNM<>?";
 
-const _DVORAK_STRING: &'static str = "
+const _DVORAK_STRING: &str = "
 `1234567890[]
   ',.pyfgcrl/=\\
    aoeuidhtns-
@@ -95,7 +114,7 @@ const _DVORAK_STRING: &'static str = "
    AOEUIDHTNS_
     :QJKXBMWVZ";
 
-const _COLEMAK_STRING: &'static str = "
+const _COLEMAK_STRING: &str = "
 `1234567890-=
   qwfpgjluy;[]\\
    arstdhneio'
@@ -105,7 +124,7 @@ const _COLEMAK_STRING: &'static str = "
    ARSTDHNEIO\"
     ZXCVBKM<>?";
 
-const _WORKMAN_STRING: &'static str = "
+const _WORKMAN_STRING: &str = "
 `1234567890-=
   qdrwbjfup;[]\\
    ashtgyneoi'
@@ -115,7 +134,7 @@ const _WORKMAN_STRING: &'static str = "
    ASHTGYNEOI\"
     ZXMCVKL<>?";
 
-const WHITE_STRING: &'static str = "
+const WHITE_STRING: &str = "
 #12345@$67890
   vyd,'_jmlu()=
    atheb-csnoi
@@ -578,138 +597,166 @@ const TRIPLE_METRIC: [(u8, u8, u8, f32); 52] = [
 // LAYOUT FUNCTIONS
 
 // Check all the assumptions that make a byte array into a layout array
-fn assert_valid_layout(l: &[u8; 190])
-{
-    assert!(l[0] == 0 && l[95] == 0, "Layout must assign 0 to the space key.");
-    let mut occurrences = [0u8; 95];
-    for k in 0..95 {
-        let li = l[k+95] as usize;
-        let c = (li as u8 + 32) as char;
-        assert!(li < 95, "Layout contains the invalid character '{}'.", c);
-        occurrences[li] += 1;
-        if li == 0 && occurrences[li] != 1 {
-            break;
-        } else {
-            assert!(occurrences[li] == 1,
-                "Layout assigns multiple keys to character '{}'", c);
+fn assert_valid_layout(l: &[u8], geometry: &Geometry, charset: &Charset)
+{
+    let num_chars     = charset.len();
+    let num_positions = geometry.num_positions();
+    assert!(l.len() == num_chars + num_positions,
+        "Layout has {} positions, but the {} keyboard has {}.",
+        l.len() as isize - num_chars as isize, geometry.name, num_positions);
+    assert!(num_positions <= NONE as usize, "Layout has more than {} positions.", NONE);
+    assert!(l[0] == 0 && l[num_chars] == 0, "Layout must assign 0 to the space key.");
+    let mut occurrences = vec![0u8; num_chars];
+    for k in 0..num_positions {
+        let li = l[k+num_chars] as usize;
+        if li == NONE as usize {
+            continue;
         }
+        assert!(li < num_chars, "Layout contains the invalid character {}.", li);
+        let c = charset.char(li as u8);
+        occurrences[li] += 1;
+        assert!(occurrences[li] == 1, "Layout assigns multiple keys to character '{}'", c);
+        assert!(l[li] as usize == k, "Second half of layout is not the inverse of the first half.");
     }
-    for i in 0..95 {
-        assert!(occurrences[i as usize] >= 1,
-            "Layout doesn't assign any keys to character '{}'.", (i as u8 + 32) as char);
-    }
-    for i in 0..95 {
-        assert!(l[(l[i]+95) as usize] == i as u8,
+    for i in 0..num_chars {
+        assert!(occurrences[i] == 1 || l[i] == NONE,
             "Second half of layout is not the inverse of the first half.");
     }
-    for i in 65u8..91 {
-        assert!(l[i as usize] <= 47,
-            "Lower-case letter {} must correspond to a lower-case key.", (i+32) as char);
-    }
-    for i in 33u8..59 {
-        assert!(l[i as usize] > 47,
-            "Upper-case letter {} must correspond to an upper-case key.", (i+32) as char);
-    }
-}
-
-// Check whether a given string has only the allowed subset of ASCII characters.
-fn check_valid_ascii_subset(s: &str) -> Result<(), (char, usize)>
-{
-    let mut line_num = 1usize;
-    for line in s.lines() {
-        for c in line.chars() {
-            if !((' ' <= c && c <= '~') || c == '\n' || c == '\r' || c == '\t') {
-                return Err((c, line_num));
-            }
+    for i in 0..num_chars as u8 {
+        if charset.is_lower_case_letter(i) {
+            assert!(l[i as usize] == NONE || !geometry.is_shifted(l[i as usize] as usize),
+                "Lower-case letter {} must correspond to a lower-case key.", charset.char(i));
+        }
+        if charset.is_upper_case_letter(i) {
+            assert!(l[i as usize] == NONE || geometry.is_shifted(l[i as usize] as usize),
+                "Upper-case letter {} must correspond to an upper-case key.", charset.char(i));
         }
-        line_num += 1;
     }
-    Ok(())
 }
 
-// Create a layout array from a string in the correct format.
-fn layout_from_string(s_with_whitespace: &str) -> [u8; 190]
+// Create a layout array from a string in the correct format. A string with only the unshifted and
+// shifted layers leaves the AltGr layers empty.
+fn layout_from_string(s_with_whitespace: &str, geometry: &Geometry, charset: &Charset) -> Vec<u8>
 {
-    let s_string = s_with_whitespace.chars().filter(|x| !x.is_whitespace()).collect::<String>();
-    assert!(s_string.len() == 94, "Layout string is {} characters, not 94.", s_string.len());
-    let s = &s_string; // s without whitespace
-    if let Err((c,_)) = check_valid_ascii_subset(s) {
-        panic!("Invalid character in layout: {} -> {}", c as u32, c);
-    }
-    let mut layout = [0u8; 190];
-    let mut ki = 1u8;
-    for c in s.chars() {
-        let ci = (c as u8) - 32;
-        assert!(ci > 0u8 && ci < 95u8, "Layout string has invalid character: {}", c);
+    let s = s_with_whitespace.chars().filter(|x| !x.is_whitespace()).collect::<Vec<char>>();
+    let num_chars     = charset.len();
+    let num_positions = geometry.num_positions();
+    let num_keys      = geometry.num_keys();
+    assert!(s.len() == num_positions - 1 || s.len() == 2 * num_keys,
+            "Layout string is {} characters, not {}.", s.len(), num_positions - 1);
+    let mut layout = vec![NONE; num_chars + num_positions];
+    layout[0]         = 0;
+    layout[num_chars] = 0;
+    for (ki, &c) in (1u8..).zip(s.iter()) {
+        if c == EMPTY_POSITION {
+            continue;
+        }
+        let ci = match charset.index(c) {
+            Some(ci) => ci,
+            None     => panic!("Invalid character in layout: {} -> {}", c as u32, c)
+        };
         layout[ci as usize] = ki;
-        layout[(ki + 95) as usize] = ci;
-        ki += 1;
+        layout[(ki as usize) + num_chars] = ci;
     }
-    assert_valid_layout(&layout);
+    assert_valid_layout(&layout, geometry, charset);
     layout
 }
 
 #[test]
 fn qwerty_valid()
 {
-    let l = layout_from_string(_QWERTY_STRING);
-    assert_valid_layout(&l);
+    let geometry = Geometry::ansi();
+    let l = layout_from_string(_QWERTY_STRING, &geometry, &Charset::ascii());
+    assert_valid_layout(&l, &geometry, &Charset::ascii());
 }
 
 #[test]
 fn dvorak_valid()
 {
-    let l = layout_from_string(_DVORAK_STRING);
-    assert_valid_layout(&l);
+    let geometry = Geometry::ansi();
+    let l = layout_from_string(_DVORAK_STRING, &geometry, &Charset::ascii());
+    assert_valid_layout(&l, &geometry, &Charset::ascii());
 }
 
 #[test]
 fn colemak_valid()
 {
-    let l = layout_from_string(_COLEMAK_STRING);
-    assert_valid_layout(&l);
+    let geometry = Geometry::ansi();
+    let l = layout_from_string(_COLEMAK_STRING, &geometry, &Charset::ascii());
+    assert_valid_layout(&l, &geometry, &Charset::ascii());
 }
 
 #[test]
 fn workman_valid()
 {
-    let l = layout_from_string(_WORKMAN_STRING);
-    assert_valid_layout(&l);
+    let geometry = Geometry::ansi();
+    let l = layout_from_string(_WORKMAN_STRING, &geometry, &Charset::ascii());
+    assert_valid_layout(&l, &geometry, &Charset::ascii());
 }
 
 #[test]
 fn initial_layout_valid()
 {
-    let l = layout_from_string(INITIAL_LAYOUT_STRING);
-    assert_valid_layout(&l);
+    let charset = Charset::ascii();
+    let names = ["ansi", "iso", "jis", "3x10", "3x12", "3x10_columnar", "3x12_columnar", "split_3x6_3"];
+    for name in names.iter() {
+        let geometry = Geometry::load(name).unwrap();
+        let l = layout_from_string(&geometry.layout, &geometry, &charset);
+        assert_valid_layout(&l, &geometry, &charset);
+        let expected = geometry.layout.split_whitespace().collect::<String>();
+        assert_eq!(layout_to_string(&l, &charset), expected);
+    }
+
+    // Characters outside of ASCII are placed like any other
+    let white = layout_from_string(WHITE_STRING, &Geometry::ansi(), &Charset::ascii());
+    let charset = Charset::new("äÄ–").unwrap();
+    let l = layout_from_string(&WHITE_STRING.replace('q', "ä").replace('Q', "Ä").replace('#', "–"),
+                               &Geometry::ansi(), &charset);
+    assert_eq!((l[95], l[96], l[97]), (white[81], white[49], white[3]));
+    assert_eq!(l[81], NONE);
+    assert_eq!(charset.char(l[98 + 1]), '–');
+}
+
+// Convert a layout to the string format used in layout files.
+fn layout_to_string(layout: &[u8], charset: &Charset) -> String
+{
+    layout[charset.len()+1..].iter().map(|&c| {
+        if c == NONE { EMPTY_POSITION } else { charset.char(c) }
+    }).collect::<String>()
 }
 
 // Convert a layout to a string and write it to a text file.
-fn write_layout_file(layout: &[u8], filename: &str)
+fn write_layout_file(layout: &[u8], filename: &str, charset: &Charset)
 {
-    let mut output = [0u8; 94];
-    for i in 1u8..95 {
-        output[(layout[i as usize] - 1) as usize] = i + 32;
-    }
     let path = Path::new(filename);
-    let mut file = std::fs::File::create(&path).unwrap();
-    let io_result = file.write_all(&output);
+    let mut file = std::fs::File::create(path).unwrap();
+    let io_result = file.write_all(layout_to_string(layout, charset).as_bytes());
     assert!(io_result.is_ok());
 }
 
-// Read a string from a file and convert it to a layout. If the file doesn't exist, use default.
-fn read_layout_file(filename: &str) -> [u8; 190]
+// Read a string from a file and convert it to a layout. If the file doesn't exist, use the default
+// layout of the keyboard.
+fn read_layout_file(filename: &str, geometry: &Geometry, charset: &Charset) -> Vec<u8>
 {
     let path = Path::new(filename);
     if std::fs::metadata(path).is_ok() {
-        let mut file = File::open(&path).unwrap();
+        let mut file = File::open(path).unwrap();
         let mut text = String::new();
         file.read_to_string(&mut text).unwrap();
-        let layout = layout_from_string(&text[..]);
-        layout
+        layout_from_string(&text[..], geometry, charset)
     } else {
-        let layout = layout_from_string(WHITE_STRING);
-        layout
+        layout_from_string(&geometry.layout, geometry, charset)
+    }
+}
+
+// Read the layout that the similarity term compares with, which is QWERTY unless the configuration
+// names a layout file.
+fn read_reference_layout(config: &Config, geometry: &Geometry, charset: &Charset) -> Vec<u8>
+{
+    if config.reference_layout.is_empty() {
+        layout_from_string(&geometry.qwerty_layout(), geometry, charset)
+    } else {
+        read_required_layout_file(&config.reference_layout, geometry, charset)
     }
 }
 
@@ -717,10 +764,9 @@ fn read_layout_file(filename: &str) -> [u8; 190]
 // TERMINAL OUTPUT FUNCTIONS
 
 // Print out a key score in the terminal in colors to distinguish finger assignments.
-fn print_key_score(k: usize, k0: u8, score: f32)
+fn print_key_score(k: usize, k0: u8, score: f32, geometry: &Geometry)
 {
-    let i = if k < 48 { k } else { k - 47 };
-    let finger_color_string = match FINGER_ASSIGNMENT[i] {
+    let finger_color_string = match geometry.finger(k) {
         1 => "\x1B[41m", // red
         2 => "\x1B[42m", // green
         3 => "\x1B[45m", // magenta
@@ -729,6 +775,7 @@ fn print_key_score(k: usize, k0: u8, score: f32)
         6 => "\x1B[45m", // magenta
         7 => "\x1B[42m", // green
         8 => "\x1B[41m", // red
+        9 | 10 => "\x1B[43m", // yellow
         _ => ""
     };
     print!("{}", finger_color_string);
@@ -742,13 +789,33 @@ fn print_key_score(k: usize, k0: u8, score: f32)
     print!("\x1B[0m");
 }
 
+// Print a score for every key in the shape of the keyboard, with the rows indented by how far they
+// are staggered, and gaps where a row is split.
+fn print_key_diagram(key: u8, scores: &[f32], geometry: &Geometry)
+{
+    for (r, row) in geometry.rows().iter().enumerate() {
+        let first_column = geometry.keys[row[0]].column;
+        let indent = (5.0 * first_column).round() as usize;
+        if r > 0 {
+            print!("\n{:1$}", "", indent);
+        }
+        let mut x = indent;
+        for &k in row.iter() {
+            let key_x = indent + (6.0 * (geometry.keys[k].column - first_column)).round() as usize;
+            print!("{:1$}", "", key_x.saturating_sub(x));
+            print_key_score(k, key, scores[k], geometry);
+            x = key_x.max(x) + 6;
+        }
+    }
+    print!("\n\n");
+}
+
 // Print a double key metric score diagram which shows the penalty from moving from that key (in
 // parentheses) to other keys [in brackets]. Blank space means the penalty for that key is 0.
-fn print_double_metric(key: u8)
+fn print_double_metric(key: u8, geometry: &Geometry)
 {
-    let mut score = [0f32; 48];
-    for i in 0..314 {
-        let (k1, k2, s) = DOUBLE_METRIC[i];
+    let mut score = vec![0f32; geometry.keys.len()];
+    for &(k1, k2, s) in geometry.double_metric.iter() {
         if k1 == key {
             score[k2 as usize] += s;
         }
@@ -756,84 +823,54 @@ fn print_double_metric(key: u8)
             score[k1 as usize] += s;
         }
     }
-    for i in 1..14 {
-        print_key_score(i, key, score[i]);
-    }
-    print!("\n        ");
-    for i in 14..27 {
-        print_key_score(i, key, score[i]);
-    }
-    print!("\n          ");
-    for i in 27..38 {
-        print_key_score(i, key, score[i]);
-    }
-    print!("\n             ");
-    for i in 38..48 {
-        print_key_score(i, key, score[i]);
-    }
-    print!("\n\n");
+    print_key_diagram(key, &score, geometry);
 }
 
-// Print out a diagram for the single key scores static array.
-fn print_single_metric()
+// Print out a diagram for the single key scores of the keyboard.
+fn print_single_metric(geometry: &Geometry)
 {
-    for i in 1..14 {
-        print_key_score(i, 0, SINGLE_METRIC[i]);
-    }
-    print!("\n        ");
-    for i in 14..27 {
-        print_key_score(i, 0, SINGLE_METRIC[i]);
-    }
-    print!("\n          ");
-    for i in 27..38 {
-        print_key_score(i, 0, SINGLE_METRIC[i]);
-    }
-    print!("\n             ");
-    for i in 38..48 {
-        print_key_score(i, 0, SINGLE_METRIC[i]);
-    }
-    print!("\n\n");
+    let score = geometry.keys.iter().map(|k| k.cost).collect::<Vec<f32>>();
+    print_key_diagram(0, &score, geometry);
 }
 
-// Print a layout array in a visually useful way.
-fn print_layout(l: &[u8; 190])
+// Print a layout array in a visually useful way, with the shifted characters to the right and the
+// AltGr layers, if any, below.
+fn print_layout(l: &[u8], geometry: &Geometry, charset: &Charset)
 {
-    assert_valid_layout(l);
-    let lower_case = &l[95..190];
-    let upper_case = &l[95+47..190];
+    assert_valid_layout(l, geometry, charset);
+    let rows = geometry.rows();
+    let symbol = |p: usize| -> char {
+        let c = l[charset.len()+p];
+        if c == NONE { EMPTY_POSITION } else { charset.char(c) }
+    };
 
-    for i in lower_case[1..14].iter() {
-        print!("{} ", (i + 32) as char);
-    }
-    print!("     ");
-    for i in upper_case[1..14].iter() {
-        print!("{} ", (i + 32) as char);
-    }
-    print!("\n   ");
-    for i in lower_case[14..27].iter() {
-        print!("{} ", (i + 32) as char);
-    }
-    print!("     ");
-    for i in upper_case[14..27].iter() {
-        print!("{} ", (i + 32) as char);
-    }
-    print!("\n    ");
-    for i in lower_case[27..38].iter() {
-        print!("{} ", (i + 32) as char);
-    }
-    print!("         ");
-    for i in upper_case[27..38].iter() {
-        print!("{} ", (i + 32) as char);
-    }
-    print!("\n     ");
-    for i in lower_case[38..48].iter() {
-        print!("{} ", (i + 32) as char);
-    }
-    print!("           ");
-    for i in upper_case[38..48].iter() {
-        print!("{} ", (i + 32) as char);
+    // Each key is drawn two characters per column from the left edge of its row, and the shifted
+    // half starts five characters after the widest row
+    let key_x = |k: usize, row: &[usize]| -> usize {
+        let first_column = geometry.keys[row[0]].column;
+        ((2.0 * first_column).round() + (2.0 * (geometry.keys[k].column - first_column)).round()) as usize
+    };
+    let widest = rows.iter().map(|row| {
+        key_x(row[row.len() - 1], row) + 2 - key_x(row[0], row)
+    }).max().unwrap();
+    for first_layer in (0..geometry.layers).step_by(2) {
+        if first_layer > 0 {
+            println!();
+        }
+        for row in rows.iter() {
+            let mut x = 0;
+            for layer in first_layer..geometry.layers.min(first_layer + 2) {
+                let offset = if layer % 2 == 1 { widest + 5 } else { 0 };
+                for &k in row.iter() {
+                    let target = offset + key_x(k, row);
+                    print!("{:1$}", "", target.saturating_sub(x));
+                    print!("{} ", symbol(geometry.position(k, layer)));
+                    x = target.max(x) + 2;
+                }
+            }
+            println!();
+        }
     }
-    print!("\n");
 }
 
 
@@ -848,21 +885,18 @@ fn load_word_frequency_list_to_string(path: &Path, multiplier: f64) -> (String,
     file.read_to_string(&mut list).unwrap();
     let filename = path.file_name().unwrap();
     let filename_str = filename.to_str().unwrap();
-    if let Err((c, ln)) = check_valid_ascii_subset(&list[..]) {
-        panic!("On line {} of {}: invalid character, {} -> {}", ln, filename_str, c as u32, c);
-    }
-    let mut line_num = 1usize;
     let mut words = String::new();
     let mut freqs = Vec::new();
-    for line in list[..].lines() {
+    for (line_index, line) in list[..].lines().enumerate() {
+        let line_num = line_index + 1;
         let mut word_freq_pair = line.split('\t');
         let word = match word_freq_pair.next() {
             Some(w) => w.trim(),
             None    => panic!("In word frequency list {} on line {}: empty line.",
                               filename_str, line_num)
         };
-        if words.len() > 0 {
-            words.push_str(" ");
+        if !words.is_empty() {
+            words.push(' ');
         }
         words.push_str(word);
         let freq_input = match word_freq_pair.next() {
@@ -877,7 +911,6 @@ fn load_word_frequency_list_to_string(path: &Path, multiplier: f64) -> (String,
         };
         assert!(freq.is_finite(), "In {} on line {}: infinite frequency!", filename_str, line_num);
         freqs.push((freq * multiplier) as f32);
-        line_num += 1;
     }
     (words, freqs)
 }
@@ -894,9 +927,11 @@ fn load_word_frequency_list_to_string(path: &Path, multiplier: f64) -> (String,
 //}
 
 // Load a word frequency list text file into a word frequency hashmap
-fn load_list_to_word_frequency_hashmap(path: &Path, multiplier: f64, hm: &mut HashMap<String, f32>)
+fn load_list_to_word_frequency_hashmap(path: &Path, multiplier: f64, hm: &mut HashMap<String, f32>,
+                                       charset: &Charset)
 {
     let (words, freqs) = load_word_frequency_list_to_string(path, multiplier);
+    encode_text(&words, path, charset);
     for (word, freq) in words[..].split(' ').zip(freqs.iter()) {
         match hm.entry(word.to_string()) {
             Vacant(entry) => { entry.insert(*freq); },
@@ -905,43 +940,52 @@ fn load_list_to_word_frequency_hashmap(path: &Path, multiplier: f64, hm: &mut Ha
     }
 }
 
-// Load an evaluation text file as a string, verify it, and add it to a word frequency hashmap
-fn load_text_to_word_frequency_hashmap(path: &Path, hm: &mut HashMap<String, f32>)
+// Load an evaluation text file as a string
+fn load_text_file(path: &Path) -> String
 {
     let mut file = std::fs::File::open(path).unwrap();
     let mut text = String::new();
     file.read_to_string(&mut text).unwrap();
-    let filename = path.file_name().unwrap();
-    if let Err((c,ln)) = check_valid_ascii_subset(&text[..]) {
-        panic!("On line {} of {}: invalid character {} -> {}",
-               ln, filename.to_str().unwrap(), c as u32, c);
-    }
-    let new_text = text.replace("\n", " ").replace("\r", " ").replace("\t", " ");
-    for line in new_text[..].lines() {
-        for word in line.split(' ') {
-            let mut c0 = 0;
-            let mut c1 = 0;
-            for c in word.chars() {
-                if !('A' <= c && c <= 'Z') && !('a' <= c && c <= 'z') && c != '\'' {
-                    if c1 > c0 {
-                        match hm.entry(word[c0..c1].to_string()) {
-                            Vacant(entry) => { entry.insert(1.0); },
-                            Occupied(mut entry) => { *entry.get_mut() += 1.0; },
-                        }
-                    }
-                    match hm.entry(c.to_string()) {
+    text
+}
+
+// Convert a text to character indices, and make sure every character of it is in the character set.
+fn encode_text(text: &str, path: &Path, charset: &Charset) -> Vec<u8>
+{
+    match charset.encode(text) {
+        Ok(encoded)  => encoded,
+        Err((c, ln)) => panic!("On line {} of {}: invalid character {} -> {} (characters outside of \
+                                ASCII must be listed in extra_characters or dead_keys)",
+                               ln, path.file_name().unwrap().to_str().unwrap(), c as u32, c)
+    }
+}
+
+// Load an evaluation text file and add it to a word frequency hashmap
+fn load_text_to_word_frequency_hashmap(path: &Path, hm: &mut HashMap<String, f32>, charset: &Charset)
+{
+    let text = load_text_file(path);
+    encode_text(&text, path, charset);
+    for word in text.split(char::is_whitespace) {
+        let mut c0 = 0;
+        for (c1, c) in word.char_indices() {
+            if !c.is_alphabetic() && c != '\'' {
+                if c1 > c0 {
+                    match hm.entry(word[c0..c1].to_string()) {
                         Vacant(entry) => { entry.insert(1.0); },
                         Occupied(mut entry) => { *entry.get_mut() += 1.0; },
                     }
-                    c0 = c1 + 1;
                 }
-                c1 += 1;
-            }
-            if c1 > c0 {
-                match hm.entry(word[c0..c1].to_string()) {
+                match hm.entry(c.to_string()) {
                     Vacant(entry) => { entry.insert(1.0); },
                     Occupied(mut entry) => { *entry.get_mut() += 1.0; },
                 }
+                c0 = c1 + c.len_utf8();
+            }
+        }
+        if word.len() > c0 {
+            match hm.entry(word[c0..].to_string()) {
+                Vacant(entry) => { entry.insert(1.0); },
+                Occupied(mut entry) => { *entry.get_mut() += 1.0; },
             }
         }
     }
@@ -964,56 +1008,69 @@ fn load_text_to_word_frequency_hashmap(path: &Path, hm: &mut HashMap<String, f32
 //    assert!(io_result.is_ok());
 //}
 
-// Load a directory of evaluation texts and word frequency list files into an array of byte arrays
-fn load_texts_directory(dir_filename: &str) -> (Vec<u8>, Vec<f32>)
+// Load a directory of evaluation texts and word frequency list files into n-gram tables. The texts
+// are split into words and merged with the word frequency lists, unless they are to be scored as
+// running text, in which case they are counted exactly as they were typed.
+fn load_texts_directory(dir_filename: &str, config: &Config, charset: &Charset) -> NgramTables
+{
+    let (words, freqs, mut counts) = load_word_list(dir_filename, config, charset);
+    counts.add_words(&words, &freqs);
+    counts.into_tables()
+}
+
+// Load the merged word frequency list of a directory of evaluation texts, along with the counts of
+// the texts that are scored as running text.
+fn load_word_list(dir_filename: &str, config: &Config, charset: &Charset)
+    -> (Vec<u8>, Vec<f32>, NgramCounts)
 {
     let mut hm = HashMap::new();
+    let mut counts = NgramCounts::new(charset.len());
     let dir = Path::new(dir_filename);
     let dir_metadata = std::fs::metadata(dir).unwrap();
     assert!(dir_metadata.is_dir(), "File is not a directory: {:?}", dir);
-    let dir_contents = std::fs::read_dir(&dir).unwrap();
+    let mut dir_contents = std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().path())
+                                                          .collect::<Vec<_>>();
+    dir_contents.sort();
     for entry in dir_contents {
-        let entry = entry.unwrap().path();
         let efn = entry.file_name().unwrap().to_str().unwrap();
         let l = efn.len();
         if l >= 4 && &efn[l-4..l] == ".txt" {
             if l >= 8 && &efn[l-8..l-4] == ".wfl" {
                 let multiplier = if efn == "corpus_1.wfl.txt" {
-                    CORPUS_1_COEFFICIENT
+                    config.corpus_1_coefficient
                 } else if efn == "corpus_2.wfl.txt" {
-                    CORPUS_2_COEFFICIENT
+                    config.corpus_2_coefficient
                 } else {
                     1.0f64
                 };
-                load_list_to_word_frequency_hashmap(&entry, multiplier, &mut hm);
+                load_list_to_word_frequency_hashmap(&entry, multiplier, &mut hm, charset);
+            } else if config.running_text {
+                counts.add_running_text(&encode_text(&load_text_file(&entry), &entry, charset));
             } else {
-                load_text_to_word_frequency_hashmap(&entry, &mut hm);
+                load_text_to_word_frequency_hashmap(&entry, &mut hm, charset);
             }
         }
     }
     let mut hm_vec = hm.iter().collect::<Vec<(&String, &f32)>>();
     hm_vec.sort_by(|a: &(&String, &f32), b: &(&String, &f32)| -> std::cmp::Ordering {
-        let (_, a2): (&String, &f32) = *a;
-        let (_, b2): (&String, &f32) = *b;
-        b2.partial_cmp(a2).unwrap()
+        let (a1, a2): (&String, &f32) = *a;
+        let (b1, b2): (&String, &f32) = *b;
+        b2.partial_cmp(a2).unwrap().then_with(|| a1.cmp(b1))
     });
-    let words_size = hm_vec.len() + hm_vec.iter().map(|&x| x.0.len()).fold(0usize, |a, b| a + b);
+    let words_size = hm_vec.len() + hm_vec.iter().map(|&x| x.0.len()).sum::<usize>();
     let mut words: Vec<u8>  = Vec::with_capacity(words_size);
     let mut freqs: Vec<f32> = Vec::with_capacity(hm_vec.len());
     for wf_tuple in hm_vec.iter() {
         let (word, freq) = *wf_tuple;
-        if *freq > CORPUS_MIN_WORD_FREQUENCY {
-            words.extend(word.as_bytes().iter().map(|&x| x));
-            words.push(32);
+        if *freq > config.corpus_min_word_frequency {
+            words.extend(charset.encode(word).unwrap());
+            words.push(0);
             freqs.push(*freq);
         }
     }
     words.pop();
-    for c in words.iter_mut() {
-        *c -= 32;
-    }
     //output_word_frequency_list(&wfl, &words[..], &freqs[..]);
-    return (words, freqs);
+    (words, freqs, counts)
 }
 
 
@@ -1034,20 +1091,39 @@ fn load_texts_directory(dir_filename: &str) -> (Vec<u8>, Vec<f32>)
 // To perform a swap, we randomly select a type, and then within that type we randomly select two
 // entities to swap.
 
+// A layout with AltGr layers has a fourth type, AltGr swaps, which move a symbol to a position on
+// an AltGr layer, swapping it with the symbol there if the position isn't empty. This is how
+// symbols get onto the AltGr layers and off them again. Key swaps only move the unshifted and
+// shifted characters of the keys, leaving the AltGr layers where they are.
+
+// Swaps are only drawn among those that keep every character off its forbidden keys (see
+// constraints.rs), so only a swap that moves a character with a partner can still break a
+// constraint, when the partner can't follow it. Such a swap is undone and another one is drawn.
+// A layout may have no swap left that satisfies the constraints, so the swapper gives up on an
+// iteration after this many attempts, and the annealing counts the iterations it gave up on.
+const MAX_SWAP_ATTEMPTS: usize = 1000;
+
 #[derive(Copy, Clone)]
 enum LayoutSwap {
     None,
     Symbol(u8),
     Home8K(u8),
     Letter(u8),
+    AltGr(u8),
 }
 
 struct LayoutSwapper
 {
-    tabu_swaps:      [LayoutSwap; 2*NUM_TABU_SWAPS],
+    tabu_swaps:       Vec<LayoutSwap>,
     symbol_swaps:     Vec<u8>,
     home8k_swaps:     Vec<u8>,
     letter_swaps:     Vec<u8>,
+    altgr_swaps:      Vec<u8>,
+    constraints:      Constraints,
+    candidates:       Vec<u8>,
+    original:         Vec<u8>,
+    num_chars:        usize,
+    num_keys:         usize,
     iteration:        usize,
     random_bits:      usize,
     random_bits_left: usize,
@@ -1055,51 +1131,66 @@ struct LayoutSwapper
 
 impl LayoutSwapper
 {
-    fn new(layout: &[u8; 190]) -> LayoutSwapper {
-        assert_valid_layout(layout);
-        let frozen = |s: u8| { FROZEN_SYMBOLS.chars().any(|x| x == (s+32) as char) };
-
-        let symbol_swaps = (1u8..95).filter(|s| {
-            !(33 <= *s && *s < 59) &&
-            !(65 <= *s && *s < 91) &&
+    fn new(layout: &[u8], config: &Config, geometry: &Geometry, charset: &Charset,
+           constraints: &Constraints) -> LayoutSwapper {
+        assert_valid_layout(layout, geometry, charset);
+        assert!(constraints.check(layout).is_ok(), "The layout must satisfy the constraints.");
+        let frozen = |s: u8| { s == NONE || constraints.is_fixed(s) };
+        let num_chars = charset.len();
+        let num_keys  = geometry.num_keys();
+
+        let symbol_swaps = (1..num_chars as u8).filter(|s| {
+            !charset.is_letter(*s) &&
+            layout[*s as usize] != NONE &&
             !frozen(*s)
         }).collect::<Vec<u8>>();
         assert!(symbol_swaps.len() != 1, "Must not have exactly 1 free symbol.");
 
-        let home8k_swaps = HOME_EIGHT.iter().map(|k| *k).filter(|k| {
-            let s       = layout[(95+*k)    as usize];
-            let s_shift = layout[(95+*k+47) as usize];
-            !frozen(s) && !frozen(s_shift)
+        let home8k_swaps = (1..=num_keys as u8).filter(|k| {
+            let s       = layout[num_chars+(*k as usize)];
+            let s_shift = layout[num_chars+(*k as usize)+num_keys];
+            geometry.keys[*k as usize].home && !frozen(s) && !frozen(s_shift)
         }).collect::<Vec<u8>>();
         assert!(home8k_swaps.len() != 1, "Must not have exactly 1 free home eight key.");
 
-        let letter_swaps = (14u8..47).filter(|k| {
-            let s       = layout[(95+*k)    as usize];
-            let s_shift = layout[(95+*k+47) as usize];
-            let c       = (s + 32) as char;
-            c.is_alphabetic() && !frozen(s) && !frozen(s_shift) && !HOME_EIGHT.contains(k) &&
-            *k != 32 && *k != 26 && *k != 19 && *k != 25
+        let letter_swaps = (1..=num_keys as u8).filter(|k| {
+            let key     = &geometry.keys[*k as usize];
+            let s       = layout[num_chars+(*k as usize)];
+            let s_shift = layout[num_chars+(*k as usize)+num_keys];
+            s != NONE && charset.is_letter(s) && !frozen(s) && !frozen(s_shift) && !key.home
         }).collect::<Vec<u8>>();
         assert!(letter_swaps.len() != 1, "Must not have exactly 1 free letter key.");
 
-        assert!(symbol_swaps.len() + home8k_swaps.len() + letter_swaps.len() >= 2 * NUM_TABU_SWAPS,
+        let altgr_swaps = (2 * num_keys + 1..geometry.num_positions()).filter(|p| {
+            let s = layout[num_chars+p];
+            s == NONE || (!charset.is_letter(s) && !frozen(s))
+        }).map(|p| p as u8).collect::<Vec<u8>>();
+
+        let num_swaps = symbol_swaps.len() + home8k_swaps.len() + letter_swaps.len() + altgr_swaps.len();
+        assert!(num_swaps >= 2 * config.num_tabu_swaps,
                 "The number of tabu swaps is higher than the number of possible swaps.");
         LayoutSwapper{
-            tabu_swaps:      [LayoutSwap::None; 2*NUM_TABU_SWAPS],
-            symbol_swaps:     symbol_swaps,
-            home8k_swaps:     home8k_swaps,
-            letter_swaps:     letter_swaps,
+            tabu_swaps:       vec![LayoutSwap::None; 2*config.num_tabu_swaps],
+            symbol_swaps,
+            home8k_swaps,
+            letter_swaps,
+            altgr_swaps,
+            constraints:      constraints.clone(),
+            candidates:       Vec::new(),
+            original:         Vec::new(),
+            num_chars,
+            num_keys,
             iteration:        0,
             random_bits:      0,
             random_bits_left: 0,
         }
     }
 
-    fn random_small_index(&mut self, array_length: usize) -> usize {
+    fn random_small_index<R: Rng>(&mut self, rng: &mut R, array_length: usize) -> usize {
         // Possibly create new random bits
         if self.random_bits_left < 10 {
             self.random_bits_left = std::mem::size_of::<usize>() * 8;
-            self.random_bits = rand::random::<usize>();
+            self.random_bits = rng.next_u64() as usize;
         }
         // Sample 10 bits at a time
         let result = (self.random_bits & ((1<<10)-1)) % array_length;
@@ -1108,13 +1199,15 @@ impl LayoutSwapper
         result
     }
 
-    fn swap(&mut self, layout: &mut [u8; 190]) {
-        if NUM_TABU_SWAPS > 0 {
-            let t1 = self.tabu_swaps[2*self.iteration+0];
+    // Make a random swap that satisfies the constraints, and return whether one was found.
+    fn swap<R: Rng>(&mut self, layout: &mut [u8], rng: &mut R) -> bool {
+        if !self.tabu_swaps.is_empty() {
+            let t1 = self.tabu_swaps[2*self.iteration];
             match t1 {
                 LayoutSwap::Symbol(s) => { self.symbol_swaps.push(s); },
                 LayoutSwap::Home8K(k) => { self.home8k_swaps.push(k); },
                 LayoutSwap::Letter(k) => { self.letter_swaps.push(k); },
+                LayoutSwap::AltGr(p)  => { self.altgr_swaps.push(p); },
                 LayoutSwap::None      => { }
             }
             let t2 = self.tabu_swaps[2*self.iteration+1];
@@ -1122,109 +1215,200 @@ impl LayoutSwapper
                 LayoutSwap::Symbol(s) => { self.symbol_swaps.push(s); },
                 LayoutSwap::Home8K(k) => { self.home8k_swaps.push(k); },
                 LayoutSwap::Letter(k) => { self.letter_swaps.push(k); },
+                LayoutSwap::AltGr(p)  => { self.altgr_swaps.push(p); },
                 LayoutSwap::None      => { }
             }
         }
 
-        let symbol_len = if self.symbol_swaps.len() > 1 { self.symbol_swaps.len() } else { 0 };
-        let home8k_len = if self.home8k_swaps.len() > 1 { self.home8k_swaps.len() } else { 0 };
-        let letter_len = if self.letter_swaps.len() > 1 { self.letter_swaps.len() } else { 0 };
-        let num_swaps = symbol_len + home8k_len + letter_len;
-        let mut i1 = self.random_small_index(num_swaps);
-        if i1 < symbol_len {
-            // symbol swap
-            let mut i2 = self.random_small_index(symbol_len - 1);
-            if i1 <= i2 {
-                i2 += 1;
-            }
-            let s1 = self.symbol_swaps[i1];
-            let s2 = self.symbol_swaps[i2];
-            let k1 = layout[s1 as usize] as usize;
-            let k2 = layout[s2 as usize] as usize;
-            layout.swap(s1 as usize, s2 as usize);
-            layout.swap(95+k1, 95+k2);
-            // add swaps to tabu list
-            if NUM_TABU_SWAPS > 0 {
-                self.tabu_swaps[2*self.iteration+0] = LayoutSwap::Symbol(s1);
-                self.tabu_swaps[2*self.iteration+1] = LayoutSwap::Symbol(s2);
-                if i1 < i2 {
-                    self.symbol_swaps.remove(i2);
-                    self.symbol_swaps.remove(i1);
-                } else {
-                    self.symbol_swaps.remove(i1);
-                    self.symbol_swaps.remove(i2);
-                }
+        // Only a swap that moves a character with a partner needs the layout kept to undo it
+        let mut swap = (LayoutSwap::None, LayoutSwap::None);
+        for _ in 0..MAX_SWAP_ATTEMPTS {
+            let (t1, t2) = match self.random_swap(layout, rng) {
+                Some(swap) => swap,
+                None       => continue,
+            };
+            let moved = self.moved_chars(layout, t1, t2);
+            if !moved.iter().any(|&c| self.constraints.has_partner(c)) {
+                self.apply_swap(layout, t1, t2);
+                swap = (t1, t2);
+                break;
             }
-        } else if i1 < symbol_len + home8k_len {
-            // home-eight-key swap
-            i1 -= symbol_len;
-            let mut i2 = self.random_small_index(home8k_len - 1);
-            if i1 <= i2 {
-                i2 += 1;
+            self.original.clear();
+            self.original.extend_from_slice(layout);
+            self.apply_swap(layout, t1, t2);
+            if self.constraints.follow(layout, &moved) {
+                swap = (t1, t2);
+                break;
             }
-            let k1 = self.home8k_swaps[i1];
-            let k2 = self.home8k_swaps[i2];
-            let k1_index        = 95+(k1 as usize);
-            let k2_index        = 95+(k2 as usize);
-            let k1_shift_index  = 95+(k1 as usize)+47;
-            let k2_shift_index  = 95+(k2 as usize)+47;
-            let k1_symbol       = layout[k1_index]       as usize;
-            let k2_symbol       = layout[k2_index]       as usize;
-            let k1_shift_symbol = layout[k1_shift_index] as usize;
-            let k2_shift_symbol = layout[k2_shift_index] as usize;
-            layout.swap(k1_index,        k2_index);
-            layout.swap(k1_symbol,       k2_symbol);
-            layout.swap(k1_shift_index,  k2_shift_index);
-            layout.swap(k1_shift_symbol, k2_shift_symbol);
-            // add swaps to tabu list
-            if NUM_TABU_SWAPS > 0 {
-                self.tabu_swaps[2*self.iteration+0] = LayoutSwap::Home8K(k1);
-                self.tabu_swaps[2*self.iteration+1] = LayoutSwap::Home8K(k2);
-                if i1 < i2 {
-                    self.home8k_swaps.remove(i2);
-                    self.home8k_swaps.remove(i1);
-                } else {
-                    self.home8k_swaps.remove(i1);
-                    self.home8k_swaps.remove(i2);
-                }
+            layout.copy_from_slice(&self.original);
+        }
+        let found = !matches!(swap.0, LayoutSwap::None);
+
+        // add swaps to tabu list
+        if !self.tabu_swaps.is_empty() {
+            self.tabu_swaps[2*self.iteration] = swap.0;
+            self.tabu_swaps[2*self.iteration+1] = swap.1;
+            for &t in [swap.0, swap.1].iter() {
+                let (swaps, x) = match t {
+                    LayoutSwap::Symbol(s) => (&mut self.symbol_swaps, s),
+                    LayoutSwap::Home8K(k) => (&mut self.home8k_swaps, k),
+                    LayoutSwap::Letter(k) => (&mut self.letter_swaps, k),
+                    LayoutSwap::AltGr(p)  => (&mut self.altgr_swaps,  p),
+                    LayoutSwap::None      => continue,
+                };
+                let i = swaps.iter().position(|&y| y == x).unwrap();
+                swaps.remove(i);
             }
+            self.iteration = (self.iteration + 1) % (self.tabu_swaps.len() / 2);
+        }
+        found
+    }
+
+    // Randomly select a swap type, and then the two entities to swap, the second among those that
+    // make a swap that keeps every character off its forbidden keys. There is none if no entity
+    // does. The second entity is drawn among all of them first, and only drawn again among those
+    // that make an allowed swap if it doesn't, which draws it uniformly among those all the same.
+    fn random_swap<R: Rng>(&mut self, layout: &[u8], rng: &mut R)
+        -> Option<(LayoutSwap, LayoutSwap)> {
+        let symbol_len = if self.symbol_swaps.len() > 1 { self.symbol_swaps.len() } else { 0 };
+        let home8k_len = if self.home8k_swaps.len() > 1 { self.home8k_swaps.len() } else { 0 };
+        let letter_len = if self.letter_swaps.len() > 1 { self.letter_swaps.len() } else { 0 };
+        let altgr_len  = if !self.symbol_swaps.is_empty() { self.altgr_swaps.len() } else { 0 };
+        let num_swaps = symbol_len + home8k_len + letter_len + altgr_len;
+        let mut i1 = self.random_small_index(rng, num_swaps);
+        // An AltGr swap pairs a position on an AltGr layer with any free symbol, and the other swaps
+        // pair two different entities of the same type
+        let (t1, swap_type, same): (LayoutSwap, fn(u8) -> LayoutSwap, Option<usize>) =
+            if i1 >= symbol_len + home8k_len + letter_len {
+                i1 -= symbol_len + home8k_len + letter_len;
+                (LayoutSwap::AltGr(self.altgr_swaps[i1]), LayoutSwap::Symbol, None)
+            } else if i1 < symbol_len {
+                (LayoutSwap::Symbol(self.symbol_swaps[i1]), LayoutSwap::Symbol, Some(i1))
+            } else if i1 < symbol_len + home8k_len {
+                i1 -= symbol_len;
+                (LayoutSwap::Home8K(self.home8k_swaps[i1]), LayoutSwap::Home8K, Some(i1))
+            } else {
+                i1 -= symbol_len + home8k_len;
+                (LayoutSwap::Letter(self.letter_swaps[i1]), LayoutSwap::Letter, Some(i1))
+            };
+        let len = self.swaps_of(swap_type).len();
+        let mut i2 = self.random_small_index(rng, if same.is_some() { len - 1 } else { len });
+        if same.is_some_and(|i1| i1 <= i2) {
+            i2 += 1;
+        }
+        let t2 = swap_type(self.swaps_of(swap_type)[i2]);
+        if !self.constraints.has_forbidden_keys() || self.allows_swap(layout, t1, t2) {
+            return Some((t1, t2));
+        }
+
+        let mut candidates = std::mem::take(&mut self.candidates);
+        candidates.clear();
+        candidates.extend(self.swaps_of(swap_type).iter().enumerate().filter(|&(i, &x)| {
+            Some(i) != same && self.allows_swap(layout, t1, swap_type(x))
+        }).map(|(_, &x)| x));
+        let swap = if candidates.is_empty() {
+            None
         } else {
-            // letter key swap
-            i1 -= symbol_len + home8k_len;
-            let mut i2 = self.random_small_index(letter_len - 1);
-            if i1 <= i2 {
-                i2 += 1;
-            }
-            let k1 = self.letter_swaps[i1];
-            let k2 = self.letter_swaps[i2];
-            let k1_index        = 95+(k1 as usize);
-            let k2_index        = 95+(k2 as usize);
-            let k1_shift_index  = 95+(k1 as usize)+47;
-            let k2_shift_index  = 95+(k2 as usize)+47;
-            let k1_symbol       = layout[k1_index]       as usize;
-            let k2_symbol       = layout[k2_index]       as usize;
-            let k1_shift_symbol = layout[k1_shift_index] as usize;
-            let k2_shift_symbol = layout[k2_shift_index] as usize;
-            layout.swap(k1_index,        k2_index);
-            layout.swap(k1_symbol,       k2_symbol);
-            layout.swap(k1_shift_index,  k2_shift_index);
-            layout.swap(k1_shift_symbol, k2_shift_symbol);
-            // add swaps to tabu list
-            if NUM_TABU_SWAPS > 0 {
-                self.tabu_swaps[2*self.iteration+0] = LayoutSwap::Letter(k1);
-                self.tabu_swaps[2*self.iteration+1] = LayoutSwap::Letter(k2);
-                if i1 < i2 {
-                    self.letter_swaps.remove(i2);
-                    self.letter_swaps.remove(i1);
-                } else {
-                    self.letter_swaps.remove(i1);
-                    self.letter_swaps.remove(i2);
-                }
-            }
+            let i2 = self.random_small_index(rng, candidates.len());
+            Some((t1, swap_type(candidates[i2])))
+        };
+        self.candidates = candidates;
+        swap
+    }
+
+    // The entities that can be swapped by a type of swap.
+    fn swaps_of(&self, swap_type: fn(u8) -> LayoutSwap) -> &[u8] {
+        match swap_type(0) {
+            LayoutSwap::Symbol(_) => &self.symbol_swaps,
+            LayoutSwap::Home8K(_) => &self.home8k_swaps,
+            LayoutSwap::Letter(_) => &self.letter_swaps,
+            _                     => &self.altgr_swaps,
+        }
+    }
+
+    // Whether a swap keeps every character that it moves off the keys forbidden to it.
+    fn allows_swap(&self, layout: &[u8], t1: LayoutSwap, t2: LayoutSwap) -> bool {
+        let num_chars = self.num_chars;
+        let num_keys  = self.num_keys;
+        let key       = |p: u8| (p as usize - 1) % num_keys + 1;
+        let allows    = |c: u8, k: usize| self.constraints.allows(c, k);
+        match (t1, t2) {
+            (LayoutSwap::Symbol(s1), LayoutSwap::Symbol(s2)) => {
+                allows(s1, key(layout[s2 as usize])) && allows(s2, key(layout[s1 as usize]))
+            },
+            (LayoutSwap::Home8K(k1), LayoutSwap::Home8K(k2)) |
+            (LayoutSwap::Letter(k1), LayoutSwap::Letter(k2)) => {
+                let (k1, k2) = (k1 as usize, k2 as usize);
+                (0..2).all(|layer| {
+                    allows(layout[num_chars+k1+layer*num_keys], k2) &&
+                    allows(layout[num_chars+k2+layer*num_keys], k1)
+                })
+            },
+            (LayoutSwap::AltGr(p), LayoutSwap::Symbol(s)) => {
+                let t = layout[num_chars+(p as usize)];
+                allows(s, key(p)) && allows(t, key(layout[s as usize]))
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    // The characters that a swap moves.
+    fn moved_chars(&self, layout: &[u8], t1: LayoutSwap, t2: LayoutSwap) -> [u8; 4] {
+        let num_chars = self.num_chars;
+        let num_keys  = self.num_keys;
+        match (t1, t2) {
+            (LayoutSwap::Symbol(s1), LayoutSwap::Symbol(s2)) => [s1, s2, NONE, NONE],
+            (LayoutSwap::Home8K(k1), LayoutSwap::Home8K(k2)) |
+            (LayoutSwap::Letter(k1), LayoutSwap::Letter(k2)) => {
+                let (k1, k2) = (k1 as usize, k2 as usize);
+                [layout[num_chars+k1], layout[num_chars+k2],
+                 layout[num_chars+k1+num_keys], layout[num_chars+k2+num_keys]]
+            },
+            (LayoutSwap::AltGr(p), LayoutSwap::Symbol(s)) => {
+                [s, layout[num_chars+(p as usize)], NONE, NONE]
+            },
+            _ => unreachable!(),
         }
+    }
 
-        if NUM_TABU_SWAPS > 0 {
-            self.iteration = (self.iteration + 1) % NUM_TABU_SWAPS;
+    // Perform a swap on a layout.
+    fn apply_swap(&self, layout: &mut [u8], t1: LayoutSwap, t2: LayoutSwap) {
+        let num_chars = self.num_chars;
+        let num_keys  = self.num_keys;
+        match (t1, t2) {
+            (LayoutSwap::Symbol(s1), LayoutSwap::Symbol(s2)) => {
+                let k1 = layout[s1 as usize] as usize;
+                let k2 = layout[s2 as usize] as usize;
+                layout.swap(s1 as usize, s2 as usize);
+                layout.swap(num_chars+k1, num_chars+k2);
+            },
+            (LayoutSwap::Home8K(k1), LayoutSwap::Home8K(k2)) |
+            (LayoutSwap::Letter(k1), LayoutSwap::Letter(k2)) => {
+                let k1_index        = num_chars+(k1 as usize);
+                let k2_index        = num_chars+(k2 as usize);
+                let k1_shift_index  = num_chars+(k1 as usize)+num_keys;
+                let k2_shift_index  = num_chars+(k2 as usize)+num_keys;
+                let k1_symbol       = layout[k1_index]       as usize;
+                let k2_symbol       = layout[k2_index]       as usize;
+                let k1_shift_symbol = layout[k1_shift_index] as usize;
+                let k2_shift_symbol = layout[k2_shift_index] as usize;
+                layout.swap(k1_index,        k2_index);
+                layout.swap(k1_symbol,       k2_symbol);
+                layout.swap(k1_shift_index,  k2_shift_index);
+                layout.swap(k1_shift_symbol, k2_shift_symbol);
+            },
+            (LayoutSwap::AltGr(p), LayoutSwap::Symbol(s)) => {
+                let p = p as usize;
+                let q = layout[s as usize] as usize;
+                let t = layout[num_chars+p];
+                layout[s as usize]  = p as u8;
+                layout[num_chars+p] = s;
+                layout[num_chars+q] = t;
+                if t != NONE {
+                    layout[t as usize] = q as u8;
+                }
+            },
+            _ => unreachable!(),
         }
     }
 }
@@ -1240,27 +1424,13 @@ struct LayoutScore
 
 impl LayoutScore
 {
-    fn zero() -> LayoutScore {
-        LayoutScore{ i: 0i32, f: 0f32 }
-    }
-
-    fn to_f64(&self) -> f64 {
+    fn to_f64(self) -> f64 {
         (self.i as f64)*1000.0 + (self.f as f64)
     }
 
-    fn add_f32(&mut self, addend: f32) {
-        self.f += addend;
-        if self.f >  1000.0 {
-            let d_i = (self.f / 1000.0) as i32;
-            self.i +=  d_i;
-            self.f -= (d_i * 1000) as f32;
-            return;
-        }
-        if self.f < -1000.0 {
-            let d_i = (self.f / 1000.0) as i32;
-            self.i +=  d_i;
-            self.f -= (d_i * 1000) as f32;
-        }
+    fn from_f64(x: f64) -> LayoutScore {
+        let i = (x / 1000.0) as i32;
+        LayoutScore{ i, f: (x - (i as f64)*1000.0) as f32 }
     }
 }
 
@@ -1285,9 +1455,9 @@ impl Add<LayoutScore> for LayoutScore {
 impl PartialEq for LayoutScore
 {
     fn eq(&self, other: &LayoutScore) -> bool {
-        ((self.i == other.i   && self.f == other.f) ||
-         (self.i == other.i-1 && self.f == other.f + 1000.0) ||
-         (self.i == other.i+1 && self.f == other.f - 1000.0))
+        (self.i == other.i   && self.f == other.f) ||
+        (self.i == other.i-1 && self.f == other.f + 1000.0) ||
+        (self.i == other.i+1 && self.f == other.f - 1000.0)
     }
 }
 
@@ -1308,13 +1478,23 @@ impl PartialOrd for LayoutScore
     }
 
     fn lt(&self, other: &LayoutScore) -> bool {
-        ((self.i <  other.i-1) ||
-         (self.i == other.i-1 && self.f-1000.0 < other.f) ||
-         (self.i == other.i   && self.f        < other.f) ||
-         (self.i == other.i+1 && self.f+1000.0 < other.f))
+        (self.i <  other.i-1) ||
+        (self.i == other.i-1 && self.f-1000.0 < other.f) ||
+        (self.i == other.i   && self.f        < other.f) ||
+        (self.i == other.i+1 && self.f+1000.0 < other.f)
     }
 }
 
+// Scores are added up exactly, as integers counting steps of 2^-32 of a metric score times steps of
+// the frequency grid, so that the total doesn't depend on the order the terms are added in.
+const SCORE_UNIT: f64 = 4294967296.0;
+
+// One term of a score, a metric score charged at a frequency, in exact steps.
+fn exact_term(score: f32, freq: f64) -> i128
+{
+    ((score as f64) * SCORE_UNIT).round() as i128 * (freq * FREQUENCY_UNIT).round() as i128
+}
+
 // Calculate the probability of accepting a random layout swap based on score differences.
 fn probability(s0: LayoutScore, s1: LayoutScore, t: f64) -> f64
 {
@@ -1325,110 +1505,310 @@ fn probability(s0: LayoutScore, s1: LayoutScore, t: f64) -> f64
     }
 }
 
-// Hard-coded bloom-like filter (false positives, no false negatives) to speed up hashmap look-up
-fn triple_filter(k0: u8, k1: u8, k2: u8) -> bool
+// A single term of the objective function: the corpus-weighted sum of its metric (the raw value)
+// along with the coefficient that the raw value is multiplied by in the linear combination.
+#[derive(Copy, Clone, Default)]
+struct ScoreTerm
 {
-    let d = 2*(k1 as i32) - (k0 as i32) - (k2 as i32);
-    (-1 <= d && d <= 1) || (-13 <= d && d <= -10) || d == -26
+    raw:         f64,
+    coefficient: f64,
 }
 
-#[test]
-fn triple_filter_test()
+impl ScoreTerm
+{
+    fn contribution(&self) -> f64 {
+        self.raw * self.coefficient
+    }
+}
+
+// The objective function split up into the terms of its linear combination, so that reports can
+// show which term dominates a score and parameter studies can see what each coefficient is worth.
+#[derive(Copy, Clone, Default)]
+struct ScoreBreakdown
 {
-    for &(k0, k1, k2, _) in TRIPLE_METRIC.iter() {
-        assert!(triple_filter(k0, k1, k2),
-                "Triple filter has a false negative for {}, {}, {}", k0, k1, k2);
+    single:          ScoreTerm,
+    double:          ScoreTerm,
+    triple:          ScoreTerm,
+    shift:           ScoreTerm,
+    altgr:           ScoreTerm,
+    altgr_shift:     ScoreTerm,
+    alternation:     ScoreTerm,
+    reversed_triple: ScoreTerm,
+    space_skipgram:  ScoreTerm,
+    shortcut:        ScoreTerm,
+    similarity:      ScoreTerm,
+}
+
+impl ScoreBreakdown
+{
+    fn terms(&self) -> [(&'static str, ScoreTerm); 11] {
+        [("Single key",       self.single),
+         ("Double key",       self.double),
+         ("Triple key",       self.triple),
+         ("Shift holding",    self.shift),
+         ("AltGr holding",    self.altgr),
+         ("AltGr+shift hold", self.altgr_shift),
+         ("Hand alternation", self.alternation),
+         ("Reversed triple",  self.reversed_triple),
+         ("Space skipgram",   self.space_skipgram),
+         ("Shortcut keys",    self.shortcut),
+         ("Similarity",       self.similarity)]
+    }
+
+    fn total(&self) -> f64 {
+        self.terms().iter().map(|t| t.1.contribution()).sum()
+    }
+
+    fn print(&self) {
+        let total = self.total();
+        println!("    {:18} {:>14} {:>11} {:>14} {:>7}", "Term", "Raw", "Coefficient", "Contribution", "");
+        for &(name, term) in self.terms().iter() {
+            let percent = if total != 0.0 { 100.0 * term.contribution() / total } else { 0.0 };
+            println!("    {:18} {:14.2} {:11.4} {:14.2} {:6.1}%",
+                     name, term.raw, term.coefficient, term.contribution(), percent);
+        }
     }
 }
 
 // Custom objective function
+#[derive(Clone)]
 struct LayoutObjectiveFunction
 {
-    words:         Vec<u8>,
-    freqs:         Vec<f32>,
-    double_scores: [f32; 9025],
-    triple_scores: HashMap<(u8, u8, u8), f32>,
+    config:            Config,
+    geometry:          Geometry,
+    charset:           Charset,
+    ngrams:            NgramTables,
+    double_scores:     Vec<f32>,
+    triple_scores:     Vec<f32>,
+    skipgram_scores:   Vec<f32>,
+    position_scores:   Vec<f32>,
+
+    // The unweighted terms that double_scores and triple_scores are built from, kept for reports
+    single_terms:      Vec<f32>,
+    shift_terms:       Vec<f32>,
+    altgr_terms:       Vec<f32>,
+    altgr_shift_terms: Vec<f32>,
+    double_terms:      Vec<f32>,
+    alternation_terms: Vec<f32>,
+    triple_terms:      HashMap<(u8, u8, u8), (f32, bool)>,
+    shortcut_terms:    Vec<f32>,
+    similarity_terms:  Vec<f32>,
 }
 
 impl LayoutObjectiveFunction
 {
     // Assemble the objective function for scoring layouts. It is a linear combination of single
     // key score, double key score, triple key score, a hand-alternation penalty, a shift-holding
-    // penalty, and a reversed triple-penalty.
-    fn new() -> LayoutObjectiveFunction {
-        let (words, freqs) = load_texts_directory("texts");
-
-        let mut double_scores = [0f32; 9025]; // (9025 = 95*95)
+    // penalty, a reversed triple-penalty, and for running text the double key score across spaces,
+    // along with optional penalties for moving shortcut letters off their QWERTY keys and characters
+    // away from a reference layout.
+    fn new(config: &Config, geometry: Geometry, charset: Charset, texts_dir: &str)
+        -> LayoutObjectiveFunction {
+        let ngrams = load_texts_directory(texts_dir, config, &charset);
+        LayoutObjectiveFunction::from_ngrams(config, geometry, charset, ngrams)
+    }
 
-        // Add in single key scores and shift penalties, removing repeat penalties
-        let min_single_metric = SINGLE_METRIC.iter().fold(std::f32::INFINITY, |m, &x| m.min(x));
-        for i in 0..95 {
-            for j in 0..48 {
+    // Assemble the objective function for already compiled n-gram tables. Each term is first
+    // tabulated separately for every transition between two positions, and then the terms are
+    // combined with their coefficients. The tables are indexed by 256 times the first position
+    // plus the second, so that transitions from or to a character that is not on the layout (NONE)
+    // fall on entries that are always 0.
+    fn from_ngrams(config: &Config, geometry: Geometry, charset: Charset, ngrams: NgramTables)
+        -> LayoutObjectiveFunction {
+        let num_keys      = geometry.num_keys();
+        let num_positions = geometry.num_positions();
+        let mut single_terms      = vec![0f32; 65536]; // (65536 = 256*256)
+        let mut shift_terms       = vec![0f32; 65536];
+        let mut altgr_terms       = vec![0f32; 65536];
+        let mut altgr_shift_terms = vec![0f32; 65536];
+        let mut double_terms      = vec![0f32; 65536];
+        let mut alternation_terms = vec![0f32; 65536];
+
+        // Single key scores and the penalties for holding shift or AltGr, removing repeat penalties.
+        // The space bar is pressed by a thumb, which has its own cost.
+        let min_single_metric = geometry.keys.iter().fold(f32::INFINITY, |m, k| m.min(k.cost));
+        for i in 0..num_positions {
+            for j in 0..num_positions {
                 if j != i {
-                    let s = (SINGLE_METRIC[j  ] - min_single_metric) * SINGLE_METRIC_COEFFICIENT;
-                    double_scores[i*95+j   ] = s;
+                    let key  = &geometry.keys[geometry.key(j)];
+                    let cost = if j == 0 { config.space_key_metric } else { key.cost };
+                    single_terms[i*256+j] = cost - min_single_metric;
+                    match geometry.layer(j) {
+                        1 => shift_terms[i*256+j]       = 1.0,
+                        2 => altgr_terms[i*256+j]       = 1.0,
+                        3 => altgr_shift_terms[i*256+j] = 1.0,
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        // Double key scores, which are the same on every layer of the two keys
+        let min_double_metric = geometry.double_metric.iter().fold(f32::INFINITY, |m,&x| m.min(x.2));
+        for &(ki, kj, ks) in geometry.double_metric.iter() {
+            let s  = ks - min_double_metric;
+            for li in 0..geometry.layers {
+                for lj in 0..geometry.layers {
+                    let i = geometry.position(ki as usize, li);
+                    let j = geometry.position(kj as usize, lj);
+                    double_terms[i*256+j] += s;
                 }
             }
-            for j in 0..47 {
-                if j+48 != i {
-                    let s = (SINGLE_METRIC[j+1] - min_single_metric) * SINGLE_METRIC_COEFFICIENT;
-                    double_scores[i*95+j+48] = s + SHIFT_HOLDING_PENALTY;
+        }
+
+        // Alternating hand penalties
+        for i in 0..num_positions {
+            for j in 0..num_positions {
+                let hi = geometry.hand(i);
+                let hj = geometry.hand(j);
+                if (hi != 0) && (hj != 0) && hi != hj {
+                    alternation_terms[i*256+j] = 1.0;
                 }
             }
         }
 
-        // Add in the double key scores
-        let min_double_metric = DOUBLE_METRIC.iter().fold(std::f32::INFINITY, |m,&x| m.min(x.2));
-        for &(ki, kj, ks) in DOUBLE_METRIC.iter() {
-            let i  = ki as usize;
-            let j  = kj as usize;
-            let s  = (ks - min_double_metric) * DOUBLE_METRIC_COEFFICIENT;
-            double_scores[ i    *95+j   ] += s;
-            double_scores[ i    *95+j+47] += s;
-            double_scores[(i+47)*95+j   ] += s;
-            double_scores[(i+47)*95+j+47] += s;
-        }
-
-        // Add in alternating hand penalties
-        for i in 0..95 {
-            for j in 0..95 {
-                let ai = if i < 48 { i } else { i - 47 };
-                let aj = if j < 48 { j } else { j - 47 };
-                let fi = FINGER_ASSIGNMENT[ai];
-                let fj = FINGER_ASSIGNMENT[aj];
-                if (fi != 0) && (fj != 0) && ((fi < 5 && fj >= 5) || (fj < 5 && fi >= 5)) {
-                    double_scores[i*95+j] += HAND_ALTERNATION_PENALTY;
+        // Triple key scores, marking the reversed ones
+        let mut triple_terms: HashMap<(u8, u8, u8), (f32, bool)> = HashMap::new();
+        for &(k1, k2, k3, ks) in geometry.triple_metric.iter() {
+            triple_terms.insert((k1,k2,k3), (ks, false));
+            triple_terms.insert((k3,k2,k1), (ks, true));
+        }
+
+        // Combine the terms
+        let mut double_scores = vec![0f32; 65536];
+        for i in 0..65536 {
+            double_scores[i] = single_terms[i]      * config.single_metric_coefficient
+                             + shift_terms[i]       * config.shift_holding_penalty
+                             + altgr_terms[i]       * config.altgr_holding_penalty
+                             + altgr_shift_terms[i] * config.altgr_shift_penalty
+                             + double_terms[i]      * config.double_metric_coefficient
+                             + alternation_terms[i] * config.hand_alternation_penalty;
+        }
+        let mut skipgram_scores = vec![0f32; 65536];
+        for i in 0..65536 {
+            skipgram_scores[i] = double_terms[i] * config.double_metric_coefficient
+                                                 * config.space_skipgram_weight;
+        }
+        // The triple metric only applies to unshifted keys, so its table is indexed by keys
+        let n = num_keys + 1;
+        let mut triple_scores = vec![0f32; n*n*n];
+        for (&(k0, k1, k2), &(ks, reversed)) in triple_terms.iter() {
+            let s = ks * config.triple_metric_coefficient;
+            let r = config.reversed_triple_penalty * config.triple_metric_coefficient;
+            let i = ((k0 as usize)*n + (k1 as usize))*n + (k2 as usize);
+            triple_scores[i] = if reversed { s+r } else { s };
+        }
+
+        // Shortcut letters off their QWERTY keys, which are scored by where each character is rather
+        // than by the n-grams, so this table is indexed by 256 times the character plus its position
+        let mut shortcut_terms = vec![0f32; 65536];
+        if !config.shortcut_keys.is_empty() {
+            let qwerty = layout_from_string(&geometry.qwerty_layout(), &geometry, &charset);
+            for c in config.shortcut_keys.to_lowercase().chars() {
+                let c = charset.index(c).unwrap() as usize;
+                let home_key = geometry.key(qwerty[c] as usize);
+                for p in 1..num_positions {
+                    if geometry.key(p) != home_key {
+                        shortcut_terms[c*256+p] = 1.0;
+                    }
                 }
             }
         }
 
-        let mut triple_scores: HashMap<(u8, u8, u8), f32> = HashMap::new();
-        for &(k1, k2, k3, ks) in TRIPLE_METRIC.iter() {
-            let s = ks * TRIPLE_METRIC_COEFFICIENT;
-            triple_scores.insert((k1,k2,k3), s);
-            triple_scores.insert((k3,k2,k1), s+REVERSED_TRIPLE_PENALTY*TRIPLE_METRIC_COEFFICIENT);
+        // Similarity to the reference layout, charging every keystroke of a character by how far it
+        // has moved: 1 for another layer of the same key or another key of the same finger, 2 for
+        // another finger of the same hand, and 3 for the other hand, which is the hardest to relearn
+        let reference = read_reference_layout(config, &geometry, &charset);
+        let mut similarity_terms = vec![0f32; 65536];
+        for c in 0..charset.len() {
+            let r = reference[c] as usize;
+            if r == NONE as usize {
+                continue;
+            }
+            for p in 0..num_positions {
+                let distance = if p == r {
+                    0.0
+                } else if geometry.finger(p) == geometry.finger(r) {
+                    1.0
+                } else if geometry.hand(p) == geometry.hand(r) {
+                    2.0
+                } else {
+                    3.0
+                };
+                similarity_terms[c*256+p] = distance * (ngrams.unigrams[c] as f32);
+            }
+        }
+
+        let mut position_scores = vec![0f32; 65536];
+        for i in 0..65536 {
+            position_scores[i] = shortcut_terms[i]   * config.shortcut_penalty
+                               + similarity_terms[i] * config.similarity_penalty;
         }
 
         LayoutObjectiveFunction{
-            words: words,
-            freqs: freqs,
-            double_scores: double_scores,
-            triple_scores: triple_scores
+            config: config.clone(),
+            geometry,
+            charset,
+            ngrams,
+            double_scores,
+            triple_scores,
+            skipgram_scores,
+            position_scores,
+            single_terms,
+            shift_terms,
+            altgr_terms,
+            altgr_shift_terms,
+            double_terms,
+            alternation_terms,
+            triple_terms,
+            shortcut_terms,
+            similarity_terms,
         }
     }
 
-    // Assign a score to a word in adjusted byte format
-    fn word_score(&self, layout: &[u8; 190], word: &[u8]) -> f32 {
+    // Assign a score to a word in adjusted byte format. Layouts are scored from the n-gram tables,
+    // which give the same result as summing this over the word list. A character typed with a dead
+    // key or compose sequence appears in the word as its keys, so the whole sequence is charged.
+    #[cfg(test)]
+    fn word_score(&self, layout: &[u8], word: &[u8]) -> f32 {
         let mut score = 0f32;
         let mut k0 = 0u8;
         let mut k1 = 0u8;
         for c2 in word.iter() {
             let k2 = layout[*c2 as usize];
-            score += self.double_scores[(k1 as usize) * 95 + (k2 as usize)];
-            if k0 != 0 && k1 != 0 && triple_filter(k0, k1, k2) {
-                if let Some(&triple_score) = self.triple_scores.get(&(k0, k1, k2)) {
-                    score += triple_score;
-                }
+            score += self.double_scores[(k1 as usize) * 256 + (k2 as usize)];
+            if k0 != 0 && k1 != 0 {
+                score += self.triple_score(k0, k1, k2);
+            }
+            k0 = k1;
+            k1 = k2;
+        }
+        score
+    }
+
+    fn triple_score(&self, k0: u8, k1: u8, k2: u8) -> f32 {
+        let n = self.geometry.keys.len();
+        let (k0, k1, k2) = (k0 as usize, k1 as usize, k2 as usize);
+        if k0 < n && k1 < n && k2 < n {
+            self.triple_scores[(k0*n + k1)*n + k2]
+        } else {
+            0.0
+        }
+    }
+
+    // The exact score of a word typed with the given frequency, the sum of the same terms as
+    // word_score.
+    #[cfg(test)]
+    fn exact_word_score(&self, layout: &[u8], word: &[u8], freq: f64) -> i128 {
+        let freq = grid_frequency(freq);
+        let mut score = 0i128;
+        let mut k0 = 0u8;
+        let mut k1 = 0u8;
+        for c2 in word.iter() {
+            let k2 = layout[*c2 as usize];
+            score += exact_term(self.double_scores[(k1 as usize) * 256 + (k2 as usize)], freq);
+            if k0 != 0 && k1 != 0 {
+                score += exact_term(self.triple_score(k0, k1, k2), freq);
             }
             k0 = k1;
             k1 = k2;
@@ -1436,42 +1816,156 @@ impl LayoutObjectiveFunction
         score
     }
 
-    // Assign a score to a layout based on the metric scores applied to a word frequency list.
-    fn score(&self, layout: &[u8; 190]) -> LayoutScore {
-        let mut score = LayoutScore::zero();
-        for (word, freq) in self.words.split(|x| { *x == 0 }).zip(self.freqs.iter()) {
-            score.add_f32(self.word_score(layout, word) * *freq);
+    // Score a bigram and a trigram of the n-gram tables on a layout.
+    fn bigram_score(&self, layout: &[u8], c0: u8, c1: u8) -> f32 {
+        self.double_scores[(layout[c0 as usize] as usize) * 256 + (layout[c1 as usize] as usize)]
+    }
+
+    fn trigram_score(&self, layout: &[u8], c0: u8, c1: u8, c2: u8) -> f32 {
+        self.triple_score(layout[c0 as usize], layout[c1 as usize], layout[c2 as usize])
+    }
+
+    // Score the two characters typed on either side of a space.
+    fn skipgram_score(&self, layout: &[u8], c0: u8, c1: u8) -> f32 {
+        self.skipgram_scores[(layout[c0 as usize] as usize) * 256 + (layout[c1 as usize] as usize)]
+    }
+
+    // Score the position of a single character.
+    fn position_score(&self, layout: &[u8], c: usize) -> f32 {
+        self.position_scores[c * 256 + (layout[c] as usize)]
+    }
+
+    // Assign a score to a layout based on the metric scores applied to the n-gram tables, and the
+    // positions of the characters.
+    fn score(&self, layout: &[u8]) -> LayoutScore {
+        LayoutScore::from_f64(self.exact_score(layout) as f64 / (SCORE_UNIT * FREQUENCY_UNIT))
+    }
+
+    // The score of a layout, added up exactly (see exact_term).
+    fn exact_score(&self, layout: &[u8]) -> i128 {
+        let mut score = 0i128;
+        for &(c0, c1, freq) in self.ngrams.bigrams.iter() {
+            score += exact_term(self.bigram_score(layout, c0, c1), freq);
+        }
+        for &(c0, c1, c2, freq) in self.ngrams.trigrams.iter() {
+            score += exact_term(self.trigram_score(layout, c0, c1, c2), freq);
+        }
+        for &(c0, c1, freq) in self.ngrams.skipgrams.iter() {
+            score += exact_term(self.skipgram_score(layout, c0, c1), freq);
+        }
+        for c in 0..self.charset.len() {
+            score += exact_term(self.position_score(layout, c), 1.0);
         }
         score
     }
 
-    // Calculate the equivalent of a count for each character, sort them, and print them.
-    fn print_char_counts(&self) {
-        let char_count = |c: char| -> f32 {
-            let mut count = 0.0;
-            for (word, freq) in self.words.split(|x| { *x == 0 }).zip(self.freqs.iter()) {
-                for i in word.iter() {
-                    if (i +32) as char == c {
-                        count += *freq;
-                    }
+    // Calculate the change in score from one layout to another that differs from it in only a few
+    // characters, rescoring just the n-grams that contain at least one of those characters. An
+    // n-gram containing several of them is only counted for the first.
+    fn score_delta(&self, layout: &[u8], new_layout: &[u8]) -> LayoutScore {
+        let mut delta   = 0f64;
+        let mut changed = [0u128; 2];
+        for c in 0..self.charset.len() {
+            if layout[c] == new_layout[c] {
+                continue;
+            }
+            let counted = |x: u8| changed[(x >> 7) as usize] & (1u128 << (x & 127)) != 0;
+            for &i in self.ngrams.char_bigrams[c].iter() {
+                let (c0, c1, freq) = self.ngrams.bigrams[i as usize];
+                if counted(c0) || counted(c1) {
+                    continue;
                 }
+                let d = self.bigram_score(new_layout, c0, c1) - self.bigram_score(layout, c0, c1);
+                delta += (d as f64) * freq;
             }
-            count
-        };
+            for &i in self.ngrams.char_trigrams[c].iter() {
+                let (c0, c1, c2, freq) = self.ngrams.trigrams[i as usize];
+                if counted(c0) || counted(c1) || counted(c2) {
+                    continue;
+                }
+                let d = self.trigram_score(new_layout, c0, c1, c2)
+                      - self.trigram_score(layout, c0, c1, c2);
+                delta += (d as f64) * freq;
+            }
+            for &i in self.ngrams.char_skipgrams[c].iter() {
+                let (c0, c1, freq) = self.ngrams.skipgrams[i as usize];
+                if counted(c0) || counted(c1) {
+                    continue;
+                }
+                let d = self.skipgram_score(new_layout, c0, c1) - self.skipgram_score(layout, c0, c1);
+                delta += (d as f64) * freq;
+            }
+            delta += (self.position_score(new_layout, c) - self.position_score(layout, c)) as f64;
+            changed[c >> 7] |= 1u128 << (c & 127);
+        }
+        LayoutScore::from_f64(delta)
+    }
+
+    // Split the score of a layout into the separate terms of the objective function. This follows
+    // the same steps as score, but looks each term up in its own table.
+    fn breakdown(&self, layout: &[u8]) -> ScoreBreakdown {
+        let mut raw = [0f64; 11];
+        for &(c0, c1, freq) in self.ngrams.bigrams.iter() {
+            let i = (layout[c0 as usize] as usize) * 256 + (layout[c1 as usize] as usize);
+            raw[0] += (self.single_terms[i]      as f64) * freq;
+            raw[1] += (self.double_terms[i]      as f64) * freq;
+            raw[3] += (self.shift_terms[i]       as f64) * freq;
+            raw[4] += (self.alternation_terms[i] as f64) * freq;
+            raw[7] += (self.altgr_terms[i]       as f64) * freq;
+            raw[8] += (self.altgr_shift_terms[i] as f64) * freq;
+        }
+        for &(c0, c1, c2, freq) in self.ngrams.trigrams.iter() {
+            let (k0, k1, k2) = (layout[c0 as usize], layout[c1 as usize], layout[c2 as usize]);
+            if let Some(&(ks, reversed)) = self.triple_terms.get(&(k0, k1, k2)) {
+                raw[2] += (ks as f64) * freq;
+                if reversed {
+                    raw[5] += freq;
+                }
+            }
+        }
+        for &(c0, c1, freq) in self.ngrams.skipgrams.iter() {
+            let i = (layout[c0 as usize] as usize) * 256 + (layout[c1 as usize] as usize);
+            raw[6] += (self.double_terms[i] as f64) * freq;
+        }
+        for (c, &p) in layout[..self.charset.len()].iter().enumerate() {
+            raw[9]  += self.shortcut_terms[c * 256 + (p as usize)]   as f64;
+            raw[10] += self.similarity_terms[c * 256 + (p as usize)] as f64;
+        }
+        let config = &self.config;
+        let term = |raw: f64, coefficient: f32| ScoreTerm{ raw, coefficient: coefficient as f64 };
+        ScoreBreakdown{
+            single:          term(raw[0], config.single_metric_coefficient),
+            double:          term(raw[1], config.double_metric_coefficient),
+            triple:          term(raw[2], config.triple_metric_coefficient),
+            shift:           term(raw[3], config.shift_holding_penalty),
+            altgr:           term(raw[7], config.altgr_holding_penalty),
+            altgr_shift:     term(raw[8], config.altgr_shift_penalty),
+            alternation:     term(raw[4], config.hand_alternation_penalty),
+            reversed_triple: term(raw[5], config.reversed_triple_penalty *
+                                          config.triple_metric_coefficient),
+            space_skipgram:  term(raw[6], config.space_skipgram_weight *
+                                          config.double_metric_coefficient),
+            shortcut:        term(raw[9], config.shortcut_penalty),
+            similarity:      term(raw[10], config.similarity_penalty),
+        }
+    }
+
+    // Calculate the equivalent of a count for each character, sort them, and print them.
+    fn print_char_counts(&self) {
+        let charset = &self.charset;
+        let char_count = |i: u8| -> f64 { self.ngrams.unigrams[i as usize] };
         let mut char_counts = Vec::new();
-        for byte in 33u8..127 {
-            if ('a' as u8) <= byte && byte <= ('z' as u8) {
+        for i in 1..charset.len() as u8 {
+            if charset.is_lower_case_letter(i) {
                 continue;
             }
-            let c = byte as char;
-            let count = if ('A' as u8) <= byte && byte <= ('Z' as u8) {
-                char_count(c) + char_count(((c as u8) + 32) as char)
-            } else {
-                char_count(c)
+            let count = match charset.other_case(i) {
+                Some(lower) if charset.is_upper_case_letter(i) => char_count(i) + char_count(lower),
+                _                                              => char_count(i),
             };
-            char_counts.push((c, count));
+            char_counts.push((charset.char(i), count));
         }
-        char_counts.sort_by(|a: &(char, f32), b: &(char, f32)| -> std::cmp::Ordering {
+        char_counts.sort_by(|a: &(char, f64), b: &(char, f64)| -> std::cmp::Ordering {
             let (_, a_count) = *a;
             let (_, b_count) = *b;
             b_count.partial_cmp(&a_count).unwrap()
@@ -1482,41 +1976,103 @@ impl LayoutObjectiveFunction
         }
     }
 
-    // Calculate how much each finger is used as a percentage for each hand.
-    fn print_layout_finger_usage(&self, layout: &[u8]) {
-        let mut fu = [0f32; 8]; // finger usage
-        for (word, freq) in self.words.split(|x| { *x == 0 }).zip(self.freqs.iter()) {
-            for c in word.iter() {
-                let k = layout[*c as usize] as usize;
-                let i = if k < 48 { k } else { k - 47 };
-                let finger_index = FINGER_ASSIGNMENT[i];
-                assert!(finger_index > 0);
-                fu[(finger_index - 1) as usize] += *freq;
+    // The same objective function with another similarity penalty.
+    fn with_similarity_penalty(&self, penalty: f32) -> LayoutObjectiveFunction {
+        let mut objective = self.clone();
+        objective.config.similarity_penalty = penalty;
+        for i in 0..65536 {
+            objective.position_scores[i] = self.shortcut_terms[i]   * self.config.shortcut_penalty
+                                         + self.similarity_terms[i] * penalty;
+        }
+        objective
+    }
+
+    // How far a layout is from the reference layout, which is the raw similarity term.
+    fn similarity(&self, layout: &[u8]) -> f64 {
+        (0..self.charset.len()).map(|c| self.similarity_terms[c*256 + (layout[c] as usize)] as f64).sum()
+    }
+
+    // The percentage of bigrams typed with one finger on two different keys.
+    fn same_finger_rate(&self, layout: &[u8]) -> f64 {
+        let mut same  = 0f64;
+        let mut total = 0f64;
+        for &(c0, c1, freq) in self.ngrams.bigrams.iter() {
+            let (p0, p1) = (layout[c0 as usize] as usize, layout[c1 as usize] as usize);
+            if p0 == NONE as usize || p1 == NONE as usize {
+                continue;
+            }
+            total += freq;
+            let finger = self.geometry.finger(p0);
+            if finger != 0 && finger == self.geometry.finger(p1) &&
+               self.geometry.key(p0) != self.geometry.key(p1) {
+                same += freq;
+            }
+        }
+        if total > 0.0 { 100.0 * same / total } else { 0.0 }
+    }
+
+    // The number of keystrokes of each finger, followed by the thumb keys, not counting the space.
+    fn finger_usage(&self, layout: &[u8]) -> [f64; 10] {
+        let mut fu = [0f64; 10];
+        for (c, &freq) in self.ngrams.unigrams.iter().enumerate() {
+            if freq == 0.0 || c == 0 || layout[c] == NONE {
+                continue;
             }
+            let finger_index = self.geometry.finger(layout[c] as usize);
+            assert!(finger_index > 0);
+            fu[(finger_index - 1) as usize] += freq;
         }
+        fu
+    }
+
+    // The difference between the keystrokes of the two hands, as a percentage of all of them.
+    fn hand_imbalance(&self, layout: &[u8]) -> f64 {
+        let fu = self.finger_usage(layout);
+        let  left_hand = fu[0] + fu[1] + fu[2] + fu[3];
+        let right_hand = fu[4] + fu[5] + fu[6] + fu[7];
+        let total = fu.iter().sum::<f64>();
+        if total > 0.0 { 100.0 * (left_hand - right_hand).abs() / total } else { 0.0 }
+    }
+
+    // Calculate how much each finger is used as a percentage for each hand.
+    fn print_layout_finger_usage(&self, layout: &[u8]) {
+        let fu = self.finger_usage(layout); // finger usage, followed by the thumb keys
         let  left_hand = fu[0] + fu[1] + fu[2] + fu[3];
         let right_hand = fu[4] + fu[5] + fu[6] + fu[7];
-        let total      = (left_hand + right_hand) / 100f32;
+        let    thumbs  = fu[8] + fu[9];
+        let total      = (left_hand + right_hand + thumbs) / 100f64;
         println!(" Left hand: {:4.1}% + {:4.1}% + {:4.1}% + {:4.1}% = {:4.1}%",
                  fu[0] / total, fu[1] / total, fu[2] / total, fu[3] / total,  left_hand / total);
         println!("Right hand: {:4.1}% + {:4.1}% + {:4.1}% + {:4.1}% = {:4.1}%  (listed backwards)",
                  fu[7] / total, fu[6] / total, fu[5] / total, fu[4] / total, right_hand / total);
+        if self.geometry.has_thumb_keys() {
+            println!("    Thumbs: {:4.1}% + {:4.1}% = {:4.1}%  (left and right thumb keys)",
+                     fu[8] / total, fu[9] / total, thumbs / total);
+        }
+        let space = self.ngrams.unigrams[0];
+        if space > 0.0 {
+            println!("    Thumbs: {:4.1}% of all keystrokes are on the space bar",
+                     100.0 * space / (space + left_hand + right_hand + thumbs));
+        }
     }
 }
 
 #[test]
 fn objective_function_word_score_test()
 {
-    let objective = LayoutObjectiveFunction::new();
-    let layout = layout_from_string(_QWERTY_STRING);
+    let config = Config::default();
+    let geometry = Geometry::ansi();
+    let charset = Charset::ascii();
+    let layout = layout_from_string(_QWERTY_STRING, &geometry, &charset);
+    let objective = LayoutObjectiveFunction::new(&config, geometry, charset, "texts");
     let word = "asdf".chars().map(|x| (x as u8) - 32).collect::<Vec<u8>>();
-    let min_single_metric = SINGLE_METRIC.iter().fold(std::f32::INFINITY, |m,&x| m.min(x));
+    let min_single_metric = SINGLE_METRIC.iter().fold(f32::INFINITY, |m,&x| m.min(x));
     let single_score = ( SINGLE_METRIC[layout[word[0] as usize] as usize] - min_single_metric
                        + SINGLE_METRIC[layout[word[1] as usize] as usize] - min_single_metric
                        + SINGLE_METRIC[layout[word[2] as usize] as usize] - min_single_metric
                        + SINGLE_METRIC[layout[word[3] as usize] as usize] - min_single_metric)
-                       * SINGLE_METRIC_COEFFICIENT;
-    let min_double_metric = DOUBLE_METRIC.iter().fold(std::f32::INFINITY, |m,&x| m.min(x.2));
+                       * config.single_metric_coefficient;
+    let min_double_metric = DOUBLE_METRIC.iter().fold(f32::INFINITY, |m,&x| m.min(x.2));
     let calculate_double_score = |layout: &[u8], c0: u8, c1: u8| -> f32 {
         let k0 = layout[c0 as usize];
         let k1 = layout[c1 as usize];
@@ -1526,12 +2082,12 @@ fn objective_function_word_score_test()
         if let Some(&(_,_,s)) = DOUBLE_METRIC.iter().find(|&&(x0, x1, _)| x0 == k1 && x1 == k0) {
             return s - min_double_metric;
         }
-        return -min_double_metric;
+        -min_double_metric
     };
     let double_score = ( calculate_double_score(&layout, word[0], word[1])
                        + calculate_double_score(&layout, word[1], word[2])
                        + calculate_double_score(&layout, word[2], word[3]))
-                       * DOUBLE_METRIC_COEFFICIENT;
+                       * config.double_metric_coefficient;
     let calculate_triple_score = |layout: &[u8], c0: u8, c1: u8, c2: u8| -> f32 {
         let k0 = layout[c0 as usize];
         let k1 = layout[c1 as usize];
@@ -1542,113 +2098,903 @@ fn objective_function_word_score_test()
         }
         if let Some(&(_,_,_,s)) = TRIPLE_METRIC.iter().find(|&&(x0, x1, x2, _)|
             x0 == k2 && x1 == k1 && x2 == k0) {
-            return s + REVERSED_TRIPLE_PENALTY;
+            return s + config.reversed_triple_penalty;
         }
-        return 0.0;
+        0.0
     };
     let triple_score = ( calculate_triple_score(&layout, word[0], word[1], word[2])
                        + calculate_triple_score(&layout, word[1], word[2], word[3]))
-                       * TRIPLE_METRIC_COEFFICIENT;
+                       * config.triple_metric_coefficient;
     let ws0 = single_score + double_score + triple_score;
     let ws1 = objective.word_score(&layout, &word);
     assert_eq!(ws0, ws1);
 }
 
+// Turn a string of space-separated words into a word list with the given frequency for each.
+#[cfg(test)]
+fn words_from_string(text: &str, freq: f32) -> (Vec<u8>, Vec<f32>)
+{
+    let words = text.bytes().map(|x| x - 32).collect::<Vec<u8>>();
+    let freqs = vec![freq; text.split(' ').count()];
+    (words, freqs)
+}
 
+// An objective function for the ASCII characters that only types the words of a string.
+#[cfg(test)]
+fn objective_from_string(config: &Config, geometry: Geometry, text: &str) -> LayoutObjectiveFunction
+{
+    let (words, freqs) = words_from_string(text, 1.0);
+    LayoutObjectiveFunction::from_ngrams(config, geometry, Charset::ascii(),
+                                         NgramTables::from_words(&words, &freqs))
+}
 
-
-fn main()
+#[test]
+fn objective_function_ngram_score_test()
 {
-    // Handle the optional command line argument to specify the layout output filename
-    let mut args = std::env::args();
-    assert!(args.next().is_some());
-    let maybe_arg = args.next();
-    let output_prefix = match maybe_arg {
-        Some(os_string) => os_string,
-        None            => "layout".to_string()
+    // Score the word list the way it was scored before the n-gram tables, one word at a time, which
+    // gives exactly the same score.
+    let config = Config::default();
+    let assert_same_score = |objective: &LayoutObjectiveFunction, words: &[u8], freqs: &[f32]| {
+        for layout_string in [_QWERTY_STRING, _DVORAK_STRING, WHITE_STRING].iter() {
+            let layout = layout_from_string(layout_string, &objective.geometry, &objective.charset);
+            let mut expected = (0..objective.charset.len()).map(|c| {
+                exact_term(objective.position_score(&layout, c), 1.0)
+            }).sum::<i128>();
+            for (word, &freq) in words.split(|x| { *x == 0 }).zip(freqs.iter()) {
+                expected += objective.exact_word_score(&layout, word, freq as f64);
+            }
+            assert_eq!(objective.exact_score(&layout), expected);
+        }
     };
+    let text = "The quick brown fox jumps over the lazy dog's tail, and (again) the QUICK fox; \
+                don't #include <stdio.h> or x[i] = y{j} + 42 * z/7 - \"q\" ~ `tick` @ $5 & 9% ^ !?";
+    let words = text.bytes().map(|x| x - 32).collect::<Vec<u8>>();
+    let freqs = (0..text.split(' ').count()).map(|i| 1.5 + (i as f32) * 0.37).collect::<Vec<f32>>();
+    let objective = LayoutObjectiveFunction::from_ngrams(&config, Geometry::ansi(), Charset::ascii(),
+                                                         NgramTables::from_words(&words, &freqs));
+    assert_same_score(&objective, &words, &freqs);
+
+    // The same for a texts directory, with a text and a word frequency list
+    let dir = std::env::temp_dir().join("white_keyboard_layout_texts_test");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy("LICENSE", dir.join("license.txt")).unwrap();
+    std::fs::write(dir.join("corpus_1.wfl.txt"), "the\t120.5\nof\t61.25\nkeyboard\t3\n").unwrap();
+    let dir = dir.to_str().unwrap();
+    let config = Config{ corpus_min_word_frequency: 0.0, ..config };
+    let (words, freqs, _) = load_word_list(dir, &config, &Charset::ascii());
+    assert!(freqs.len() > 50);
+    let objective = LayoutObjectiveFunction::from_ngrams(&config, Geometry::ansi(), Charset::ascii(),
+                                                         load_texts_directory(dir, &config,
+                                                                              &Charset::ascii()));
+    assert_same_score(&objective, &words, &freqs);
+}
+
+#[test]
+fn objective_function_breakdown_test()
+{
+    // The terms of the breakdown add up to the score
+    let config = Config::default();
+    let objective = objective_from_string(&config, Geometry::ansi(),
+                                          "the quick brown fox jumps over THE lazy dog's (tail)");
+    for layout_string in [_QWERTY_STRING, _DVORAK_STRING, WHITE_STRING].iter() {
+        let layout = layout_from_string(layout_string, &objective.geometry, &objective.charset);
+        let score = objective.score(&layout).to_f64();
+        let total = objective.breakdown(&layout).total();
+        assert!((score - total).abs() < 1e-3, "Breakdown total {} != score {}", total, score);
+    }
+}
 
-    let objective  = LayoutObjectiveFunction::new();
-    if PRINT_OBJECTIVE_FUNCTION {
-        print_single_metric();
-        for i in 1u8..48 {
-            print_double_metric(i);
+#[test]
+fn objective_function_thumb_keys_test()
+{
+    // Thumb keys belong to a hand, unlike the space bar
+    let config = Config::default();
+    let objective = objective_from_string(&config, Geometry::split_3x6_3(), "the quick brown fox");
+    let alternation = |i: usize, j: usize| objective.alternation_terms[i*256+j];
+    assert_eq!(alternation(37, 14), 0.0);
+    assert_eq!(alternation(37, 23), 1.0);
+    assert_eq!(alternation(41, 23), 0.0);
+    assert_eq!(alternation(0, 23), 0.0);
+    assert_eq!(alternation(37 + 41, 14), 0.0);
+    assert!(objective.single_terms[14*256 + 38] < objective.single_terms[14*256 + 37]);
+}
+
+#[test]
+fn objective_function_score_delta_test()
+{
+    // Including the position terms of the shortcut letters and the similarity to QWERTY
+    let config = Config{
+        frozen_symbols:     "".to_string(),
+        shortcut_keys:      "zxcv".to_string(),
+        shortcut_penalty:   10.0,
+        similarity_penalty: 0.5,
+        ..Config::default()
+    };
+    let text = "the quick brown fox jumps over THE lazy dog's (tail) 1+1=2 {x} [y] <z> zoo zoom";
+    let (words, freqs) = words_from_string(text, 7.0);
+    let altgr = Geometry{ name: "ansi with AltGr".to_string(), layers: 4, ..Geometry::ansi() };
+    for geometry in [Geometry::ansi(), Geometry::iso(), Geometry::ortholinear(12), altgr] {
+        let name = geometry.name.clone();
+        let charset = Charset::ascii();
+        let mut counts = NgramCounts::new(charset.len());
+        counts.add_words(&words, &freqs);
+        counts.add_running_text(&charset.encode(text).unwrap());
+        let mut layout = layout_from_string(&geometry.layout, &geometry, &charset);
+        let constraints = Constraints::new(&config, &geometry, &charset);
+        let mut swapper = LayoutSwapper::new(&layout, &config, &geometry, &charset, &constraints);
+        let objective = LayoutObjectiveFunction::from_ngrams(&config, geometry, charset,
+                                                             counts.into_tables());
+        let mut rng = SeededRng::new(3);
+        for _ in 0..500 {
+            let mut new_layout = layout.clone();
+            swapper.swap(&mut new_layout, &mut rng);
+            assert_valid_layout(&new_layout, &objective.geometry, &objective.charset);
+            let expected = objective.score(&new_layout).to_f64() - objective.score(&layout).to_f64();
+            let delta = objective.score_delta(&layout, &new_layout).to_f64();
+            assert!((expected - delta).abs() < 1e-2, "Score delta {} != {} on {}", delta, expected, name);
+            layout = new_layout;
         }
-        objective.print_char_counts();
-        print!("\n");
     }
+}
+
+#[test]
+fn shortcut_keys_test()
+{
+    // The White layout moves z, x, c and v off their QWERTY keys
+    let config = Config{ shortcut_keys: "zxcv".to_string(), shortcut_penalty: 10.0, ..Config::default() };
+    let geometry = Geometry::ansi();
+    let charset = Charset::ascii();
+    let qwerty = layout_from_string(_QWERTY_STRING, &geometry, &charset);
+    let white  = layout_from_string(WHITE_STRING, &geometry, &charset);
+    let objective = objective_from_string(&config, geometry.clone(), "zoo vex");
+    assert_eq!(objective.breakdown(&qwerty).shortcut.raw, 0.0);
+    assert_eq!(objective.breakdown(&white).shortcut.raw, 4.0);
+
+    // Fixed shortcut keys never move
+    let config = Config{ fix_shortcut_keys: true, ..config };
+    let constraints = Constraints::new(&config, &geometry, &charset);
+    assert!(constraints.check(&white).is_err());
+
+    // The White layout is made to satisfy them by moving the pinned letters back to their keys
+    let mut layout = white.clone();
+    constraints.place_pins(&mut layout);
+    assert!(constraints.check(&layout).is_ok());
+    assert_eq!(objective.breakdown(&layout).shortcut.raw, 0.0);
+    let mut swapper = LayoutSwapper::new(&layout, &config, &geometry, &charset, &constraints);
+    let mut rng = SeededRng::new(5);
+    for _ in 0..1000 {
+        swapper.swap(&mut layout, &mut rng);
+    }
+    for c in "zxcvZXCV".chars() {
+        let c = charset.index(c).unwrap() as usize;
+        assert_eq!(layout[c], qwerty[c]);
+    }
+}
 
-    let mut layout = read_layout_file("optimal_layout.txt");
-    let mut score  = objective.score(&layout);
+#[test]
+fn similarity_term_test()
+{
+    // Moving a character costs more the harder its new place is to relearn
+    let config = Config{ similarity_penalty: 0.5, ..Config::default() };
+    let objective = objective_from_string(&config, Geometry::ansi(), "tea tea");
+    let qwerty = layout_from_string(_QWERTY_STRING, &objective.geometry, &objective.charset);
+    let a = objective.charset.index('a').unwrap() as usize;
+    let cost = |c: char| {
+        let p = qwerty[objective.charset.index(c).unwrap() as usize] as usize;
+        objective.similarity_terms[a*256 + p] / objective.ngrams.unigrams[a] as f32
+    };
+    assert_eq!([cost('a'), cost('A'), cost('q'), cost('s'), cost(';')], [0.0, 1.0, 1.0, 2.0, 3.0]);
+
+    // Swapping the A and semicolon keys moves a to the other hand, and nothing else that is typed
+    let swapped = _QWERTY_STRING.replace('a', "§").replace(';', "a").replace('§', ";")
+                                .replace('A', "§").replace(':', "A").replace('§', ":");
+    let swapped = layout_from_string(&swapped, &objective.geometry, &objective.charset);
+    assert_eq!(objective.breakdown(&qwerty).similarity.raw, 0.0);
+    assert_eq!(objective.breakdown(&swapped).similarity.raw, 3.0 * objective.ngrams.unigrams[a]);
+}
 
-    // Display the starting layout
-    print_layout(&layout);
-    print!("\n     Score: {}\n", score.to_f64());
-    objective.print_layout_finger_usage(&layout);
-    print!("\n");
-
-    // Perform several simulated annealing cycles
-    let mut cycle_iteration   = 0u64;
-    let mut cycle_temperature = CYCLE_TEMPERATURE_START;
-
-    while cycle_temperature > CYCLE_TEMPERATURE_FINAL {
-        let mut new_layout:     [u8; 190];
-        let mut best_layout     = layout;
-        let mut best_score      = score;
-        let     prev_best_score = score;
-        let mut random_key_swap = LayoutSwapper::new(&layout);
-        let mut temperature     = cycle_temperature;
-        let mut iteration       = 0u64;
-
-        println!("Iteration {}", cycle_iteration);
-        while temperature > TEMPERATURE_FINAL {
-
-            // Make new layout
-            new_layout = layout;
-            random_key_swap.swap(&mut new_layout);
-
-            // Test new layout
-            let new_score = objective.score(&new_layout);
-
-            // Possibly switch current layout to new one based on probability
-            if probability(score, new_score, temperature) > rand::random::<f64>() {
-                layout = new_layout;
-                score  = new_score;
+// COMMANDS
+
+// Read a layout file that the user asked for by name, which unlike the starting layout of the
+// optimizer has no sensible default.
+fn read_required_layout_file(filename: &str, geometry: &Geometry, charset: &Charset) -> Vec<u8>
+{
+    if std::fs::metadata(filename).is_err() {
+        eprintln!("Layout file not found: {}", filename);
+        std::process::exit(1);
+    }
+    read_layout_file(filename, geometry, charset)
+}
+
+// Print a layout followed by its score and finger usage.
+fn print_layout_summary(objective: &LayoutObjectiveFunction, layout: &[u8])
+{
+    print_layout(layout, &objective.geometry, &objective.charset);
+    print!("\n     Score: {}\n", objective.score(layout).to_f64());
+    objective.print_layout_finger_usage(layout);
+    println!();
+}
+
+// Print the key metric diagrams and the character counts of the corpus.
+fn analyze(objective: &LayoutObjectiveFunction)
+{
+    let geometry = &objective.geometry;
+    print_single_metric(geometry);
+    for i in 1..=geometry.num_keys() {
+        print_double_metric(i as u8, geometry);
+    }
+    objective.print_char_counts();
+    println!();
+}
+
+// Evaluate layout files without changing them, followed by a table of all of them if there are
+// several to compare.
+fn score(objective: &LayoutObjectiveFunction, filenames: &[String])
+{
+    let mut results = Vec::new();
+    for filename in filenames.iter() {
+        let layout = read_required_layout_file(filename, &objective.geometry, &objective.charset);
+        let breakdown = objective.breakdown(&layout);
+        println!("{}", filename);
+        print_layout_summary(objective, &layout);
+        breakdown.print();
+        println!();
+        results.push((filename, objective.score(&layout), breakdown));
+    }
+    if results.len() > 1 {
+        print!("{:>14}", "Score");
+        for name in ["Single", "Double", "Triple", "Shift", "AltGr", "AltGr+shift", "Alternation",
+                     "Reversed", "Skipgram", "Shortcut", "Similarity"].iter() {
+            print!(" {:>12}", name);
+        }
+        println!("  Layout");
+        for &(filename, score, ref b) in results.iter() {
+            print!("{:14.2}", score.to_f64());
+            for &(_, term) in b.terms().iter() {
+                print!(" {:12.2}", term.contribution());
             }
+            println!("  {}", filename);
+        }
+    }
+}
+
+fn compare(objective: &LayoutObjectiveFunction, filename_a: &str, filename_b: &str)
+{
+    let layout_a = read_required_layout_file(filename_a, &objective.geometry, &objective.charset);
+    let layout_b = read_required_layout_file(filename_b, &objective.geometry, &objective.charset);
+    println!("A: {}", filename_a);
+    print_layout_summary(objective, &layout_a);
+    println!("B: {}", filename_b);
+    print_layout_summary(objective, &layout_b);
+
+    let score_a = objective.score(&layout_a).to_f64();
+    let score_b = objective.score(&layout_b).to_f64();
+    println!("Score difference (B - A): {}", score_b - score_a);
+    let charset = &objective.charset;
+    let moved = (1..charset.len() as u8).filter(|&c| layout_a[c as usize] != layout_b[c as usize])
+                                        .map(|c| charset.char(c))
+                                        .collect::<String>();
+    println!("Symbols on different keys: {} {}", moved.chars().count(), moved);
+}
+
+fn export(filename: &str, options: &Options, geometry: &Geometry, charset: &Charset)
+{
+    let layout = read_required_layout_file(filename, geometry, charset);
+    let exported = match &options.format[..] {
+        "klc" => klc::klc_file(&layout, geometry, charset, &options.layout_name, &options.locale,
+                               options.qwerty_shortcuts).map(|text| vec![("", klc::utf16_bytes(&text))]),
+        "keylayout" => keylayout::keylayout_file(&layout, geometry, charset, &options.layout_name,
+                                                 options.qwerty_shortcuts)
+                                 .map(|text| vec![("", text.into_bytes())]),
+        "loadkeys" => loadkeys::loadkeys_file(&layout, geometry, charset, &options.layout_name,
+                                              options.qwerty_shortcuts, options.caps_backspace)
+                               .map(|text| vec![("", text.into_bytes())]),
+        "qmk" => firmware::qmk_file(&layout, geometry, charset, &options.layout_name,
+                                    options.caps_backspace).map(|text| vec![("", text.into_bytes())]),
+        "zmk" => firmware::zmk_file(&layout, geometry, charset, &options.layout_name,
+                                    options.caps_backspace).map(|text| vec![("", text.into_bytes())]),
+        "xkb" => xkb::xkb_files(&layout, geometry, charset, &options.layout_name, &options.locale)
+                     .map(|files| {
+                         let mut outputs = vec![("", files.symbols.into_bytes()),
+                                                (".evdev.xml", files.evdev.into_bytes())];
+                         outputs.extend(files.compose.map(|compose| (".XCompose", compose.into_bytes())));
+                         outputs
+                     }),
+        _ if options.output_file.is_some() => {
+            Ok(vec![("", layout_to_string(&layout, charset).into_bytes())])
+        }
+        _     => Ok(vec![("", format!("{}\n", layout_to_string(&layout, charset)).into_bytes())]),
+    };
+    let outputs = match exported {
+        Ok(outputs) => outputs,
+        Err(e)      => {
+            eprintln!("Export error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Formats with several files write the others next to the output file, with a suffix
+    for (suffix, bytes) in outputs {
+        let io_result = match options.output_file {
+            Some(ref output_file) => File::create(format!("{}{}", output_file, suffix))
+                                         .and_then(|mut file| file.write_all(&bytes)),
+            None                  => std::io::stdout().write_all(&bytes),
+        };
+        assert!(io_result.is_ok());
+    }
+}
+
+// Record the configuration that produced a layout file next to it, including the seed, so that the
+// run can be repeated by passing it back in with --config.
+fn write_config_file(config: &Config, filename: &str)
+{
+    let mut file = std::fs::File::create(filename).unwrap();
+    let io_result = file.write_all(config.to_config_string().as_bytes());
+    assert!(io_result.is_ok());
+}
+
+// The complete state of an annealing run, which is everything needed to continue it exactly. The
+// run is a series of cycles, each of which starts a new swapper at a lower starting temperature
+// from the best layout of the previous cycle. Within a cycle, scores are kept up to date by adding
+// the score change of each accepted swap, and they are recalculated in full between cycles so that
+// rounding errors can't build up.
+struct AnnealingState
+{
+    layout:            Vec<u8>,
+    score:             LayoutScore,
+    best_layout:       Vec<u8>,
+    best_score:        LayoutScore,
+    prev_best_score:   LayoutScore,
+    swapper:           LayoutSwapper,
+    rng:               SeededRng,
+    temperature:       f64,
+    iteration:         u64,
+    cycle_temperature: f64,
+    cycle_iteration:   u64,
+    total_iterations:  u64,
+    stuck_iterations:  u64,
+}
+
+impl AnnealingState
+{
+    fn new(layout: Vec<u8>, score: LayoutScore, config: &Config, geometry: &Geometry,
+           charset: &Charset, constraints: &Constraints, rng: SeededRng) -> AnnealingState {
+        AnnealingState{
+            swapper:           LayoutSwapper::new(&layout, config, geometry, charset, constraints),
+            best_layout:       layout.clone(),
+            layout,
+            score,
+            best_score:        score,
+            prev_best_score:   score,
+            rng,
+            temperature:       config.cycle_temperature_start,
+            iteration:         0,
+            cycle_temperature: config.cycle_temperature_start,
+            cycle_iteration:   0,
+            total_iterations:  0,
+            stuck_iterations:  0,
+        }
+    }
+
+    // Try one random swap and possibly accept it.
+    fn step(&mut self, objective: &LayoutObjectiveFunction) {
+        // Make new layout
+        let mut new_layout = self.layout.clone();
+        if !self.swapper.swap(&mut new_layout, &mut self.rng) {
+            self.stuck_iterations += 1;
+        }
+
+        // Test new layout
+        let new_score = self.score + objective.score_delta(&self.layout, &new_layout);
+
+        // Possibly switch current layout to new one based on probability
+        if probability(self.score, new_score, self.temperature) > self.rng.next_f64() {
+            self.layout = new_layout;
+            self.score  = new_score;
+        }
+
+        // Save layout if it's the best one yet
+        if self.score < self.best_score {
+            self.best_layout.copy_from_slice(&self.layout);
+            self.best_score  = self.score;
+        }
+    }
 
-            // Save layout if it's the best one yet
-            if score < best_score {
-                best_layout = layout;
-                best_score  = score;
+    // Move on to the next iteration and cool down.
+    fn advance(&mut self, config: &Config) {
+        self.iteration        += 1;
+        self.total_iterations += 1;
+        self.temperature      *= config.temperature_factor;
+    }
+
+    // Report the iterations since the run started that found no swap satisfying the constraints.
+    fn print_stuck_iterations(&self, name: &str) {
+        if self.stuck_iterations > 0 {
+            println!("{}: {} iterations found no swap that satisfies the constraints", name,
+                     self.stuck_iterations);
+        }
+    }
+
+    // Prepare for the next cycle, starting from the best layout so far.
+    fn next_cycle(&mut self, config: &Config, objective: &LayoutObjectiveFunction) {
+        self.layout             = self.best_layout.clone();
+        self.score              = objective.score(&self.layout);
+        self.best_score         = self.score;
+        self.cycle_iteration   += 1;
+        self.cycle_temperature *= config.cycle_temperature_factor;
+        self.prev_best_score    = self.score;
+        self.swapper            = LayoutSwapper::new(&self.layout, config, &objective.geometry,
+                                                     &objective.charset, &self.swapper.constraints);
+        self.temperature        = self.cycle_temperature;
+        self.iteration          = 0;
+    }
+}
+
+// The best layout found so far by any chain of a parallel run.
+struct SharedBest
+{
+    layout: Option<Vec<u8>>,
+    score:  LayoutScore,
+}
+
+// Run one annealing chain until it finishes or runs out of iterations, and return its final state.
+// A run with a single chain prints its full progress; the chains of a parallel run only print a
+// line per cycle, and the layout itself whenever it is the best of all chains so far.
+fn run_chain(objective: &LayoutObjectiveFunction, config: &Config, options: &Options, chain: usize,
+             mut state: AnnealingState, shared_best: &Mutex<SharedBest>,
+             checkpoints: &Mutex<Vec<String>>) -> AnnealingState
+{
+    let verbose  = config.num_chains == 1;
+    let resuming = state.total_iterations > 0;
+
+    // Each chain's results are written with a configuration that reproduces that chain alone
+    let chain_config = Config{
+        seed:       config.seed.map(|seed| seed.wrapping_add(chain as u64)),
+        num_chains: 1,
+        ..config.clone()
+    };
+    let chain_prefix = match verbose {
+        true  => options.prefix.clone(),
+        false => format!("{}_c{}", options.prefix, chain),
+    };
+
+    let iteration_budget = options.iterations.unwrap_or(u64::MAX);
+    let save_checkpoint = |state: &AnnealingState| {
+        if let Some(ref filename) = options.checkpoint_file {
+            let mut states = checkpoints.lock().unwrap();
+            states[chain] = checkpoint::format_state(state, &objective.charset);
+            checkpoint::write_checkpoint(filename, config, &states);
+        }
+    };
+
+    while state.cycle_temperature > config.cycle_temperature_final &&
+          state.total_iterations < iteration_budget {
+        if verbose {
+            if resuming && state.iteration > 0 {
+                println!("Iteration {} (resumed at {})", state.cycle_iteration, state.iteration);
+            } else {
+                println!("Iteration {}", state.cycle_iteration);
             }
+        }
+        while state.temperature > config.temperature_final &&
+              state.total_iterations < iteration_budget {
+            state.step(objective);
 
             // Display diagnostic information
-            if iteration % 100000 == 0 {
-                println!("{:9}    T: {:9.2}    C: {:12.2}    B: {:12.2}",
-                         iteration, temperature, score.to_f64(), best_score.to_f64());
+            if verbose && state.iteration.is_multiple_of(100000) {
+                println!("{:9}    T: {:9.2}    C: {:12.2}    B: {:12.2}", state.iteration,
+                         state.temperature, state.score.to_f64(), state.best_score.to_f64());
+            }
+
+            state.advance(config);
+            if state.total_iterations.is_multiple_of(options.checkpoint_interval) {
+                save_checkpoint(&state);
+            }
+        }
+
+        if verbose {
+            println!();
+        } else {
+            println!("Chain {:3}    cycle {:3}    B: {:12.2}", chain, state.cycle_iteration,
+                     state.best_score.to_f64());
+        }
+
+        // Stop in the middle of the cycle if the iteration budget ran out, so that a later run
+        // resumed from the checkpoint finishes it and outputs its best layout only once
+        if state.temperature > config.temperature_final {
+            break;
+        }
+
+        // Output new best layout if different than previous best
+        if state.best_score != state.prev_best_score {
+            let filename = format!("{}/{}_{}_{}", options.output_dir, chain_prefix,
+                                   state.best_score.to_f64() as i32, state.cycle_iteration);
+            write_layout_file(&state.best_layout, &format!("{}.txt", filename), &objective.charset);
+            write_config_file(&chain_config, &format!("{}.toml", filename));
+
+            // Keep the global best layout up to date, holding the lock while printing it so that
+            // the output of different chains doesn't interleave
+            let mut best = shared_best.lock().unwrap();
+            let improved = state.best_score < best.score || best.layout.is_none();
+            if improved {
+                best.layout = Some(state.best_layout.clone());
+                best.score  = state.best_score;
+                write_layout_file(&state.best_layout, &options.best_file, &objective.charset);
+                if !verbose {
+                    println!("\nNew best layout from chain {} in cycle {}:", chain,
+                             state.cycle_iteration);
+                }
             }
+            if verbose || improved {
+                print_layout(&state.best_layout, &objective.geometry, &objective.charset);
+                print!("\n     Score: {}\n", state.best_score.to_f64());
+                objective.print_layout_finger_usage(&state.best_layout);
+                println!();
+                objective.breakdown(&state.best_layout).print();
+                println!();
+            }
+        }
+
+        state.next_cycle(config, objective);
+        save_checkpoint(&state);
+    }
+    save_checkpoint(&state);
+    state.print_stuck_iterations(&format!("Chain {}", chain));
+    state
+}
+
+// Print the distribution of the final scores of the chains of a parallel run.
+fn print_chain_summary(config: &Config, scores: &[LayoutScore])
+{
+    let mut sorted = scores.iter().enumerate().map(|(i, s)| (i, s.to_f64())).collect::<Vec<_>>();
+    sorted.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+    let values = sorted.iter().map(|x| x.1).collect::<Vec<f64>>();
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let stddev = (values.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / n).sqrt();
+    let median = match values.len() % 2 {
+        0 => (values[values.len()/2 - 1] + values[values.len()/2]) / 2.0,
+        _ => values[values.len()/2],
+    };
+    let best = scores.iter().fold(scores[0], |a, &b| if b < a { b } else { a });
+    let num_best = scores.iter().filter(|&&s| s == best).count();
+
+    println!("# Final scores of {} chains", scores.len());
+    println!("{:>7}  {:>20}  {:>12}", "Chain", "Seed", "Score");
+    for &(i, value) in sorted.iter() {
+        let seed = config.seed.unwrap().wrapping_add(i as u64);
+        println!("{:7}  {:20}  {:12.2}", i, seed, value);
+    }
+    println!();
+    println!("   Best: {:12.2}", values[0]);
+    println!(" Median: {:12.2}", median);
+    println!("   Mean: {:12.2}", mean);
+    println!("  Worst: {:12.2}", values[values.len() - 1]);
+    println!(" StdDev: {:12.2}", stddev);
+    println!("{} of {} chains reached the best score", num_best, scores.len());
+}
 
-            iteration   += 1;
-            temperature *= TEMPERATURE_FACTOR;
+// Search with a genetic algorithm instead of annealing (see genetic.rs), for the given number of
+// generations or until the iteration budget runs out, and write out the best layout.
+fn evolve(objective: &LayoutObjectiveFunction, config: &Config, options: &Options)
+{
+    if let Err(e) = std::fs::create_dir_all(&options.output_dir) {
+        eprintln!("Unable to create output directory {}: {}", options.output_dir, e);
+        std::process::exit(1);
+    }
+    let seed   = config.seed.expect("The seed must be chosen before optimizing.");
+    let mut layout = read_layout_file(&options.layout_file, &objective.geometry, &objective.charset);
+    let constraints = load_constraints(config, &objective.geometry, &objective.charset);
+    constraints.place_pins(&mut layout);
+    if let Err(e) = constraints.check(&layout) {
+        eprintln!("The starting layout {} breaks a constraint: {}", options.layout_file, e);
+        std::process::exit(1);
+    }
+
+    // Display the starting layout
+    print_layout_summary(objective, &layout);
+
+    let mut rng = SeededRng::new(seed);
+    let mut population = Population::new(&layout, objective, config, &constraints, &mut rng);
+    let iteration_budget = options.iterations.unwrap_or(u64::MAX);
+    let mut generation = 0;
+    while generation < config.generations && population.evaluations() < iteration_budget {
+        population.next_generation(objective, config, &mut rng);
+        generation += 1;
+        if generation % 100 == 0 {
+            let scores = population.layouts.iter().map(|x| x.1.to_f64()).collect::<Vec<f64>>();
+            println!("{:9}    E: {:10}    M: {:12.2}    B: {:12.2}", generation, population.evaluations(),
+                     scores.iter().sum::<f64>() / (scores.len() as f64), scores[0]);
         }
+    }
+
+    // The scores were kept up to date by differences, so the best one is recalculated in full
+    let best = &population.best().0;
+    let score = objective.score(best);
+    let filename = format!("{}/{}_{}_genetic", options.output_dir, options.prefix, score.to_f64() as i32);
+    write_layout_file(best, &format!("{}.txt", filename), &objective.charset);
+    write_config_file(config, &format!("{}.toml", filename));
+    write_layout_file(best, &options.best_file, &objective.charset);
+    println!();
+    print_layout_summary(objective, best);
+    objective.breakdown(best).print();
+}
+
+fn optimize(objective: &LayoutObjectiveFunction, config: &Config, options: &Options,
+            resumed_states: Option<Vec<AnnealingState>>)
+{
+    if let Err(e) = std::fs::create_dir_all(&options.output_dir) {
+        eprintln!("Unable to create output directory {}: {}", options.output_dir, e);
+        std::process::exit(1);
+    }
 
-		// Output new best layout if different than previous best
-		print!("\n");
-        if best_score != prev_best_score {
-            print_layout(&best_layout);
-            print!("\n     Score: {}\n", best_score.to_f64());
-            objective.print_layout_finger_usage(&best_layout);
-	        print!("\n");
-			write_layout_file(&best_layout, &format!("layouts/{}_{}_{}.txt",
-									                output_prefix,
-                                                    best_score.to_f64() as i32,
-                                                    cycle_iteration)[..]);
-            write_layout_file(&best_layout, "optimal_layout.txt");
+    // Every chain starts from the same layout, and chain i is seeded with seed + i, so that a run
+    // with a single chain is the same as a run from before chains existed
+    let states = match resumed_states {
+        Some(states) => states,
+        None         => {
+            let seed   = config.seed.expect("The seed must be chosen before optimizing.");
+            let mut layout = read_layout_file(&options.layout_file, &objective.geometry,
+                                              &objective.charset);
+            let constraints = load_constraints(config, &objective.geometry, &objective.charset);
+            constraints.place_pins(&mut layout);
+            if let Err(e) = constraints.check(&layout) {
+                eprintln!("The starting layout {} breaks a constraint: {}", options.layout_file, e);
+                std::process::exit(1);
+            }
+            let score = objective.score(&layout);
+            (0..config.num_chains).map(|chain| {
+                let rng = SeededRng::new(seed.wrapping_add(chain as u64));
+                AnnealingState::new(layout.clone(), score, config, &objective.geometry,
+                                    &objective.charset, &constraints, rng)
+            }).collect::<Vec<AnnealingState>>()
         }
+    };
+
+    // Display the starting layout
+    print_layout(&states[0].layout, &objective.geometry, &objective.charset);
+    print!("\n     Score: {}\n", states[0].score.to_f64());
+    objective.print_layout_finger_usage(&states[0].layout);
+    println!();
+
+    // Perform several simulated annealing cycles in each chain
+    let start_score = states.iter().fold(states[0].best_score,
+                                         |a, s| if s.best_score < a { s.best_score } else { a });
+    let shared_best = Mutex::new(SharedBest{ layout: None, score: start_score });
+    let checkpoints = Mutex::new(states.iter().map(|s| checkpoint::format_state(s, &objective.charset))
+                                          .collect::<Vec<String>>());
+    let final_states = std::thread::scope(|scope| {
+        let handles = states.into_iter().enumerate().map(|(chain, state)| {
+            let shared_best = &shared_best;
+            let checkpoints = &checkpoints;
+            scope.spawn(move || {
+                run_chain(objective, config, options, chain, state, shared_best, checkpoints)
+            })
+        }).collect::<Vec<_>>();
+        handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<AnnealingState>>()
+    });
 
-        // Prepare for next cycle
-        score = best_score;
-        cycle_iteration   += 1;
-        cycle_temperature *= CYCLE_TEMPERATURE_FACTOR;
+    if config.num_chains > 1 {
+        println!();
+        print_chain_summary(config, &final_states.iter().map(|s| s.best_score).collect::<Vec<_>>());
+    }
+}
+
+// The iterations between the layouts that an annealing run of the pareto command offers to the
+// archive, besides its best layout at the end of each cycle.
+const PARETO_SAMPLE_INTERVAL: u64 = 1000;
+
+// The objectives of the Pareto front (see pareto.rs), evaluated with an objective function that has
+// no similarity penalty.
+fn pareto_objectives(objective: &LayoutObjectiveFunction, layout: &[u8]) -> [f64; NUM_OBJECTIVES]
+{
+    [objective.score(layout).to_f64(), objective.similarity(layout), objective.hand_imbalance(layout),
+     objective.same_finger_rate(layout)]
+}
+
+// Search for the Pareto front with several annealing runs in parallel, one for each similarity
+// penalty, and write its layouts out along with a summary table. Each run keeps an archive of its
+// own, and these are merged in order at the end, so that the front doesn't depend on the timing
+// of the threads.
+fn pareto(objective: &LayoutObjectiveFunction, config: &Config, options: &Options)
+{
+    if let Err(e) = std::fs::create_dir_all(&options.output_dir) {
+        eprintln!("Unable to create output directory {}: {}", options.output_dir, e);
+        std::process::exit(1);
+    }
+    let seed   = config.seed.expect("The seed must be chosen before optimizing.");
+    let mut layout = read_layout_file(&options.layout_file, &objective.geometry, &objective.charset);
+    let constraints = load_constraints(config, &objective.geometry, &objective.charset);
+    constraints.place_pins(&mut layout);
+    if let Err(e) = constraints.check(&layout) {
+        eprintln!("The starting layout {} breaks a constraint: {}", options.layout_file, e);
+        std::process::exit(1);
+    }
+
+    // Each run's layouts are written with a configuration that has its penalty and seed
+    let run_config = |run: usize| {
+        let fraction = if config.pareto_runs > 1 { run as f32 / (config.pareto_runs - 1) as f32 }
+                       else { 0.0 };
+        Config{
+            seed:               Some(seed.wrapping_add(run as u64)),
+            similarity_penalty: fraction * config.pareto_similarity_penalty,
+            ..config.clone()
+        }
+    };
+
+    let effort = objective.with_similarity_penalty(0.0);
+    let iteration_budget = options.iterations.unwrap_or(u64::MAX);
+    let run_archives = std::thread::scope(|scope| {
+        let handles = (0..config.pareto_runs).map(|run| {
+            let (effort, layout, constraints, run_config) = (&effort, &layout, &constraints, &run_config);
+            scope.spawn(move || {
+                let run_config = run_config(run);
+                let penalty = run_config.similarity_penalty;
+                let objective = effort.with_similarity_penalty(penalty);
+                let rng = SeededRng::new(run_config.seed.unwrap());
+                let mut state = AnnealingState::new(layout.clone(), objective.score(layout), config,
+                                                    &objective.geometry, &objective.charset,
+                                                    constraints, rng);
+                let mut archive = ParetoArchive::new(config.pareto_front_size);
+                let mut offer = |layout: &[u8]| {
+                    archive.insert(layout, pareto_objectives(effort, layout));
+                };
+                offer(layout);
+                while state.cycle_temperature > config.cycle_temperature_final &&
+                      state.total_iterations < iteration_budget {
+                    while state.temperature > config.temperature_final &&
+                          state.total_iterations < iteration_budget {
+                        state.step(&objective);
+                        if state.iteration.is_multiple_of(PARETO_SAMPLE_INTERVAL) {
+                            offer(&state.layout);
+                        }
+                        state.advance(config);
+                    }
+                    offer(&state.best_layout);
+                    println!("Run {:3}    similarity penalty {:8.4}    cycle {:3}    B: {:12.2}", run,
+                             penalty, state.cycle_iteration, state.best_score.to_f64());
+                    if state.temperature > config.temperature_final {
+                        break;
+                    }
+                    state.next_cycle(config, &objective);
+                }
+                state.print_stuck_iterations(&format!("Run {}", run));
+                archive
+            })
+        }).collect::<Vec<_>>();
+        handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<ParetoArchive>>()
+    });
+
+    // Write out the front in order of effort, and a table to choose from
+    let mut archive = ParetoArchive::new(config.pareto_front_size);
+    for run_archive in run_archives.iter() {
+        for &(ref layout, values) in run_archive.entries.iter() {
+            archive.insert(layout, values);
+        }
+    }
+    let mut table = format!("{:>4}", "#");
+    for name in OBJECTIVE_NAMES.iter() {
+        table.push_str(&format!(" {:>12}", name));
+    }
+    table.push_str("  Layout\n");
+    for (i, &&(ref layout, values)) in archive.sorted().iter().enumerate() {
+        let filename = format!("{}/{}_pareto_{}", options.output_dir, options.prefix, i);
+        let run = run_archives.iter().position(|a| a.entries.iter().any(|e| e.0 == *layout)).unwrap();
+        write_layout_file(layout, &format!("{}.txt", filename), &objective.charset);
+        write_config_file(&run_config(run), &format!("{}.toml", filename));
+        table.push_str(&format!("{:4} {:12.2} {:12.2} {:11.2}% {:11.2}%  {}.txt\n", i, values[0],
+                                values[1], values[2], values[3], filename));
+    }
+    print!("\nPareto front of {} layouts:\n{}", archive.entries.len(), table);
+    let summary_filename = format!("{}/{}_pareto.txt", options.output_dir, options.prefix);
+    let mut file = std::fs::File::create(&summary_filename).unwrap();
+    let io_result = file.write_all(table.as_bytes());
+    assert!(io_result.is_ok());
+}
+
+// Load the keyboard geometry named in the configuration.
+fn load_geometry(config: &Config) -> Geometry
+{
+    match Geometry::from_config(config) {
+        Ok(geometry) => geometry,
+        Err(e)       => {
+            eprintln!("Geometry error in {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Load the constraint file named in the configuration, if any.
+fn load_constraints(config: &Config, geometry: &Geometry, charset: &Charset) -> Constraints
+{
+    match Constraints::from_config(config, geometry, charset) {
+        Ok(constraints) => constraints,
+        Err(e)          => {
+            eprintln!("Constraint error in {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// The extra characters and dead keys are checked when the configuration is loaded.
+fn load_charset(config: &Config) -> Charset
+{
+    Charset::from_config(config).unwrap()
+}
+
+fn main()
+{
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let options = match cli::parse_args(&args) {
+        Ok(options) => options,
+        Err(e)      => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if options.command == Command::Help {
+        print!("{}", cli::USAGE);
+        return;
+    }
+    if let Command::Export(ref filename) = options.command {
+        match Config::load(&options.config_file) {
+            Ok(config) => export(filename, &options, &load_geometry(&config), &load_charset(&config)),
+            Err(e)     => {
+                eprintln!("Configuration error in {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // A resumed run carries its own configuration, so that it continues exactly as it started
+    let mut resumed_states = None;
+    let loaded_config = match options.resume_file {
+        Some(ref filename) => checkpoint::read_checkpoint(filename).map(|(config, states)| {
+            resumed_states = Some(states);
+            config
+        }),
+        None => Config::load(&options.config_file)
+    };
+
+    // Load the optimization parameters and echo them so that every run can be reproduced
+    let mut config = match loaded_config {
+        Ok(config) => config,
+        Err(e)     => {
+            eprintln!("Configuration error in {}", e);
+            std::process::exit(1);
+        }
+    };
+    if resumed_states.is_none() {
+        if options.seed.is_some() {
+            config.seed = options.seed;
+        }
+        if let Some(chains) = options.chains {
+            config.num_chains = chains;
+        }
+    }
+    let searching = options.command == Command::Optimize || options.command == Command::Pareto;
+    if searching && config.seed.is_none() {
+        config.seed = Some(SeededRng::random_seed());
+    }
+    config.print();
+
+    let objective = LayoutObjectiveFunction::new(&config, load_geometry(&config), load_charset(&config),
+                                                 &options.texts_dir);
+    match options.command {
+        Command::Optimize => {
+            if config.print_objective_function {
+                analyze(&objective);
+            }
+            match options.search {
+                Search::Annealing => optimize(&objective, &config, &options, resumed_states),
+                Search::Genetic   => evolve(&objective, &config, &options),
+            }
+        },
+        Command::Pareto => pareto(&objective, &config, &options),
+        Command::Score(ref filenames) => score(&objective, filenames),
+        Command::Compare(ref a, ref b) => compare(&objective, a, b),
+        Command::Analyze => analyze(&objective),
+        Command::Export(_) | Command::Help => unreachable!(),
     }
 }
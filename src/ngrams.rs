@@ -0,0 +1,199 @@
+// N-GRAM FREQUENCY TABLES
+
+// The corpus is compiled once into tables of how often each character, each pair of consecutive
+// characters and each run of three consecutive characters occurs, over the characters of the
+// character set. Scoring a layout then only has to visit each distinct n-gram once instead
+// of walking every word of the corpus. The start of a word is counted as a bigram from the space
+// (character 0) to its first character, which is exactly how word_score starts each word from key
+// 0. Trigrams never include the space, since the triple metric is never applied across the start
+// of a word.
+
+// Running text can also be counted as it was typed, spaces and punctuation included. Its bigrams
+// then include the transitions into and out of the space bar, and the two characters on either
+// side of a space are counted as a skipgram, since they are typed in quick succession with only a
+// thumb press between them.
+
+// Frequencies are counted on a grid of 2^-20, so that adding them up is exact for any total below
+// 2^33: the tables then hold exactly the sums of the frequencies of the words, whatever their order.
+
+// Only the n-grams that occur are stored, in sorted order so that scores are summed in the same
+// order on every run. For incremental scoring, each character also has a list of the bigrams,
+// trigrams and skipgrams it takes part in.
+
+use std::collections::HashMap;
+
+#[cfg(test)]
+use charset::Charset;
+
+// The number of steps of the frequency grid in a frequency of 1.
+pub const FREQUENCY_UNIT: f64 = 1048576.0;
+
+// A frequency rounded to the grid the tables are counted on.
+pub fn grid_frequency(freq: f64) -> f64
+{
+    (freq * FREQUENCY_UNIT).round() / FREQUENCY_UNIT
+}
+
+#[derive(Clone)]
+pub struct NgramTables
+{
+    pub unigrams:       Vec<f64>,
+    pub bigrams:        Vec<(u8, u8, f64)>,
+    pub trigrams:       Vec<(u8, u8, u8, f64)>,
+    pub skipgrams:      Vec<(u8, u8, f64)>,
+    pub char_bigrams:   Vec<Vec<u32>>,
+    pub char_trigrams:  Vec<Vec<u32>>,
+    pub char_skipgrams: Vec<Vec<u32>>,
+}
+
+// Running totals of the n-grams seen so far, which are turned into tables once everything has
+// been counted.
+pub struct NgramCounts
+{
+    num_chars: usize,
+    unigrams:  Vec<f64>,
+    bigrams:   Vec<f64>,
+    trigrams:  HashMap<(u8, u8, u8), f64>,
+    skipgrams: Vec<f64>,
+}
+
+impl NgramCounts
+{
+    pub fn new(num_chars: usize) -> NgramCounts {
+        NgramCounts{
+            num_chars,
+            unigrams:  vec![0f64; num_chars],
+            bigrams:   vec![0f64; num_chars*num_chars],
+            trigrams:  HashMap::new(),
+            skipgrams: vec![0f64; num_chars*num_chars],
+        }
+    }
+
+    // Count a single word of character indices, typed from a standing start at the space bar.
+    fn add_word(&mut self, word: &[u8], freq: f64) {
+        let freq = grid_frequency(freq);
+        let mut c0 = 0u8;
+        let mut c1 = 0u8;
+        for (i, &c2) in word.iter().enumerate() {
+            self.unigrams[c2 as usize] += freq;
+            self.bigrams[(c1 as usize)*self.num_chars + (c2 as usize)] += freq;
+            if i >= 2 {
+                *self.trigrams.entry((c0, c1, c2)).or_insert(0.0) += freq;
+            }
+            c0 = c1;
+            c1 = c2;
+        }
+    }
+
+    // Count a word frequency list, given as words of character indices separated by zeros.
+    pub fn add_words(&mut self, words: &[u8], freqs: &[f32]) {
+        for (word, &freq) in words.split(|x| { *x == 0 }).zip(freqs.iter()) {
+            self.add_word(word, freq as f64);
+        }
+    }
+
+    // Count a whole text of character indices as it is typed, where all whitespace has already
+    // become spaces. A run of spaces is typed as a single one.
+    pub fn add_running_text(&mut self, text: &[u8]) {
+        let mut c0 = 0u8;
+        let mut c1 = 0u8;
+        for &c2 in text.iter() {
+            if c2 == 0 && c1 == 0 {
+                continue;
+            }
+            self.unigrams[c2 as usize] += 1.0;
+            self.bigrams[(c1 as usize)*self.num_chars + (c2 as usize)] += 1.0;
+            if c0 != 0 && c1 != 0 && c2 != 0 {
+                *self.trigrams.entry((c0, c1, c2)).or_insert(0.0) += 1.0;
+            }
+            if c0 != 0 && c1 == 0 {
+                self.skipgrams[(c0 as usize)*self.num_chars + (c2 as usize)] += 1.0;
+            }
+            c0 = c1;
+            c1 = c2;
+        }
+    }
+
+    pub fn into_tables(self) -> NgramTables {
+        let n = self.num_chars;
+        let nonzero = |counts: &[f64]| -> Vec<(u8, u8, f64)> {
+            (0..n*n).filter(|&i| counts[i] != 0.0).map(|i| {
+                ((i / n) as u8, (i % n) as u8, counts[i])
+            }).collect()
+        };
+        let bigrams   = nonzero(&self.bigrams);
+        let skipgrams = nonzero(&self.skipgrams);
+        let mut trigrams = self.trigrams.into_iter().map(|((c0, c1, c2), f)| (c0, c1, c2, f))
+                                        .collect::<Vec<(u8, u8, u8, f64)>>();
+        trigrams.sort_by_key(|t| (t.0, t.1, t.2));
+
+        let index_pairs = |pairs: &[(u8, u8, f64)]| -> Vec<Vec<u32>> {
+            let mut index = vec![Vec::new(); n];
+            for (i, &(c0, c1, _)) in pairs.iter().enumerate() {
+                index[c0 as usize].push(i as u32);
+                if c1 != c0 {
+                    index[c1 as usize].push(i as u32);
+                }
+            }
+            index
+        };
+        let char_bigrams   = index_pairs(&bigrams);
+        let char_skipgrams = index_pairs(&skipgrams);
+        let mut char_trigrams = vec![Vec::new(); n];
+        for (i, &(c0, c1, c2, _)) in trigrams.iter().enumerate() {
+            char_trigrams[c0 as usize].push(i as u32);
+            if c1 != c0 {
+                char_trigrams[c1 as usize].push(i as u32);
+            }
+            if c2 != c0 && c2 != c1 {
+                char_trigrams[c2 as usize].push(i as u32);
+            }
+        }
+
+        NgramTables{
+            unigrams:       self.unigrams,
+            bigrams,
+            trigrams,
+            skipgrams,
+            char_bigrams,
+            char_trigrams,
+            char_skipgrams,
+        }
+    }
+}
+
+impl NgramTables
+{
+    // Compile a word frequency list on its own.
+    #[cfg(test)]
+    pub fn from_words(words: &[u8], freqs: &[f32]) -> NgramTables {
+        let mut counts = NgramCounts::new(95);
+        counts.add_words(words, freqs);
+        counts.into_tables()
+    }
+}
+
+#[test]
+fn ngram_tables_test()
+{
+    // "abca ab", each word with frequency 2
+    let words = [65, 66, 67, 65, 0, 65, 66];
+    let tables = NgramTables::from_words(&words, &[2.0, 2.0]);
+    assert_eq!(tables.unigrams[65], 6.0);
+    assert_eq!(tables.unigrams[67], 2.0);
+    assert_eq!(tables.bigrams, vec![(0, 65, 4.0), (65, 66, 4.0), (66, 67, 2.0), (67, 65, 2.0)]);
+    assert_eq!(tables.trigrams, vec![(65, 66, 67, 2.0), (66, 67, 65, 2.0)]);
+    assert_eq!(tables.char_bigrams[65], vec![0, 1, 3]);
+    assert_eq!(tables.char_trigrams[65], vec![0, 1]);
+    assert!(tables.skipgrams.is_empty());
+
+    // The same words as running text, where the space between them is typed too
+    let mut counts = NgramCounts::new(95);
+    counts.add_running_text(&Charset::ascii().encode("abca  ab\n").unwrap());
+    let tables = counts.into_tables();
+    assert_eq!(tables.unigrams[0], 2.0);
+    assert_eq!(tables.bigrams, vec![(0, 65, 2.0), (65, 0, 1.0), (65, 66, 2.0),
+                                    (66, 0, 1.0), (66, 67, 1.0), (67, 65, 1.0)]);
+    assert_eq!(tables.trigrams, vec![(65, 66, 67, 1.0), (66, 67, 65, 1.0)]);
+    assert_eq!(tables.skipgrams, vec![(65, 65, 1.0)]);
+}
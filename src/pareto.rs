@@ -0,0 +1,107 @@
+// PARETO FRONT
+
+// Some goals compete with each other, and folding them into one score means choosing the trade-off
+// up front through the coefficients. The pareto command instead keeps an archive of layouts that
+// are not dominated on any of four objectives, all of which are better when lower:
+//
+//   effort        the score of the objective function, without the similarity penalty
+//   similarity    the raw similarity term, how far the characters have moved from the reference
+//   imbalance     the difference between the use of the two hands, as a percentage of keystrokes
+//   same finger   the percentage of bigrams typed with one finger on two different keys
+//
+// One layout dominates another if it is no worse on every objective and better on at least one.
+// The archive is fed by several annealing runs at different similarity penalties, which spread the
+// search out between the fastest layouts and the ones closest to the reference, and each run adds
+// a sample of the layouts it passes through as well as its best ones. When the archive is full,
+// the layout in the most crowded part of the front makes way, so that the front stays spread out.
+
+pub const NUM_OBJECTIVES: usize = 4;
+
+pub const OBJECTIVE_NAMES: [&str; NUM_OBJECTIVES] = ["Effort", "Similarity", "Imbalance",
+                                                             "Same finger"];
+
+pub struct ParetoArchive
+{
+    pub entries: Vec<(Vec<u8>, [f64; NUM_OBJECTIVES])>,
+    max_size:    usize,
+}
+
+pub fn dominates(a: &[f64; NUM_OBJECTIVES], b: &[f64; NUM_OBJECTIVES]) -> bool
+{
+    a.iter().zip(b.iter()).all(|(x, y)| x <= y) && a.iter().zip(b.iter()).any(|(x, y)| x < y)
+}
+
+impl ParetoArchive
+{
+    pub fn new(max_size: usize) -> ParetoArchive {
+        assert!(max_size >= 2);
+        ParetoArchive{ entries: Vec::new(), max_size }
+    }
+
+    // Add a layout unless it is dominated by or equal to one already in the archive, removing the
+    // layouts that it dominates. Returns whether the layout was added.
+    pub fn insert(&mut self, layout: &[u8], values: [f64; NUM_OBJECTIVES]) -> bool {
+        if self.entries.iter().any(|e| dominates(&e.1, &values) || e.1 == values) {
+            return false;
+        }
+        self.entries.retain(|e| !dominates(&values, &e.1));
+        self.entries.push((layout.to_vec(), values));
+        if self.entries.len() > self.max_size {
+            let distances = self.crowding_distances();
+            let most_crowded = (0..distances.len()).fold(0, |m, i| {
+                if distances[i] < distances[m] { i } else { m }
+            });
+            self.entries.remove(most_crowded);
+        }
+        true
+    }
+
+    // The crowding distance of each layout: the sum over the objectives of the distance between its
+    // neighbours on either side, relative to the range of the objective. The layouts at either end
+    // of an objective are never the most crowded.
+    fn crowding_distances(&self) -> Vec<f64> {
+        let n = self.entries.len();
+        let mut distances = vec![0f64; n];
+        for k in 0..NUM_OBJECTIVES {
+            let mut order = (0..n).collect::<Vec<usize>>();
+            order.sort_by(|&a, &b| self.entries[a].1[k].partial_cmp(&self.entries[b].1[k]).unwrap());
+            let range = self.entries[order[n-1]].1[k] - self.entries[order[0]].1[k];
+            if range <= 0.0 {
+                continue;
+            }
+            distances[order[0]]   = f64::INFINITY;
+            distances[order[n-1]] = f64::INFINITY;
+            for i in 1..n-1 {
+                let gap = self.entries[order[i+1]].1[k] - self.entries[order[i-1]].1[k];
+                distances[order[i]] += gap / range;
+            }
+        }
+        distances
+    }
+
+    // The layouts of the front in order of effort.
+    pub fn sorted(&self) -> Vec<&(Vec<u8>, [f64; NUM_OBJECTIVES])> {
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        entries
+    }
+}
+
+#[test]
+fn pareto_archive_test()
+{
+    let mut archive = ParetoArchive::new(3);
+    assert!(archive.insert(&[1], [5.0, 5.0, 1.0, 1.0]));
+    assert!(!archive.insert(&[2], [6.0, 5.0, 1.0, 1.0]));
+    assert!(!archive.insert(&[3], [5.0, 5.0, 1.0, 1.0]));
+    assert!(archive.insert(&[4], [4.0, 6.0, 1.0, 1.0]));
+    assert!(archive.insert(&[5], [4.0, 5.0, 1.0, 1.0]));
+    assert_eq!(archive.entries.len(), 1);
+
+    // Of the four trade-offs between the first two objectives, the one closest to another goes
+    assert!(archive.insert(&[6], [1.0, 9.0, 1.0, 1.0]));
+    assert!(archive.insert(&[7], [9.0, 1.0, 1.0, 1.0]));
+    assert!(archive.insert(&[8], [3.9, 5.1, 1.0, 1.0]));
+    let layouts = archive.sorted().iter().map(|e| e.0[0]).collect::<Vec<u8>>();
+    assert_eq!(layouts, vec![6, 5, 7]);
+}
@@ -0,0 +1,64 @@
+// SEEDED RANDOM NUMBER GENERATION
+
+// Every random decision made by the optimizer is drawn from a single generator that is created from
+// a seed, so that two runs with the same seed and the same inputs make exactly the same decisions.
+// The generator is SplitMix64, whose entire state is one 64-bit integer. That makes it trivial to
+// record and restore, and it is more than random enough for choosing layout swaps.
+
+use rand::Rng;
+
+#[derive(Copy, Clone)]
+pub struct SeededRng
+{
+    state: u64,
+}
+
+impl SeededRng
+{
+    pub fn new(seed: u64) -> SeededRng {
+        SeededRng{ state: seed }
+    }
+
+    // Continue a sequence from a state previously returned by state().
+    pub fn from_state(state: u64) -> SeededRng {
+        SeededRng{ state }
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    // Pick a seed for a run that wasn't given one.
+    pub fn random_seed() -> u64 {
+        ::rand::random::<u64>()
+    }
+}
+
+impl Rng for SeededRng
+{
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[test]
+fn seeded_rng_reproducible_test()
+{
+    let mut a = SeededRng::new(42);
+    let mut b = SeededRng::new(42);
+    let mut c = SeededRng::new(43);
+    let sa = (0..100).map(|_| a.next_u64()).collect::<Vec<u64>>();
+    let sb = (0..100).map(|_| b.next_u64()).collect::<Vec<u64>>();
+    let sc = (0..100).map(|_| c.next_u64()).collect::<Vec<u64>>();
+    assert!(sa == sb);
+    assert!(sa != sc);
+    assert!((0..1000).map(|_| a.next_f64()).all(|x| (0.0..1.0).contains(&x)));
+}
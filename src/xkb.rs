@@ -0,0 +1,176 @@
+// LINUX XKB LAYOUTS
+
+// X11 and Wayland take their keyboard layouts from XKB, whose symbols files give every key the
+// keysyms of its shift levels. "export --format xkb" writes a symbols file for a layout of the ANSI
+// or ISO board, which goes in /usr/share/X11/xkb/symbols under the lower-case name of the layout,
+// after which "setxkbmap NAME" switches to it. Next to it goes a <layout> entry for the layout
+// list of /usr/share/X11/xkb/rules/evdev.xml, which makes the layout show up in desktop settings.
+// AltGr layers become the third and fourth shift levels, with the right Alt key as AltGr.
+//
+// Keyboard shortcuts follow the keysyms, so "--qwerty-shortcuts" has no effect here. A character
+// that starts a sequence gets its dead keysym if it has one, and the sequences themselves go in an
+// XCompose file, which X reads from ~/.XCompose, on top of the compose table of the locale.
+
+use charset::Charset;
+use geometry::Geometry;
+use klc;
+use super::NONE;
+
+// The XKB name of each PC key, as numbered by Geometry::pc_keys.
+const KEY_NAMES: [&str; 49] = [
+    "SPCE",
+    "TLDE", "AE01", "AE02", "AE03", "AE04", "AE05", "AE06", "AE07", "AE08", "AE09", "AE10", "AE11",
+    "AE12",
+    "AD01", "AD02", "AD03", "AD04", "AD05", "AD06", "AD07", "AD08", "AD09", "AD10", "AD11", "AD12",
+    "BKSL",
+    "AC01", "AC02", "AC03", "AC04", "AC05", "AC06", "AC07", "AC08", "AC09", "AC10", "AC11",
+    "AB01", "AB02", "AB03", "AB04", "AB05", "AB06", "AB07", "AB08", "AB09", "AB10",
+    "LSGT",
+];
+
+// The keysyms of the printable ASCII characters other than letters and digits.
+const SYMBOL_KEYSYMS: [(char, &str); 33] = [
+    (' ', "space"), ('!', "exclam"), ('"', "quotedbl"), ('#', "numbersign"), ('$', "dollar"),
+    ('%', "percent"), ('&', "ampersand"), ('\'', "apostrophe"), ('(', "parenleft"),
+    (')', "parenright"), ('*', "asterisk"), ('+', "plus"), (',', "comma"), ('-', "minus"),
+    ('.', "period"), ('/', "slash"), (':', "colon"), (';', "semicolon"), ('<', "less"),
+    ('=', "equal"), ('>', "greater"), ('?', "question"), ('@', "at"), ('[', "bracketleft"),
+    ('\\', "backslash"), (']', "bracketright"), ('^', "asciicircum"), ('_', "underscore"),
+    ('`', "grave"), ('{', "braceleft"), ('|', "bar"), ('}', "braceright"), ('~', "asciitilde"),
+];
+
+// The dead keysyms of the characters that have one.
+const DEAD_KEYSYMS: [(char, &str); 16] = [
+    ('´', "dead_acute"), ('\'', "dead_acute"), ('`', "dead_grave"), ('^', "dead_circumflex"),
+    ('ˆ', "dead_circumflex"), ('¨', "dead_diaeresis"), ('"', "dead_diaeresis"), ('~', "dead_tilde"),
+    ('˜', "dead_tilde"), ('¸', "dead_cedilla"), ('˘', "dead_breve"), ('ˇ', "dead_caron"),
+    ('¯', "dead_macron"), ('°', "dead_abovering"), ('˛', "dead_ogonek"), ('˝', "dead_doubleacute"),
+];
+
+// The files of an XKB layout.
+pub struct XkbFiles
+{
+    pub symbols: String,
+    pub evdev:   String,
+    pub compose: Option<String>,
+}
+
+// The keysym of a character, which is its Unicode keysym outside of ASCII.
+fn keysym(c: char) -> String
+{
+    match SYMBOL_KEYSYMS.iter().find(|s| s.0 == c) {
+        Some(s)                           => s.1.to_string(),
+        None if c.is_ascii_alphanumeric() => c.to_string(),
+        None                              => format!("U{:04X}", c as u32),
+    }
+}
+
+// The name of a layout as XKB knows it, in lower case.
+pub fn xkb_name(name: &str) -> String
+{
+    name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect()
+}
+
+// Write a layout as XKB files.
+pub fn xkb_files(layout: &[u8], geometry: &Geometry, charset: &Charset, name: &str, locale: &str)
+    -> Result<XkbFiles, String>
+{
+    let pc_keys   = geometry.pc_keys()?;
+    let num_chars = charset.len();
+    let xkb_name  = xkb_name(name);
+    let &(_, _, language_name, language) = klc::find_locale(locale)?;
+    let language_name = language_name.split(" (").next().unwrap();
+    if name.is_empty() {
+        return Err("the layout needs a name".to_string());
+    }
+
+    // Dead keys without a dead keysym keep the keysym of their character
+    let starts = charset.sequences().iter().map(|s| s.1[0]).collect::<Vec<u8>>();
+    let symbol = |c: u8| -> String {
+        match DEAD_KEYSYMS.iter().find(|d| d.0 == charset.char(c)) {
+            Some(d) if starts.contains(&c) => d.1.to_string(),
+            _                              => keysym(charset.char(c)),
+        }
+    };
+
+    let mut symbols = format!("// The {} keyboard layout, for /usr/share/X11/xkb/symbols/{}\n\n\
+                               default partial alphanumeric_keys\nxkb_symbols \"basic\" {{\n\
+                               \x20   name[Group1] = \"{} ({})\";\n\n",
+                              name, xkb_name, language_name, name);
+    for k in 1..geometry.num_keys() + 1 {
+        let levels = (0..geometry.layers).map(|layer| {
+            let c = layout[num_chars + geometry.position(k, layer)];
+            if c == NONE { "NoSymbol".to_string() } else { symbol(c) }
+        }).collect::<Vec<String>>();
+        let key_name = KEY_NAMES[pc_keys[k]];
+        symbols.push_str(&format!("    key <{}> {{ [ {} ] }};\n", key_name, levels.join(", ")));
+    }
+    if geometry.layers > 2 {
+        symbols.push_str("\n    include \"level3(ralt_switch)\"\n");
+    }
+    symbols.push_str("};\n");
+
+    let evdev = format!("<layout>\n  <configItem>\n    <name>{}</name>\n\
+                         \x20   <shortDescription>{}</shortDescription>\n\
+                         \x20   <description>{} ({})</description>\n\
+                         \x20   <languageList>\n      <iso639Id>{}</iso639Id>\n    </languageList>\n\
+                         \x20 </configItem>\n</layout>\n",
+                        xkb_name, &locale[..2], language_name, name, language);
+
+    let compose = if charset.sequences().is_empty() {
+        None
+    } else {
+        let mut compose = "include \"%L\"\n\n".to_string();
+        for &(produced, ref keys) in charset.sequences().iter() {
+            let keys = keys.iter().map(|&c| format!("<{}>", symbol(c))).collect::<Vec<String>>();
+            let quoted = if produced == '"' || produced == '\\' { format!("\\{}", produced) }
+                         else { produced.to_string() };
+            compose.push_str(&format!("{} : \"{}\" {}\n", keys.join(" "), quoted, keysym(produced)));
+        }
+        Some(compose)
+    };
+    Ok(XkbFiles{ symbols, evdev, compose })
+}
+
+#[test]
+fn xkb_symbols_test()
+{
+    use super::{WHITE_STRING, layout_from_string};
+
+    // Every key of the symbols file has the keysyms of the characters of its key in the layout
+    let charset = Charset::ascii();
+    let keysyms = (0..charset.len() as u8).map(|c| (keysym(charset.char(c)), c)).collect::<Vec<_>>();
+    for name in ["ansi", "iso"].iter() {
+        let geometry = Geometry::load(name).unwrap();
+        let pc_keys = geometry.pc_keys().unwrap();
+        let layout = layout_from_string(&geometry.layout, &geometry, &charset);
+        let files = xkb_files(&layout, &geometry, &charset, "White", "en-US").unwrap();
+        let mut keys_seen = 0;
+        for line in files.symbols.lines().filter(|l| l.trim_start().starts_with("key <")) {
+            let key_name = &line[line.find('<').unwrap() + 1..line.find('>').unwrap()];
+            let pc_key = KEY_NAMES.iter().position(|&n| n == key_name).unwrap();
+            let k = pc_keys.iter().position(|&p| p == pc_key).unwrap();
+            let levels = &line[line.find('[').unwrap() + 1..line.find(']').unwrap()];
+            for (layer, level) in levels.split(',').enumerate() {
+                let c = layout[charset.len() + geometry.position(k, layer)];
+                if level.trim() == "NoSymbol" {
+                    assert_eq!(c, NONE);
+                } else {
+                    assert_eq!(Some(c), keysyms.iter().find(|s| s.0 == level.trim()).map(|s| s.1));
+                }
+            }
+            keys_seen += 1;
+        }
+        assert_eq!(keys_seen, geometry.num_keys());
+        assert!(files.evdev.contains("<name>white</name>") && files.compose.is_none());
+    }
+
+    // Dead keys get their dead keysyms, and their sequences go in the compose file
+    let geometry = Geometry::ansi();
+    let charset = Charset::new("´").unwrap().with_sequences("´eé").unwrap();
+    let layout = layout_from_string(&WHITE_STRING.replace('#', "´"), &geometry, &charset);
+    let files = xkb_files(&layout, &geometry, &charset, "White", "de-DE").unwrap();
+    assert!(files.symbols.contains("key <TLDE> { [ dead_acute, grave ] };"));
+    assert!(files.evdev.contains("<iso639Id>ger</iso639Id>"));
+    assert_eq!(files.compose.unwrap(), "include \"%L\"\n\n<dead_acute> <e> : \"é\" U00E9\n");
+}